@@ -5,8 +5,31 @@
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion, BenchmarkId, Throughput};
 use image::{DynamicImage, RgbaImage};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 
+/// Bytes pedidos al asignador desde el inicio (solo crece): la diferencia entre dos lecturas
+/// es lo que asignó el código intermedio
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Asignador del sistema que cuenta los bytes de cada asignación
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
 /// Genera una imagen de prueba con dimensiones específicas
 fn generate_test_image(width: u32, height: u32) -> DynamicImage {
     let mut img = RgbaImage::new(width, height);
@@ -144,6 +167,128 @@ fn bench_resize_fast(c: &mut Criterion) {
     group.finish();
 }
 
+/// Benchmark de Resizer reutilizado vs uno nuevo por llamada (100 resizes a tamaños distintos)
+fn bench_resizer_reuse(c: &mut Criterion) {
+    use fast_image_resize::{images::Image, PixelType, ResizeAlg, ResizeOptions, Resizer};
+    
+    let mut group = c.benchmark_group("resizer_reuse");
+    group.measurement_time(Duration::from_secs(10));
+    
+    let (width, height) = (640, 480);
+    let src_image = Image::from_vec_u8(
+        width,
+        height,
+        generate_test_image(width, height).to_rgba8().into_raw(),
+        PixelType::U8x4,
+    ).unwrap();
+    let options = ResizeOptions::new()
+        .resize_alg(ResizeAlg::Convolution(fast_image_resize::FilterType::Lanczos3));
+    // Destinos variados: el Resizer reutilizado debe adaptarse a cada tamaño
+    let targets: Vec<(u32, u32)> = (0..100).map(|i| (100 + i * 3, 80 + i * 2)).collect();
+    
+    group.throughput(Throughput::Elements(targets.len() as u64));
+    
+    group.bench_function("fresh_x100", |b| {
+        b.iter(|| {
+            for &(w, h) in &targets {
+                let mut dst_image = Image::new(w, h, PixelType::U8x4);
+                let mut resizer = Resizer::new();
+                resizer.resize(&src_image, &mut dst_image, Some(&options)).unwrap();
+                black_box(&dst_image);
+            }
+        });
+    });
+    
+    group.bench_function("reused_x100", |b| {
+        let mut resizer = Resizer::new();
+        b.iter(|| {
+            for &(w, h) in &targets {
+                let mut dst_image = Image::new(w, h, PixelType::U8x4);
+                resizer.resize(&src_image, &mut dst_image, Some(&options)).unwrap();
+                black_box(&dst_image);
+            }
+        });
+    });
+    
+    group.finish();
+}
+
+/// Benchmark de la cadena real del pipeline: resize → quantize → encode → preview (8K → 4K)
+/// "owned" pasa el RgbaImage en propiedad entre etapas, como process_pipeline; "reconvert" es la
+/// línea base que vuelve a convertir a RGBA8 en cada etapa. Además del tiempo se imprimen los
+/// bytes asignados por iteración de cada variante
+fn bench_pipeline_chain(c: &mut Criterion) {
+    use windoosh_lib::bench::{
+        apply_quantize, extract_rgba_data, resize_with_simd, ImageEncoder, ImageMetadata,
+        OxiPngCodec,
+    };
+    use windoosh_lib::{QuantizeOptionsDto, ResizeOptionsDto};
+
+    let mut group = c.benchmark_group("pipeline_chain");
+    group.measurement_time(Duration::from_secs(20));
+    group.sample_size(10);
+    
+    let (width, height) = (7680, 4320);
+    let img = generate_test_image(width, height);
+    let resize: ResizeOptionsDto = serde_json::from_value(serde_json::json!({
+        "width": width / 2,
+        "height": height / 2,
+        "filter": "Lanczos3"
+    }))
+    .unwrap();
+    let quantize = QuantizeOptionsDto {
+        num_colors: 256,
+        dither: 1.0,
+        dither_algorithm: String::new(),
+        fixed_palette: None,
+        speed: Some(10),
+        min_quality: None,
+        max_quality: None,
+    };
+    let png_options = serde_json::json!({ "level": 1 });
+    let metadata = ImageMetadata::default();
+
+    // Una conversión (into_rgba8, sin copia) y una copia para el encoder: la preview se queda el buffer
+    let owned = || {
+        let resized = resize_with_simd(&img, &resize).unwrap();
+        let rgba = apply_quantize(resized, &quantize).unwrap().into_rgba8();
+        let encoded = OxiPngCodec.encode(rgba.clone(), &png_options, &metadata).unwrap();
+        let preview = extract_rgba_data(DynamicImage::ImageRgba8(rgba));
+        encoded.data.len() + preview.data.len()
+    };
+    // Cuatro conversiones completas, una por etapa
+    let reconvert = || {
+        let source = DynamicImage::ImageRgba8(img.to_rgba8());
+        let resized = resize_with_simd(&source, &resize).unwrap();
+        let quantized =
+            apply_quantize(DynamicImage::ImageRgba8(resized.to_rgba8()), &quantize).unwrap();
+        let encoded = OxiPngCodec.encode(quantized.to_rgba8(), &png_options, &metadata).unwrap();
+        let preview = extract_rgba_data(DynamicImage::ImageRgba8(quantized.to_rgba8()));
+        encoded.data.len() + preview.data.len()
+    };
+    let variants: [(&str, &dyn Fn() -> usize); 2] =
+        [("owned_8K", &owned), ("reconvert_8K", &reconvert)];
+
+    group.throughput(Throughput::Bytes((width * height * 4) as u64));
+
+    for (name, chain) in variants {
+        // La primera ejecución reserva buffers que luego se reutilizan (Resizer, thread pool)
+        black_box(chain());
+        let before = ALLOCATED.load(Ordering::Relaxed);
+        black_box(chain());
+        let allocated = ALLOCATED.load(Ordering::Relaxed) - before;
+        println!(
+            "pipeline_chain/{}: {:.1} MiB asignados por iteración",
+            name,
+            allocated as f64 / 1048576.0
+        );
+
+        group.bench_function(name, |b| b.iter(|| black_box(chain())));
+    }
+    
+    group.finish();
+}
+
 /// Benchmark de encoding JPEG
 fn bench_jpeg_encode(c: &mut Criterion) {
     let mut group = c.benchmark_group("jpeg_encode");
@@ -257,6 +402,8 @@ criterion_group!(
     benches,
     bench_resize_image_rs,
     bench_resize_fast,
+    bench_resizer_reuse,
+    bench_pipeline_chain,
     bench_jpeg_encode,
     bench_png_encode,
     bench_base64_overhead,
@@ -0,0 +1,93 @@
+use super::traits::{EncodingResult, ImageEncoder, ImageMetadata, parse_options};
+use image::RgbaImage;
+use ravif::{Encoder, Img, RGBA8};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+pub struct AvifCodec;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct AvifOptions {
+    quality: f32,       // 0.0 - 100.0
+    alpha_quality: f32, // 0.0 - 100.0
+    speed: u8,          // 0-10 (ravif acepta 1-10, 0 se trata como 1)
+}
+
+impl Default for AvifOptions {
+    fn default() -> Self {
+        Self {
+            quality: 50.0,
+            alpha_quality: 75.0,
+            speed: 6,
+        }
+    }
+}
+
+impl ImageEncoder for AvifCodec {
+    fn name(&self) -> &str {
+        "avif"
+    }
+
+    fn supported_formats(&self) -> Vec<&str> {
+        vec!["avif"]
+    }
+
+    fn encode(
+        &self,
+        image: RgbaImage,
+        options: &Value,
+        _metadata: &ImageMetadata,
+    ) -> Result<EncodingResult, String> {
+        let opts: AvifOptions = parse_options(options, &self.options_schema())?;
+
+        let (width, height) = image.dimensions();
+        let pixels: Vec<RGBA8> = image
+            .pixels()
+            .map(|p| {
+                let [r, g, b, a] = p.0;
+                RGBA8::new(r, g, b, a)
+            })
+            .collect();
+
+        // ravif hace panic con speed fuera de 1..=10
+        let encoded = Encoder::new()
+            .with_quality(opts.quality.clamp(0.0, 100.0))
+            .with_alpha_quality(opts.alpha_quality.clamp(0.0, 100.0))
+            .with_speed(opts.speed.clamp(1, 10))
+            .encode_rgba(Img::new(pixels.as_slice(), width as usize, height as usize))
+            .map_err(|e| format!("Error codificando AVIF: {}", e))?;
+
+        Ok(EncodingResult {
+            data: encoded.avif_file,
+            mime_type: "image/avif".to_string(),
+            extension: "avif".to_string(),
+        })
+    }
+
+    fn options_schema(&self) -> Value {
+        json!({
+            "quality": {
+                "type": "slider",
+                "label": "Quality",
+                "min": 0,
+                "max": 100,
+                "default": 50
+            },
+            "alpha_quality": {
+                "type": "slider",
+                "label": "Alpha Quality",
+                "min": 0,
+                "max": 100,
+                "default": 75
+            },
+            "speed": {
+                "type": "slider",
+                "label": "Speed",
+                "min": 0,
+                "max": 10,
+                "default": 6
+            }
+        })
+    }
+}
@@ -0,0 +1,51 @@
+use super::traits::{EncodingResult, ImageEncoder, ImageMetadata, is_grayscale, is_opaque};
+use image::codecs::bmp::BmpEncoder;
+use image::{DynamicImage, ImageEncoder as _, RgbaImage};
+use serde_json::{json, Value};
+
+pub struct BmpCodec;
+
+impl ImageEncoder for BmpCodec {
+    fn name(&self) -> &str {
+        "bmp"
+    }
+
+    fn supported_formats(&self) -> Vec<&str> {
+        vec!["bmp"]
+    }
+
+    fn encode(
+        &self,
+        image: RgbaImage,
+        _options: &Value,
+        _metadata: &ImageMetadata,
+    ) -> Result<EncodingResult, String> {
+        let (width, height) = (image.width(), image.height());
+
+        // Sin compresión: 24 bits, 32 si hay alpha, o 8 bits con paleta de grises
+        // La escala de grises con alpha no existe en BMP y pasa a 32 bits
+        let image = if !is_opaque(&image) {
+            DynamicImage::ImageRgba8(image)
+        } else if is_grayscale(&image) {
+            DynamicImage::ImageLuma8(DynamicImage::ImageRgba8(image).into_luma8())
+        } else {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(image).into_rgb8())
+        };
+
+        let mut bytes = Vec::new();
+        BmpEncoder::new(&mut bytes)
+            .write_image(image.as_bytes(), width, height, image.color().into())
+            .map_err(|e| format!("Error codificando BMP: {}", e))?;
+
+        Ok(EncodingResult {
+            data: bytes,
+            mime_type: "image/bmp".to_string(),
+            extension: "bmp".to_string(),
+        })
+    }
+
+    fn options_schema(&self) -> Value {
+        // BMP no tiene parámetros ajustables
+        json!({})
+    }
+}
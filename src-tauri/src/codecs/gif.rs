@@ -0,0 +1,193 @@
+use super::traits::{
+    AnimationFrame, EncodingResult, ImageEncoder, ImageMetadata, parse_options,
+};
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+pub struct GifCodec;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct GifOptions {
+    num_colors: u32, // 2-256
+    dither: f32,     // 0.0 - 1.0
+    speed: u8,       // 1-10 de imagequant; 0 = según el tamaño
+    min_quality: u8, // 0-100; por debajo falla en lugar de dar una paleta pobre
+    max_quality: u8, // 0-100
+}
+
+impl Default for GifOptions {
+    fn default() -> Self {
+        Self {
+            num_colors: 256,
+            dither: 1.0,
+            speed: 0,
+            min_quality: 0,
+            max_quality: 100,
+        }
+    }
+}
+
+impl GifOptions {
+    fn tuning(&self) -> crate::QuantizerTuning {
+        crate::QuantizerTuning {
+            speed: self.speed,
+            min_quality: self.min_quality,
+            max_quality: self.max_quality,
+        }
+    }
+}
+
+impl ImageEncoder for GifCodec {
+    fn name(&self) -> &str {
+        "gif"
+    }
+
+    fn supported_formats(&self) -> Vec<&str> {
+        vec!["gif"]
+    }
+
+    fn encode(
+        &self,
+        image: RgbaImage,
+        options: &Value,
+        _metadata: &ImageMetadata,
+    ) -> Result<EncodingResult, String> {
+        let opts: GifOptions = parse_options(options, &self.options_schema())?;
+
+        let (width, height) = gif_dimensions(image.width(), image.height())?;
+
+        // Misma quantización que apply_quantize, pero conservando los índices
+        let (palette, mut indices) =
+            crate::quantize_indexed(&image, opts.num_colors, opts.dither, opts.tuning())
+                .map_err(|e| e.to_string())?;
+
+        let transparent = unify_transparent(&palette, &mut indices);
+
+        let rgb_palette: Vec<u8> = palette.iter().flat_map(|c| [c.r, c.g, c.b]).collect();
+
+        let mut output_bytes = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut output_bytes, width, height, &rgb_palette)
+                .map_err(|e| format!("Error creando GIF encoder: {}", e))?;
+            let frame = gif::Frame::from_indexed_pixels(width, height, indices, transparent);
+            encoder
+                .write_frame(&frame)
+                .map_err(|e| format!("Error codificando GIF: {}", e))?;
+        }
+
+        Ok(EncodingResult {
+            data: output_bytes,
+            mime_type: "image/gif".to_string(),
+            extension: "gif".to_string(),
+        })
+    }
+
+    fn options_schema(&self) -> Value {
+        json!({
+            "num_colors": {
+                "type": "slider",
+                "label": "Palette Size",
+                "min": 2,
+                "max": 256,
+                "default": 256
+            },
+            "dither": {
+                "type": "slider",
+                "label": "Dithering",
+                "min": 0,
+                "max": 1,
+                "step": 0.1,
+                "default": 1.0
+            },
+            "speed": {
+                "type": "slider",
+                "label": "Quantizer Speed (0 = auto)",
+                "min": 0,
+                "max": 10,
+                "default": 0
+            },
+            "min_quality": {
+                "type": "slider",
+                "label": "Minimum Palette Quality",
+                "min": 0,
+                "max": 100,
+                "default": 0
+            },
+            "max_quality": {
+                "type": "slider",
+                "label": "Maximum Palette Quality",
+                "min": 0,
+                "max": 100,
+                "default": 100
+            }
+        })
+    }
+}
+
+/// GIF animado: una paleta global compartida por todos los frames
+/// Los frames son canvases completos, por eso cada uno se limpia al fondo antes del siguiente
+pub fn encode_animated(frames: &[AnimationFrame], options: &Value) -> Result<EncodingResult, String> {
+    let opts: GifOptions = parse_options(options, &GifCodec.options_schema())?;
+
+    let first = frames.first().ok_or("La animación no tiene frames")?;
+    let (width, height) = gif_dimensions(first.image.width(), first.image.height())?;
+
+    let images: Vec<_> = frames.iter().map(|f| &f.image).collect();
+    let (palette, mut indexed) =
+        crate::quantize_shared(&images, opts.num_colors, opts.dither, opts.tuning())
+            .map_err(|e| e.to_string())?;
+
+    let mut transparent = None;
+    for indices in indexed.iter_mut() {
+        transparent = unify_transparent(&palette, indices);
+    }
+
+    let rgb_palette: Vec<u8> = palette.iter().flat_map(|c| [c.r, c.g, c.b]).collect();
+
+    let mut output_bytes = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut output_bytes, width, height, &rgb_palette)
+            .map_err(|e| format!("Error creando GIF encoder: {}", e))?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(|e| format!("Error configurando GIF: {}", e))?;
+
+        for (indices, frame) in indexed.into_iter().zip(frames) {
+            let mut gif_frame = gif::Frame::from_indexed_pixels(width, height, indices, transparent);
+            // GIF guarda la duración en centésimas de segundo
+            gif_frame.delay = ((frame.delay_ms + 5) / 10).min(u16::MAX as u32) as u16;
+            gif_frame.dispose = gif::DisposalMethod::Background;
+            encoder
+                .write_frame(&gif_frame)
+                .map_err(|e| format!("Error codificando GIF: {}", e))?;
+        }
+    }
+
+    Ok(EncodingResult {
+        data: output_bytes,
+        mime_type: "image/gif".to_string(),
+        extension: "gif".to_string(),
+    })
+}
+
+fn gif_dimensions(width: u32, height: u32) -> Result<(u16, u16), String> {
+    match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(w), Ok(h)) => Ok((w, h)),
+        _ => Err("GIF no admite dimensiones mayores a 65535px".to_string()),
+    }
+}
+
+/// GIF solo tiene un índice transparente: se unifican los colores con alpha < 128
+fn unify_transparent(palette: &[imagequant::RGBA], indices: &mut [u8]) -> Option<u8> {
+    let transparent = palette.iter().position(|c| c.a < 128).map(|i| i as u8);
+    if let Some(t) = transparent {
+        for idx in indices.iter_mut() {
+            if palette[*idx as usize].a < 128 {
+                *idx = t;
+            }
+        }
+    }
+    transparent
+}
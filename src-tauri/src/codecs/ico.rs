@@ -0,0 +1,96 @@
+use super::traits::{EncodingResult, ImageEncoder, ImageMetadata, parse_options};
+use crate::{resize_with_simd, ResizeOptionsDto};
+use ico::{IconDir, IconDirEntry, IconImage, ResourceType};
+use image::{DynamicImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+pub struct IcoCodec;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct IcoOptions {
+    sizes: Vec<u32>, // Lados de los iconos cuadrados que se empaquetan (máx. 256)
+}
+
+impl Default for IcoOptions {
+    fn default() -> Self {
+        Self {
+            sizes: vec![16, 32, 48],
+        }
+    }
+}
+
+impl ImageEncoder for IcoCodec {
+    fn name(&self) -> &str {
+        "ico"
+    }
+
+    fn supported_formats(&self) -> Vec<&str> {
+        vec!["ico"]
+    }
+
+    fn encode(
+        &self,
+        image: RgbaImage,
+        options: &Value,
+        _metadata: &ImageMetadata,
+    ) -> Result<EncodingResult, String> {
+        let mut opts: IcoOptions = parse_options(options, &self.options_schema())?;
+        opts.sizes.sort_unstable();
+        opts.sizes.dedup();
+        if opts.sizes.is_empty() {
+            return Err("Selecciona al menos un tamaño de icono".to_string());
+        }
+
+        let image = DynamicImage::ImageRgba8(image);
+        let mut icon_dir = IconDir::new(ResourceType::Icon);
+        for &size in &opts.sizes {
+            let icon = icon_of_size(&image, size)?;
+            // ico elige BMP (mejor compatibilidad) o PNG (alpha parcial, tamaños grandes)
+            let entry = IconDirEntry::encode(&icon)
+                .map_err(|e| format!("Error codificando icono de {}px: {}", size, e))?;
+            icon_dir.add_entry(entry);
+        }
+
+        let mut bytes = Vec::new();
+        icon_dir
+            .write(&mut bytes)
+            .map_err(|e| format!("Error escribiendo ICO: {}", e))?;
+
+        Ok(EncodingResult {
+            data: bytes,
+            mime_type: "image/x-icon".to_string(),
+            extension: "ico".to_string(),
+        })
+    }
+
+    fn options_schema(&self) -> Value {
+        json!({
+            "sizes": {
+                "type": "multiselect",
+                "label": "Icon Sizes",
+                "options": [16, 24, 32, 48, 64, 128, 256],
+                "default": [16, 32, 48]
+            }
+        })
+    }
+}
+
+/// Icono cuadrado de `size` px: la imagen se reduce para caber y el resto queda transparente
+fn icon_of_size(image: &DynamicImage, size: u32) -> Result<IconImage, String> {
+    let opts = ResizeOptionsDto {
+        width: size,
+        height: size,
+        filter: String::new(),
+        resize_quality: "best".to_string(),
+        lock_aspect: true,
+        fit: "contain".to_string(),
+        scale_percent: None,
+        max_dimension: None,
+        linear_light: false,
+        no_upscale: false,
+    };
+    let resized = resize_with_simd(image, &opts).map_err(|e| e.to_string())?;
+    Ok(IconImage::from_rgba_data(size, size, resized.into_rgba8().into_raw()))
+}
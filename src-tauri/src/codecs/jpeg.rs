@@ -1,42 +1,78 @@
-use super::traits::{EncodingResult, ImageEncoder};
-use image::DynamicImage;
+use super::traits::{EncodingResult, ImageEncoder, ImageMetadata, is_grayscale, parse_options};
+use image::{DynamicImage, RgbaImage};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
 pub struct JpegCodec;
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 struct JpegOptions {
     quality: u8, // 1-100
-    // Placeholders para paridad futura con MozJPEG
-    // trellis: bool,
-    // progressive: bool,
+    // Solo aplican con el backend MozJPEG (feature "mozjpeg-native")
+    progressive: bool,
+    trellis: bool,
+    subsampling: String, // "4:2:0" | "4:2:2" | "4:4:4"
+    smoothing: u8,        // 0-100; >0 suaviza ligeramente la fuente antes de la DCT (reduce ruido y tamaño)
+    optimize_coding: bool, // Tablas Huffman optimizadas para la imagen (más pequeño, algo más lento)
+    embed_icc: bool,     // Incrustar el perfil ICC del original (APP2)
+    strip_metadata: bool, // No escribir EXIF/XMP/ICC (tiene prioridad sobre embed_icc)
+    restart_interval: Option<u16>, // Filas de MCU entre marcadores RST; None = sin marcadores
 }
 
 impl Default for JpegOptions {
     fn default() -> Self {
         Self {
             quality: 75,
+            progressive: true,
+            trellis: true,
+            subsampling: "4:2:0".to_string(),
+            smoothing: 0,
+            optimize_coding: true,
+            embed_icc: true,
+            strip_metadata: true,
+            restart_interval: None,
         }
     }
 }
 
+impl JpegOptions {
+    /// Factores de muestreo (h, v) del croma; valores desconocidos usan 4:2:0
+    fn chroma_factors(&self) -> (u8, u8) {
+        match self.subsampling.as_str() {
+            "4:4:4" => (1, 1),
+            "4:2:2" => (2, 1),
+            _ => (2, 2),
+        }
+    }
+
+    /// Filas de MCU entre marcadores de reinicio (0 equivale a no ponerlos)
+    fn restart_rows(&self) -> Option<u16> {
+        self.restart_interval.filter(|&rows| rows > 0)
+    }
+}
+
 impl ImageEncoder for JpegCodec {
     fn name(&self) -> &str {
-        "mozjpeg" // Usamos este nombre para compatibilidad UI con Squoosh, aunque backend sea standard por ahora
+        "mozjpeg" // Nombre compatible con la UI de Squoosh; sin la feature "mozjpeg-native" el backend es jpeg-encoder
     }
 
     fn supported_formats(&self) -> Vec<&str> {
         vec!["jpeg", "jpg"]
     }
 
-    fn encode(&self, image: &DynamicImage, options: &Value) -> Result<EncodingResult, String> {
-        let opts: JpegOptions = serde_json::from_value(options.clone()).unwrap_or_default();
+    fn encode(
+        &self,
+        image: RgbaImage,
+        options: &Value,
+        metadata: &ImageMetadata,
+    ) -> Result<EncodingResult, String> {
+        let opts: JpegOptions = parse_options(options, &self.options_schema())?;
+
+        let icc = metadata.icc_to_embed(opts.strip_metadata, opts.embed_icc);
+        let exif = metadata.exif_to_embed.as_deref();
 
-        let mut output_bytes = Vec::new();
-        // Usamos el encoder estándar de Rust que es seguro y multiplataforma
-        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut output_bytes, opts.quality);
-        image.write_with_encoder(encoder).map_err(|e| e.to_string())?;
+        let output_bytes = encode_jpeg(image, &opts, icc, exif)?;
 
         Ok(EncodingResult {
             data: output_bytes,
@@ -45,6 +81,10 @@ impl ImageEncoder for JpegCodec {
         })
     }
 
+    fn supports_alpha(&self) -> bool {
+        false
+    }
+
     fn options_schema(&self) -> Value {
         json!({
             "quality": {
@@ -53,8 +93,495 @@ impl ImageEncoder for JpegCodec {
                 "min": 0,
                 "max": 100,
                 "default": 75
+            },
+            "progressive": {
+                "type": "checkbox",
+                "label": "Progressive",
+                "default": true
+            },
+            "trellis": {
+                "type": "checkbox",
+                "label": "Trellis Quantization",
+                "default": true
+            },
+            "subsampling": {
+                "type": "select",
+                "label": "Chroma Subsampling",
+                "options": ["4:2:0", "4:2:2", "4:4:4"],
+                "default": "4:2:0"
+            },
+            "smoothing": {
+                "type": "slider",
+                "label": "Smoothing (blurs source before DCT)",
+                "min": 0,
+                "max": 100,
+                "default": 0
+            },
+            "optimize_coding": {
+                "type": "checkbox",
+                "label": "Optimize Huffman Tables",
+                "default": true
+            },
+            "embed_icc": {
+                "type": "checkbox",
+                "label": "Keep Color Profile (ICC)",
+                "default": true
+            },
+            "strip_metadata": {
+                "type": "checkbox",
+                "label": "Strip Metadata (EXIF/XMP/ICC)",
+                "default": true
+            },
+            "restart_interval": {
+                "type": "slider",
+                "label": "Restart Markers every N MCU rows (recovers from corruption, slightly larger)",
+                "min": 1,
+                "max": 65535,
+                "default": null
             }
-            // A futuro: añadir checkboxes para Progressive, Trellis, etc.
         })
     }
 }
+
+/// Muestras a codificar: un canal si todos los píxeles son grises, RGB en otro caso
+/// (un JPEG de un solo componente ocupa bastante menos que el mismo gris en YCbCr)
+/// El alpha se descarta: el pipeline ya aplanó la imagen (supports_alpha = false)
+fn jpeg_samples(image: RgbaImage) -> (Vec<u8>, u32, u32, bool) {
+    let (width, height) = image.dimensions();
+    if is_grayscale(&image) {
+        (image.pixels().map(|p| p[0]).collect(), width, height, true)
+    } else {
+        (DynamicImage::ImageRgba8(image).into_rgb8().into_raw(), width, height, false)
+    }
+}
+
+/// Contenido del segmento APP1 para un bloque EXIF (TIFF)
+fn exif_segment(tiff: &[u8]) -> Vec<u8> {
+    [b"Exif\0\0".as_slice(), tiff].concat()
+}
+
+/// Backend MozJPEG: trellis + optimización de scans para mejor calidad por byte
+#[cfg(feature = "mozjpeg-native")]
+fn encode_jpeg(
+    image: RgbaImage,
+    opts: &JpegOptions,
+    icc: Option<&[u8]>,
+    exif: Option<&[u8]>,
+) -> Result<Vec<u8>, String> {
+    let (samples, width, height, gray) = jpeg_samples(image);
+    // El crate mozjpeg no expone restart_in_rows: los marcadores se añaden en una segunda
+    // pasada sin pérdida sobre los coeficientes (como jpegtran -restart), que escribe EXIF e ICC
+    let restart = opts.restart_rows();
+    let (icc_first_pass, exif_first_pass) = if restart.is_some() { (None, None) } else { (icc, exif) };
+    let color_space = if gray {
+        mozjpeg::ColorSpace::JCS_GRAYSCALE
+    } else {
+        mozjpeg::ColorSpace::JCS_RGB
+    };
+
+    // libjpeg reporta errores haciendo unwind, hay que capturarlos
+    let encoded = std::panic::catch_unwind(|| -> std::io::Result<Vec<u8>> {
+        let mut comp = mozjpeg::Compress::new(color_space);
+
+        // Sin trellis: perfil JCP_FASTEST (equivalente a libjpeg-turbo)
+        // Debe ir antes del resto de parámetros porque reinicia los defaults
+        if !opts.trellis {
+            comp.set_fastest_defaults();
+        }
+
+        comp.set_size(width as usize, height as usize);
+        comp.set_quality(opts.quality.clamp(1, 100) as f32);
+        comp.set_optimize_coding(opts.optimize_coding);
+        comp.set_smoothing_factor(opts.smoothing.min(100));
+
+        // Sin croma no hay submuestreo que configurar
+        if !gray {
+            let chroma = opts.chroma_factors();
+            comp.set_chroma_sampling_pixel_sizes(chroma, chroma);
+        }
+
+        if opts.progressive {
+            comp.set_progressive_mode();
+        } else {
+            // Sin scan_info el encoder escribe un único scan baseline
+            comp.set_optimize_scans(false);
+        }
+
+        let mut started = comp.start_compress(Vec::new())?;
+        if let Some(tiff) = exif_first_pass {
+            started.write_marker(mozjpeg::Marker::APP(1), &exif_segment(tiff));
+        }
+        if let Some(profile) = icc_first_pass {
+            started.write_icc_profile(profile);
+        }
+        started.write_scanlines(&samples)?;
+        started.finish()
+    })
+    .map_err(|_| "MozJPEG falló al codificar".to_string())?
+    .map_err(|e| format!("Error codificando JPEG: {}", e))?;
+
+    match restart {
+        Some(rows) => transcode(&encoded, icc, exif, Some(rows), opts.progressive),
+        None => Ok(encoded),
+    }
+}
+
+/// Fallback puro Rust (cross-compilation sin toolchain C)
+/// progressive/trellis se ignoran en este backend
+#[cfg(not(feature = "mozjpeg-native"))]
+fn encode_jpeg(
+    image: RgbaImage,
+    opts: &JpegOptions,
+    icc: Option<&[u8]>,
+    exif: Option<&[u8]>,
+) -> Result<Vec<u8>, String> {
+    let (mut samples, width, height, gray) = jpeg_samples(image);
+    if opts.smoothing > 0 {
+        let channels = if gray { 1 } else { 3 };
+        let (w, h) = (width as usize, height as usize);
+        samples = smooth_samples(&samples, w, h, channels, opts.smoothing.min(100));
+    }
+    let (width, height) = match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(w), Ok(h)) => (w, h),
+        _ => return Err("JPEG no admite dimensiones mayores a 65535px".to_string()),
+    };
+
+    let mut output_bytes = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut output_bytes, opts.quality.clamp(1, 100));
+    // Las tablas optimizadas de jpeg-encoder no cuentan el reinicio del DC en cada marcador RST
+    // (códigos inexistentes para esas diferencias): con marcadores se usan las tablas estándar
+    let restart = opts.restart_rows();
+    encoder.set_optimized_huffman_tables(opts.optimize_coding && restart.is_none());
+    let (h, v) = opts.chroma_factors();
+    if let Some(sampling) = jpeg_encoder::SamplingFactor::from_factors(h, v) {
+        encoder.set_sampling_factor(sampling);
+    }
+    if let Some(rows) = restart {
+        // jpeg-encoder cuenta el intervalo en MCUs: una fila de MCUs cubre 8 o 16 px de alto
+        // según el submuestreo. El marcador DRI guarda 16 bits, así que no se puede recortar
+        // sin cambiar el intervalo pedido
+        let mcu_width = if gray { 8 } else { 8 * h as u32 };
+        let mcus = (width as u32).div_ceil(mcu_width) * rows as u32;
+        let mcus = u16::try_from(mcus).map_err(|_| {
+            format!(
+                "Intervalo de reinicio demasiado grande: {} filas de MCU son {} MCUs (máximo 65535)",
+                rows, mcus
+            )
+        })?;
+        encoder.set_restart_interval(mcus);
+    }
+    if let Some(tiff) = exif {
+        encoder
+            .add_app_segment(1, exif_segment(tiff))
+            .map_err(|e| format!("Error incrustando EXIF: {}", e))?;
+    }
+    if let Some(profile) = icc {
+        encoder
+            .add_icc_profile(profile)
+            .map_err(|e| format!("Error incrustando perfil ICC: {}", e))?;
+    }
+    let color_type = if gray {
+        jpeg_encoder::ColorType::Luma
+    } else {
+        jpeg_encoder::ColorType::Rgb
+    };
+    encoder
+        .encode(&samples, width, height, color_type)
+        .map_err(|e| e.to_string())?;
+    Ok(output_bytes)
+}
+
+/// Suavizado de entrada equivalente al smoothing_factor de libjpeg (jpeg-encoder no lo tiene)
+/// Cada muestra = propia × (1 − 8·SF/1024) + suma de sus 8 vecinos × SF/1024; bordes replicados
+/// `src` son filas de `width` píxeles con `channels` muestras intercaladas (RGB o gris)
+#[cfg(not(feature = "mozjpeg-native"))]
+fn smooth_samples(src: &[u8], width: usize, height: usize, channels: usize, factor: u8) -> Vec<u8> {
+    use rayon::prelude::*;
+
+    if width == 0 || height == 0 {
+        return src.to_vec();
+    }
+    let neighbor_weight = factor as u32;
+    let member_weight = 1024 - 8 * neighbor_weight;
+
+    let mut out = vec![0u8; src.len()];
+    out.par_chunks_exact_mut(width * channels)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let rows = [y.saturating_sub(1), y, (y + 1).min(height - 1)];
+            for x in 0..width {
+                let cols = [x.saturating_sub(1), x, (x + 1).min(width - 1)];
+                for c in 0..channels {
+                    let sample = |ry: usize, cx: usize| src[(ry * width + cx) * channels + c] as u32;
+                    let member = sample(y, x);
+                    let total: u32 = rows
+                        .iter()
+                        .flat_map(|&ry| cols.iter().map(move |&cx| (ry, cx)))
+                        .map(|(ry, cx)| sample(ry, cx))
+                        .sum();
+                    let neighbors = total - member;
+                    row[x * channels + c] =
+                        ((member * member_weight + neighbors * neighbor_weight + 512) / 1024) as u8;
+                }
+            }
+        });
+
+    out
+}
+
+/// Re-optimización sin pérdida de un JPEG existente (equivalente a `jpegtran -copy none -optimize -progressive`)
+/// Reescribe los coeficientes DCT del original con tablas Huffman óptimas y scans progresivos:
+/// cero pérdida generacional. Descarta todos los marcadores salvo `exif` (ya filtrado) e `icc` si se pasan
+#[cfg(feature = "mozjpeg-native")]
+pub fn optimize_lossless(
+    source: &[u8],
+    icc: Option<&[u8]>,
+    exif: Option<&[u8]>,
+) -> Result<Vec<u8>, String> {
+    transcode(source, icc, exif, None, true)
+}
+
+/// Copia los coeficientes DCT de `source` a un JPEG nuevo sin re-cuantizar (núcleo de jpegtran)
+/// Huffman siempre optimizado; `restart_rows` añade marcadores RST cada N filas de MCU
+#[cfg(feature = "mozjpeg-native")]
+fn transcode(
+    source: &[u8],
+    icc: Option<&[u8]>,
+    exif: Option<&[u8]>,
+    restart_rows: Option<u16>,
+    progressive: bool,
+) -> Result<Vec<u8>, String> {
+    use mozjpeg_sys::*;
+    use std::mem;
+    use std::os::raw::{c_int, c_uint, c_ulong, c_void};
+
+    extern "C" {
+        fn free(ptr: *mut c_void);
+    }
+
+    // libjpeg no retorna en error_exit: se deshace la pila hasta el catch_unwind
+    extern "C-unwind" fn error_exit(_cinfo: &mut jpeg_common_struct) {
+        panic!("libjpeg error_exit");
+    }
+
+    // Guards para liberar libjpeg y el buffer de salida también si hay unwind
+    struct Decompress(Box<jpeg_decompress_struct>);
+    impl Drop for Decompress {
+        fn drop(&mut self) {
+            unsafe { jpeg_destroy_decompress(&mut self.0) }
+        }
+    }
+    struct Compress(Box<jpeg_compress_struct>);
+    impl Drop for Compress {
+        fn drop(&mut self) {
+            unsafe { jpeg_destroy_compress(&mut self.0) }
+        }
+    }
+    struct MemDest {
+        buffer: *mut u8,
+        size: c_ulong,
+    }
+    impl Drop for MemDest {
+        fn drop(&mut self) {
+            if !self.buffer.is_null() {
+                unsafe { free(self.buffer as *mut c_void) }
+            }
+        }
+    }
+
+    std::panic::catch_unwind(|| unsafe {
+        let mut src_err: Box<jpeg_error_mgr> = Box::new(mem::zeroed());
+        jpeg_std_error(&mut src_err);
+        src_err.error_exit = Some(error_exit);
+        let mut dst_err: Box<jpeg_error_mgr> = Box::new(mem::zeroed());
+        jpeg_std_error(&mut dst_err);
+        dst_err.error_exit = Some(error_exit);
+
+        let mut src = Decompress(Box::new(mem::zeroed()));
+        src.0.common.err = &mut *src_err;
+        jpeg_create_decompress(&mut src.0);
+        jpeg_mem_src(&mut src.0, source.as_ptr(), source.len() as c_ulong);
+        // Sin jpeg_save_markers: EXIF/XMP/comentarios del original no se conservan
+        jpeg_read_header(&mut src.0, 1);
+        let coefficients = jpeg_read_coefficients(&mut src.0);
+
+        let mut dest = MemDest { buffer: std::ptr::null_mut(), size: 0 };
+        let mut dst = Compress(Box::new(mem::zeroed()));
+        dst.0.common.err = &mut *dst_err;
+        jpeg_create_compress(&mut dst.0);
+        jpeg_copy_critical_parameters(&src.0, &mut dst.0);
+        dst.0.optimize_coding = 1;
+        if let Some(rows) = restart_rows {
+            dst.0.restart_in_rows = rows as c_int;
+        }
+        if progressive {
+            jpeg_simple_progression(&mut dst.0);
+        } else {
+            // El perfil por defecto de mozjpeg ya es progresivo: sin scan_info queda un único scan
+            jpeg_c_set_bool_param(&mut dst.0, J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS, 0);
+            dst.0.scan_info = std::ptr::null();
+            dst.0.num_scans = 0;
+        }
+        jpeg_mem_dest(&mut dst.0, &mut dest.buffer, &mut dest.size);
+        jpeg_write_coefficients(&mut dst.0, coefficients);
+        if let Some(tiff) = exif {
+            let segment = exif_segment(tiff);
+            // 0xE1 = APP1
+            jpeg_write_marker(&mut dst.0, 0xE1, segment.as_ptr(), segment.len() as c_uint);
+        }
+        if let Some(profile) = icc {
+            jpeg_write_icc_profile(&mut dst.0, profile.as_ptr(), profile.len() as c_uint);
+        }
+        jpeg_finish_compress(&mut dst.0);
+        jpeg_finish_decompress(&mut src.0);
+
+        std::slice::from_raw_parts(dest.buffer, dest.size as usize).to_vec()
+    })
+    .map_err(|_| "No se pudieron reescribir los coeficientes del JPEG (¿archivo corrupto?)".to_string())
+}
+
+/// Fallback puro Rust: solo elimina los segmentos de metadatos (EXIF, XMP, comentarios...)
+/// y copia los datos de la imagen tal cual. Sin libjpeg no se re-codifica el Huffman
+/// ni se convierte a progresivo; sigue sin haber pérdida alguna
+/// `exif` (ya filtrado) se escribe como APP1 en lugar del EXIF original
+#[cfg(not(feature = "mozjpeg-native"))]
+pub fn optimize_lossless(
+    source: &[u8],
+    icc: Option<&[u8]>,
+    exif: Option<&[u8]>,
+) -> Result<Vec<u8>, String> {
+    const SOI: u8 = 0xD8;
+    const SOS: u8 = 0xDA;
+    let corrupt = || "El JPEG original está corrupto o truncado".to_string();
+
+    if !source.starts_with(&[0xFF, SOI]) {
+        return Err("El original no es un JPEG".to_string());
+    }
+    let mut out = Vec::with_capacity(source.len());
+    out.extend_from_slice(&source[..2]);
+    let mut pending_exif = exif.map(exif_segment);
+    let mut pos = 2;
+    loop {
+        // Bytes 0xFF de relleno antes del marcador
+        while source.get(pos + 1) == Some(&0xFF) && source[pos] == 0xFF {
+            pos += 1;
+        }
+        let marker = match source.get(pos..pos + 2) {
+            Some(&[0xFF, marker]) => marker,
+            _ => return Err(corrupt()),
+        };
+        // El APP1 va tras el APP0 JFIF si lo hay (debe ser el primer segmento)
+        if marker != 0xE0 {
+            if let Some(segment) = pending_exif.take() {
+                let length = u16::try_from(segment.len() + 2)
+                    .map_err(|_| "El EXIF no cabe en un segmento JPEG".to_string())?;
+                out.extend_from_slice(&[0xFF, 0xE1]);
+                out.extend_from_slice(&length.to_be_bytes());
+                out.extend_from_slice(&segment);
+            }
+        }
+        // Desde el primer scan todo son datos de imagen (y las tablas entre scans progresivos)
+        if marker == SOS {
+            out.extend_from_slice(&source[pos..]);
+            return Ok(out);
+        }
+        let length = source
+            .get(pos + 2..pos + 4)
+            .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+            .filter(|&len| len >= 2)
+            .ok_or_else(corrupt)?;
+        let segment = source.get(pos..pos + 2 + length).ok_or_else(corrupt)?;
+        let payload = &segment[4..];
+        let keep = match marker {
+            0xE0 => payload.starts_with(b"JFIF\0"),
+            0xE2 => icc.is_some() && payload.starts_with(b"ICC_PROFILE\0"),
+            // Adobe indica la transformación de color: sin él cambiarían los colores
+            0xEE => payload.starts_with(b"Adobe"),
+            0xE1..=0xEF | 0xFE => false,
+            _ => true,
+        };
+        if keep {
+            out.extend_from_slice(segment);
+        }
+        pos += segment.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn encode(image: &DynamicImage, options: Value) -> Vec<u8> {
+        JpegCodec
+            .encode(image.to_rgba8(), &options, &ImageMetadata::default())
+            .expect("encode")
+            .data
+    }
+
+    /// Degradado con ruido de semilla fija, parecido a una foto
+    fn photo(width: u32, height: u32) -> DynamicImage {
+        let mut state = 7u32;
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let noise = (state >> 27) as u8;
+            Rgb([(x * 220 / width) as u8 + noise, (y * 220 / height) as u8 + noise, 90 + noise])
+        }))
+    }
+
+    #[test]
+    fn optimize_coding_shrinks_photo() {
+        let image = photo(256, 192);
+        let standard = encode(&image, json!({ "quality": 80, "optimize_coding": false }));
+        let optimized = encode(&image, json!({ "quality": 80, "optimize_coding": true }));
+        assert!(
+            optimized.len() < standard.len(),
+            "optimizado = {} bytes, estándar = {} bytes",
+            optimized.len(),
+            standard.len()
+        );
+    }
+
+    #[test]
+    fn gray_pixels_encode_a_single_component() {
+        let gray = DynamicImage::ImageLuma8(photo(64, 64).to_luma8());
+        let decoded = image::load_from_memory(&encode(&gray, json!({}))).expect("JPEG válido");
+        assert_eq!(decoded.color().channel_count(), 1);
+
+        let color = image::load_from_memory(&encode(&photo(64, 64), json!({}))).expect("JPEG válido");
+        assert_eq!(color.color().channel_count(), 3);
+    }
+
+    /// Marcadores RST0-RST7 en los datos de imagen (tras SOS, 0xFF 0xD0-0xD7)
+    fn count_restart_markers(jpeg: &[u8]) -> usize {
+        jpeg.windows(2)
+            .filter(|pair| pair[0] == 0xFF && (0xD0..=0xD7).contains(&pair[1]))
+            .count()
+    }
+
+    #[test]
+    fn restart_interval_writes_one_marker_per_interval() {
+        // 128 px de alto con 4:2:0: 8 filas de MCU de 16 px
+        let image = photo(128, 128);
+        let encode_rows = |rows: u16| {
+            encode(
+                &image,
+                json!({ "subsampling": "4:2:0", "progressive": false, "restart_interval": rows }),
+            )
+        };
+
+        assert_eq!(count_restart_markers(&encode_rows(1)), 7);
+        assert_eq!(count_restart_markers(&encode_rows(4)), 1);
+        assert_eq!(count_restart_markers(&encode(&image, json!({}))), 0);
+        assert!(image::load_from_memory(&encode_rows(1)).is_ok());
+    }
+
+    #[test]
+    #[cfg(not(feature = "mozjpeg-native"))]
+    fn restart_interval_beyond_16_bits_is_an_error() {
+        // 4096 MCUs por fila × 16 filas = 65536 MCUs: no cabe en el marcador DRI
+        let image = photo(65_535, 1);
+        let options = json!({ "subsampling": "4:2:0", "restart_interval": 16 });
+        assert!(JpegCodec.encode(image.to_rgba8(), &options, &ImageMetadata::default()).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use super::traits::{EncodingResult, ImageEncoder, ImageMetadata, parse_options};
+use image::{DynamicImage, RgbaImage};
+use jpegxl_rs::encode::{EncoderFrame, EncoderSpeed};
+use jpegxl_rs::image::ToDynamic;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+pub struct JxlCodec;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct JxlOptions {
+    distance: f32, // 0.0 (lossless) - 15.0
+    effort: u8,    // 1-9
+    lossless: bool,
+}
+
+impl Default for JxlOptions {
+    fn default() -> Self {
+        Self {
+            distance: 1.0,
+            effort: 7,
+            lossless: false,
+        }
+    }
+}
+
+impl JxlOptions {
+    /// distance 0.0 equivale a lossless real (libjxl solo lo garantiza con el flag)
+    fn is_lossless(&self) -> bool {
+        self.lossless || self.distance <= 0.0
+    }
+}
+
+/// Indica si las opciones dadas producen un JXL sin pérdida
+/// Usado por el pipeline para decidir si re-decodificar para el preview
+pub fn is_lossless(options: &Value) -> bool {
+    serde_json::from_value::<JxlOptions>(options.clone())
+        .unwrap_or_default()
+        .is_lossless()
+}
+
+/// Decodifica un JXL (image-rs no soporta el formato)
+pub fn decode(data: &[u8]) -> Result<DynamicImage, String> {
+    let decoder = jpegxl_rs::decoder_builder()
+        .build()
+        .map_err(|e| format!("Error creando JXL decoder: {}", e))?;
+    decoder
+        .decode_to_image(data)
+        .map_err(|e| format!("Error decodificando JXL: {}", e))?
+        .ok_or_else(|| "Formato de píxel JXL no soportado".to_string())
+}
+
+fn effort_to_speed(effort: u8) -> EncoderSpeed {
+    match effort.clamp(1, 9) {
+        1 => EncoderSpeed::Lightning,
+        2 => EncoderSpeed::Thunder,
+        3 => EncoderSpeed::Falcon,
+        4 => EncoderSpeed::Cheetah,
+        5 => EncoderSpeed::Hare,
+        6 => EncoderSpeed::Wombat,
+        7 => EncoderSpeed::Squirrel,
+        8 => EncoderSpeed::Kitten,
+        _ => EncoderSpeed::Tortoise,
+    }
+}
+
+impl ImageEncoder for JxlCodec {
+    fn name(&self) -> &str {
+        "jxl"
+    }
+
+    fn supported_formats(&self) -> Vec<&str> {
+        vec!["jxl"]
+    }
+
+    fn encode(
+        &self,
+        image: RgbaImage,
+        options: &Value,
+        _metadata: &ImageMetadata,
+    ) -> Result<EncodingResult, String> {
+        let opts: JxlOptions = parse_options(options, &self.options_schema())?;
+        let lossless = opts.is_lossless();
+
+        // libjxl espera RGBA intercalado, 8 bits, endianness nativa
+        let (width, height) = image.dimensions();
+
+        let mut encoder = jpegxl_rs::encoder_builder()
+            .has_alpha(true)
+            .lossless(lossless)
+            .uses_original_profile(lossless)
+            .speed(effort_to_speed(opts.effort))
+            .quality(opts.distance.clamp(0.0, 15.0))
+            .build()
+            .map_err(|e| format!("Error creando JXL encoder: {}", e))?;
+
+        let frame = EncoderFrame::new(image.as_raw().as_slice()).num_channels(4);
+        let bytes = encoder
+            .encode_frame(&frame, width, height)
+            .map_err(|e| format!("Error codificando JXL: {}", e))?;
+
+        Ok(EncodingResult {
+            data: bytes,
+            mime_type: "image/jxl".to_string(),
+            extension: "jxl".to_string(),
+        })
+    }
+
+    fn options_schema(&self) -> Value {
+        json!({
+            "distance": {
+                "type": "slider",
+                "label": "Distance (0 = lossless)",
+                "min": 0,
+                "max": 15,
+                "step": 0.1,
+                "default": 1.0
+            },
+            "effort": {
+                "type": "slider",
+                "label": "Effort",
+                "min": 1,
+                "max": 9,
+                "default": 7
+            },
+            "lossless": {
+                "type": "checkbox",
+                "label": "Lossless",
+                "default": false
+            }
+        })
+    }
+}
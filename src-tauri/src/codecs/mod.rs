@@ -2,10 +2,25 @@ pub mod traits;
 pub mod jpeg;
 pub mod png;
 pub mod webp;
-// pub mod avif;
+pub mod avif;
+pub mod gif;
+pub mod qoi;
+pub mod bmp;
+pub mod tiff;
+pub mod ico;
+#[cfg(feature = "jxl")]
+pub mod jxl;
 
 // Re-exportar traits y codecs
-pub use traits::{EncodingResult, ImageEncoder};
+pub use traits::{rgba_pixels, AnimationFrame, EncodingResult, ImageEncoder, ImageMetadata};
 pub use jpeg::JpegCodec;
 pub use png::OxiPngCodec;
 pub use webp::WebPCodec;
+pub use avif::AvifCodec;
+pub use gif::GifCodec;
+pub use qoi::QoiCodec;
+pub use bmp::BmpCodec;
+pub use tiff::TiffCodec;
+pub use ico::IcoCodec;
+#[cfg(feature = "jxl")]
+pub use jxl::JxlCodec;
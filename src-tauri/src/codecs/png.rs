@@ -1,16 +1,28 @@
-use super::traits::{EncodingResult, ImageEncoder};
-use image::{DynamicImage, GenericImageView, ImageFormat};
-use oxipng::{Options, RawImage};
+use super::traits::{
+    EncodingResult, ImageEncoder, ImageMetadata, is_grayscale, is_opaque, parse_options, rgba_pixels,
+};
+use image::codecs::png::PngEncoder;
+use image::{ColorType, DynamicImage, GenericImageView, ImageEncoder as _, RgbaImage};
+use oxipng::{indexset, BitDepth, IndexSet, Options, RawImage, RowFilter, RGBA8};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io::Cursor;
 
 pub struct OxiPngCodec;
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 struct OxiPngOptions {
     level: u8, // 0-6
     interlace: bool,
+    embed_icc: bool, // Incrustar el perfil ICC del original (chunk iCCP)
+    strip_metadata: bool, // Eliminar chunks auxiliares (tEXt/eXIf/iCCP...) salvo los necesarios
+    bit_depth: Option<u8>, // 1/2/4/8/16; None = 8. Combinaciones inválidas caen a 8 bits
+    grayscale: bool,       // Convertir a escala de grises antes de codificar
+    keep_16bit: bool,      // Original de 16 bits sin operaciones de píxeles: escribir 16 bits por canal
+    filters: String, // "Preset" | "None" | "Sub" | "Up" | "Average" | "Paeth" | "All" | "Adaptive"
 }
 
 impl Default for OxiPngOptions {
@@ -18,10 +30,41 @@ impl Default for OxiPngOptions {
         Self {
             level: 2,
             interlace: false,
+            embed_icc: true,
+            strip_metadata: true,
+            bit_depth: None,
+            grayscale: false,
+            keep_16bit: false,
+            filters: "Preset".to_string(),
         }
     }
 }
 
+impl OxiPngOptions {
+    /// Filtros de fila a probar; None conserva los del preset de `level`
+    fn row_filters(&self) -> Option<IndexSet<RowFilter>> {
+        let filters = match self.filters.as_str() {
+            "None" => indexset! {RowFilter::None},
+            "Sub" => indexset! {RowFilter::Sub},
+            "Up" => indexset! {RowFilter::Up},
+            "Average" => indexset! {RowFilter::Average},
+            "Paeth" => indexset! {RowFilter::Paeth},
+            // Los cinco filtros estándar; oxipng se queda con el que mejor comprime
+            "All" => indexset! {
+                RowFilter::None,
+                RowFilter::Sub,
+                RowFilter::Up,
+                RowFilter::Average,
+                RowFilter::Paeth
+            },
+            // Heurística adaptativa clásica (libpng): filtro por fila con mínima suma absoluta
+            "Adaptive" => indexset! {RowFilter::MinSum},
+            _ => return None,
+        };
+        Some(filters)
+    }
+}
+
 impl ImageEncoder for OxiPngCodec {
     fn name(&self) -> &str {
         "oxipng"
@@ -31,8 +74,32 @@ impl ImageEncoder for OxiPngCodec {
         vec!["png"]
     }
 
-    fn encode(&self, image: &DynamicImage, options: &Value) -> Result<EncodingResult, String> {
-        let opts: OxiPngOptions = serde_json::from_value(options.clone()).unwrap_or_default();
+    fn encode(
+        &self,
+        image: RgbaImage,
+        options: &Value,
+        metadata: &ImageMetadata,
+    ) -> Result<EncodingResult, String> {
+        self.encode_with_progress(image, options, metadata, &|_| {})
+    }
+
+    fn encode_with_progress(
+        &self,
+        image: RgbaImage,
+        options: &Value,
+        metadata: &ImageMetadata,
+        progress: &(dyn Fn(&str) + Sync),
+    ) -> Result<EncodingResult, String> {
+        let opts: OxiPngOptions = parse_options(options, &self.options_schema())?;
+
+        // El pipeline trabaja en 8 bits: con keep_16bit se codifica el original de más precisión
+        // (metadata solo lo trae si ninguna etapa cambió los píxeles)
+        let image = match &metadata.high_depth_source {
+            Some(source) if opts.keep_16bit && source.dimensions() == image.dimensions() => {
+                Cow::Borrowed(source.as_ref())
+            }
+            _ => Cow::Owned(DynamicImage::ImageRgba8(image)),
+        };
 
         // Configurar OxiPNG
         let mut oxipng_opts = Options::from_preset(opts.level);
@@ -41,18 +108,34 @@ impl ImageEncoder for OxiPngCodec {
         } else { 
             None 
         };
+        if let Some(filters) = opts.row_filters() {
+            oxipng_opts.filter = filters;
+        }
+        if opts.strip_metadata {
+            // All conserva solo los chunks críticos (y tRNS, necesario para la transparencia)
+            oxipng_opts.strip = oxipng::StripChunks::All;
+        }
 
         // Intentar usar RawImage para evitar doble encoding
         // Si falla, usar el método tradicional como fallback
-        let optimized_bytes = match try_encode_raw(image, &oxipng_opts) {
+        let icc = metadata.icc_to_embed(opts.strip_metadata, opts.embed_icc);
+        let raw = try_encode_raw(&image, &opts, &oxipng_opts, icc, metadata, progress);
+        let optimized_bytes = match raw {
             Ok(bytes) => bytes,
             Err(_) => {
                 // Fallback: encode a PNG primero y luego optimizar
                 let mut raw_png_bytes = Vec::new();
-                image.write_to(&mut Cursor::new(&mut raw_png_bytes), ImageFormat::Png)
+                let mut png_encoder = PngEncoder::new(Cursor::new(&mut raw_png_bytes));
+                if let Some(profile) = icc {
+                    png_encoder
+                        .set_icc_profile(profile.to_vec())
+                        .map_err(|e| e.to_string())?;
+                }
+                image.write_with_encoder(png_encoder)
                     .map_err(|e| e.to_string())?;
-                oxipng::optimize_from_memory(&raw_png_bytes, &oxipng_opts)
-                    .map_err(|e| e.to_string())?
+                optimize_in_trials(&oxipng_opts, progress, |trial| {
+                    oxipng::optimize_from_memory(&raw_png_bytes, trial).map_err(|e| e.to_string())
+                })?
             }
         };
 
@@ -76,27 +159,310 @@ impl ImageEncoder for OxiPngCodec {
                 "type": "checkbox",
                 "label": "Interlace (Adam7)",
                 "default": false
+            },
+            "embed_icc": {
+                "type": "checkbox",
+                "label": "Keep Color Profile (ICC)",
+                "default": true
+            },
+            "strip_metadata": {
+                "type": "checkbox",
+                "label": "Strip Metadata (EXIF/XMP/ICC)",
+                "default": true
+            },
+            "bit_depth": {
+                "type": "select",
+                "label": "Bit Depth",
+                "options": [1, 2, 4, 8, 16],
+                "default": 8
+            },
+            "grayscale": {
+                "type": "checkbox",
+                "label": "Grayscale",
+                "default": false
+            },
+            "keep_16bit": {
+                "type": "checkbox",
+                "label": "Keep 16-bit Depth",
+                "default": false
+            },
+            "filters": {
+                "type": "select",
+                "label": "Row Filters",
+                "options": ["Preset", "None", "Sub", "Up", "Average", "Paeth", "All", "Adaptive"],
+                "default": "Preset"
             }
         })
     }
 }
 
 /// Intenta codificar usando RawImage directamente (evita PNG encode + re-optimize)
-fn try_encode_raw(image: &DynamicImage, opts: &Options) -> Result<Vec<u8>, String> {
+fn try_encode_raw(
+    image: &DynamicImage,
+    settings: &OxiPngOptions,
+    opts: &Options,
+    icc: Option<&[u8]>,
+    metadata: &ImageMetadata,
+    progress: &(dyn Fn(&str) + Sync),
+) -> Result<Vec<u8>, String> {
     let (width, height) = image.dimensions();
     
-    // Siempre usar RGBA para compatibilidad
-    let rgba = image.to_rgba8();
-    let raw_data = rgba.into_raw();
-    
-    let raw_image = RawImage::new(
+    let (color_type, bit_depth, raw_data) =
+        raw_parts(image, settings, metadata.source_palette.as_deref());
+
+    let mut raw_image = RawImage::new(
         width,
         height,
-        oxipng::ColorType::RGBA,
-        oxipng::BitDepth::Eight,
+        color_type,
+        bit_depth,
         raw_data,
-    ).map_err(|e| format!("Error creando RawImage RGBA: {:?}", e))?;
+    ).map_err(|e| format!("Error creando RawImage: {:?}", e))?;
+
+    // oxipng lo escribe como chunk iCCP comprimido
+    if let Some(profile) = icc {
+        raw_image.add_icc_profile(profile);
+    }
     
-    raw_image.create_optimized_png(opts)
-        .map_err(|e| format!("Error optimizando PNG: {:?}", e))
+    optimize_in_trials(opts, progress, |trial| {
+        raw_image
+            .create_optimized_png(trial)
+            .map_err(|e| format!("Error optimizando PNG: {:?}", e))
+    })
+}
+
+/// Optimización exhaustiva como una prueba por filtro de fila, quedándose con el PNG más pequeño
+/// oxipng no expone callbacks: entre prueba y prueba se emite "png-optimizing-trial N of M"
+/// Con evaluación rápida o un solo filtro se hace una única llamada, como antes
+fn optimize_in_trials(
+    opts: &Options,
+    progress: &(dyn Fn(&str) + Sync),
+    optimize: impl Fn(&Options) -> Result<Vec<u8>, String>,
+) -> Result<Vec<u8>, String> {
+    if opts.fast_evaluation || opts.filter.len() < 2 {
+        return optimize(opts);
+    }
+
+    let total = opts.filter.len();
+    let mut best: Option<Vec<u8>> = None;
+    for (i, &filter) in opts.filter.iter().enumerate() {
+        let trial = Options {
+            filter: indexset! {filter},
+            ..opts.clone()
+        };
+        let bytes = optimize(&trial)?;
+        if best.as_ref().is_none_or(|best| bytes.len() < best.len()) {
+            best = Some(bytes);
+        }
+        progress(&format!("png-optimizing-trial {} of {}", i + 1, total));
+    }
+    best.ok_or_else(|| "OxiPNG no produjo ningún resultado".to_string())
+}
+
+/// Elige tipo de color y profundidad para RawImage según el contenido y las opciones
+/// Prioridad: gris opaco, paleta (≤256 colores, p. ej. tras apply_quantize), gris+alpha, RGBA
+/// Si el original era un PNG indexado y los colores siguen en su paleta, se reutiliza esa paleta
+/// Combinaciones inválidas (p. ej. RGBA a 2 bits) caen a 8 bits
+fn raw_parts(
+    image: &DynamicImage,
+    settings: &OxiPngOptions,
+    source_palette: Option<&[[u8; 4]]>,
+) -> (oxipng::ColorType, BitDepth, Vec<u8>) {
+    let requested = settings
+        .bit_depth
+        .and_then(|d| BitDepth::try_from(d).ok())
+        .unwrap_or(BitDepth::Eight);
+
+    // 16 bits solo aporta algo si el original tiene más de 8 bits por canal
+    let wants_16bit = requested == BitDepth::Sixteen || settings.keep_16bit;
+    let high_precision_source = matches!(
+        image.color(),
+        ColorType::L16
+            | ColorType::La16
+            | ColorType::Rgb16
+            | ColorType::Rgba16
+            | ColorType::Rgb32F
+            | ColorType::Rgba32F
+    );
+    if wants_16bit && high_precision_source {
+        return raw_parts_16(image, settings.grayscale);
+    }
+
+    let rgba = if settings.grayscale {
+        Cow::Owned(DynamicImage::ImageLumaA8(image.to_luma_alpha8()).to_rgba8())
+    } else {
+        rgba_pixels(image)
+    };
+    let width = rgba.width() as usize;
+    let is_gray = is_grayscale(&rgba);
+    let opaque = is_opaque(&rgba);
+    let low_bits = match requested {
+        BitDepth::One | BitDepth::Two | BitDepth::Four => Some(requested as u8),
+        _ => None,
+    };
+
+    if is_gray && opaque {
+        let luma: Vec<u8> = rgba.pixels().map(|p| p[0]).collect();
+        let gray = oxipng::ColorType::Grayscale {
+            transparent_shade: None,
+        };
+        return match low_bits {
+            Some(bits) => {
+                // Reescalar 0-255 al rango de la profundidad pedida (con pérdida)
+                let max = (1u32 << bits) - 1;
+                let scaled: Vec<u8> = luma
+                    .iter()
+                    .map(|&v| ((v as u32 * max + 127) / 255) as u8)
+                    .collect();
+                (gray, requested, pack_rows(&scaled, width, bits))
+            }
+            None => (gray, BitDepth::Eight, luma),
+        };
+    }
+
+    if let Some((palette, mut indices)) = palette_reduce(&rgba) {
+        let palette = source_palette
+            .and_then(|source| remap_to_palette(&palette, &mut indices, source))
+            .unwrap_or(palette);
+        return match low_bits {
+            Some(bits) if palette.len() <= 1 << bits => (
+                oxipng::ColorType::Indexed { palette },
+                requested,
+                pack_rows(&indices, width, bits),
+            ),
+            _ => (oxipng::ColorType::Indexed { palette }, BitDepth::Eight, indices),
+        };
+    }
+
+    if is_gray {
+        let luma_alpha: Vec<u8> = rgba.pixels().flat_map(|p| [p[0], p[3]]).collect();
+        return (oxipng::ColorType::GrayscaleAlpha, BitDepth::Eight, luma_alpha);
+    }
+
+    (oxipng::ColorType::RGBA, BitDepth::Eight, rgba.into_owned().into_raw())
+}
+
+/// Variante de 16 bits por canal (muestras big-endian, como exige PNG)
+fn raw_parts_16(image: &DynamicImage, grayscale: bool) -> (oxipng::ColorType, BitDepth, Vec<u8>) {
+    let rgba = if grayscale {
+        DynamicImage::ImageLumaA16(image.to_luma_alpha16()).to_rgba16()
+    } else {
+        image.to_rgba16()
+    };
+    let is_gray = rgba.pixels().all(|p| p[0] == p[1] && p[1] == p[2]);
+    let opaque = rgba.pixels().all(|p| p[3] == u16::MAX);
+
+    let (color_type, samples): (_, Vec<u16>) = match (is_gray, opaque) {
+        (true, true) => (
+            oxipng::ColorType::Grayscale {
+                transparent_shade: None,
+            },
+            rgba.pixels().map(|p| p[0]).collect(),
+        ),
+        (true, false) => (
+            oxipng::ColorType::GrayscaleAlpha,
+            rgba.pixels().flat_map(|p| [p[0], p[3]]).collect(),
+        ),
+        (false, true) => (
+            oxipng::ColorType::RGB {
+                transparent_color: None,
+            },
+            rgba.pixels().flat_map(|p| [p[0], p[1], p[2]]).collect(),
+        ),
+        (false, false) => (oxipng::ColorType::RGBA, rgba.into_raw()),
+    };
+
+    let data = samples.iter().flat_map(|s| s.to_be_bytes()).collect();
+    (color_type, BitDepth::Sixteen, data)
+}
+
+/// Empaqueta muestras de `bits` bits (MSB primero); cada fila se rellena hasta el byte
+fn pack_rows(samples: &[u8], width: usize, bits: u8) -> Vec<u8> {
+    let per_byte = 8 / bits as usize;
+    let mut packed = Vec::with_capacity(samples.len().div_ceil(per_byte));
+    for row in samples.chunks(width.max(1)) {
+        for group in row.chunks(per_byte) {
+            let mut byte = 0u8;
+            for (i, &sample) in group.iter().enumerate() {
+                byte |= sample << (8 - bits as usize * (i + 1));
+            }
+            packed.push(byte);
+        }
+    }
+    packed
+}
+
+/// Reescribe `indices` contra `source` si todos los colores de `palette` están en ella
+/// Devuelve la paleta original completa (mismo orden y tRNS); None deja los índices intactos
+fn remap_to_palette(palette: &[RGBA8], indices: &mut [u8], source: &[[u8; 4]]) -> Option<Vec<RGBA8>> {
+    let positions = palette
+        .iter()
+        .map(|c| {
+            source
+                .iter()
+                .position(|entry| *entry == [c.r, c.g, c.b, c.a])
+                .map(|i| i as u8)
+        })
+        .collect::<Option<Vec<u8>>>()?;
+
+    for index in indices.iter_mut() {
+        *index = positions[*index as usize];
+    }
+    Some(source.iter().map(|&[r, g, b, a]| RGBA8::new(r, g, b, a)).collect())
+}
+
+/// Paleta e índices (un byte por pixel) si la imagen tiene como mucho 256 colores RGBA distintos
+/// Devuelve None en cuanto aparece el color 257
+fn palette_reduce(rgba: &RgbaImage) -> Option<(Vec<RGBA8>, Vec<u8>)> {
+    let mut lookup: HashMap<[u8; 4], u8> = HashMap::new();
+    let mut palette = Vec::new();
+    let mut indices = Vec::with_capacity(rgba.width() as usize * rgba.height() as usize);
+
+    for pixel in rgba.pixels() {
+        let index = match lookup.get(&pixel.0) {
+            Some(&i) => i,
+            None => {
+                if palette.len() == 256 {
+                    return None;
+                }
+                let i = palette.len() as u8;
+                let [r, g, b, a] = pixel.0;
+                palette.push(RGBA8::new(r, g, b, a));
+                lookup.insert(pixel.0, i);
+                i
+            }
+        };
+        indices.push(index);
+    }
+
+    Some((palette, indices))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn encoded_len(image: &DynamicImage, options: Value) -> usize {
+        OxiPngCodec
+            .encode(image.to_rgba8(), &options, &ImageMetadata::default())
+            .expect("encode")
+            .data
+            .len()
+    }
+
+    /// Degradado con textura fina (más de 256 colores: no se reduce a paleta)
+    fn photo_gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let grain = ((x * 7 + y * 13) % 5) as u8;
+            Rgb([(x * 250 / width) as u8 + grain, (y * 250 / height) as u8 + grain, ((x + y) / 4) as u8])
+        }))
+    }
+
+    #[test]
+    fn all_filters_beat_no_filter_on_gradient() {
+        let image = photo_gradient(256, 256);
+        let none = encoded_len(&image, json!({ "filters": "None" }));
+        let all = encoded_len(&image, json!({ "filters": "All" }));
+        assert!(all < none, "All = {} bytes, None = {} bytes", all, none);
+    }
 }
@@ -0,0 +1,44 @@
+use super::traits::{EncodingResult, ImageEncoder, ImageMetadata, is_opaque};
+use image::{DynamicImage, RgbaImage};
+use serde_json::{json, Value};
+
+pub struct QoiCodec;
+
+impl ImageEncoder for QoiCodec {
+    fn name(&self) -> &str {
+        "qoi"
+    }
+
+    fn supported_formats(&self) -> Vec<&str> {
+        vec!["qoi"]
+    }
+
+    fn encode(
+        &self,
+        image: RgbaImage,
+        _options: &Value,
+        _metadata: &ImageMetadata,
+    ) -> Result<EncodingResult, String> {
+        let (width, height) = (image.width(), image.height());
+
+        // QOI guarda 3 o 4 canales según el largo del buffer
+        let result = if is_opaque(&image) {
+            let rgb = DynamicImage::ImageRgba8(image).into_rgb8();
+            qoi::encode_to_vec(rgb.as_raw(), width, height)
+        } else {
+            qoi::encode_to_vec(image.as_raw(), width, height)
+        };
+        let bytes = result.map_err(|e| format!("Error codificando QOI: {}", e))?;
+
+        Ok(EncodingResult {
+            data: bytes,
+            mime_type: "image/qoi".to_string(),
+            extension: "qoi".to_string(),
+        })
+    }
+
+    fn options_schema(&self) -> Value {
+        // QOI no tiene parámetros ajustables
+        json!({})
+    }
+}
@@ -0,0 +1,142 @@
+use super::traits::{
+    EncodingResult, ImageEncoder, ImageMetadata, is_grayscale, is_opaque, parse_options,
+};
+use image::{DynamicImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::io::Cursor;
+use tiff::encoder::colortype::{self, ColorType};
+use tiff::encoder::{Compression, DeflateLevel, Predictor, TiffEncoder, TiffValue};
+use tiff::tags::{Tag, Type};
+
+pub struct TiffCodec;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct TiffOptions {
+    compression: String, // "none" | "lzw" | "deflate" | "packbits"
+    embed_icc: bool,     // Incrustar el perfil ICC del original (tag 34675)
+    strip_metadata: bool, // No escribir ICC (tiene prioridad sobre embed_icc)
+}
+
+impl Default for TiffOptions {
+    fn default() -> Self {
+        Self {
+            compression: "lzw".to_string(),
+            embed_icc: true,
+            strip_metadata: true,
+        }
+    }
+}
+
+impl TiffOptions {
+    /// Compresión y predictor; el predictor horizontal solo está definido para LZW y Deflate
+    fn compression(&self) -> (Compression, Predictor) {
+        match self.compression.as_str() {
+            "lzw" => (Compression::Lzw, Predictor::Horizontal),
+            "deflate" => (Compression::Deflate(DeflateLevel::Best), Predictor::Horizontal),
+            "packbits" => (Compression::Packbits, Predictor::None),
+            _ => (Compression::Uncompressed, Predictor::None),
+        }
+    }
+}
+
+impl ImageEncoder for TiffCodec {
+    fn name(&self) -> &str {
+        "tiff"
+    }
+
+    fn supported_formats(&self) -> Vec<&str> {
+        vec!["tiff", "tif"]
+    }
+
+    fn encode(
+        &self,
+        image: RgbaImage,
+        options: &Value,
+        metadata: &ImageMetadata,
+    ) -> Result<EncodingResult, String> {
+        let opts: TiffOptions = parse_options(options, &self.options_schema())?;
+        let icc = metadata.icc_to_embed(opts.strip_metadata, opts.embed_icc);
+        let (compression, predictor) = opts.compression();
+
+        let mut bytes = Vec::new();
+        let mut encoder = TiffEncoder::new(Cursor::new(&mut bytes))
+            .map_err(|e| format!("Error codificando TIFF: {}", e))?
+            .with_compression(compression)
+            .with_predictor(predictor);
+
+        // RGBA si hay transparencia; si no, gris de un canal o RGB según los píxeles
+        let (width, height) = image.dimensions();
+        let result = if !is_opaque(&image) {
+            write_tiff::<colortype::RGBA8>(&mut encoder, width, height, image.as_raw(), icc)
+        } else if is_grayscale(&image) {
+            let luma: Vec<u8> = image.pixels().map(|p| p[0]).collect();
+            write_tiff::<colortype::Gray8>(&mut encoder, width, height, &luma, icc)
+        } else {
+            let rgb = DynamicImage::ImageRgba8(image).into_rgb8();
+            write_tiff::<colortype::RGB8>(&mut encoder, width, height, rgb.as_raw(), icc)
+        };
+        result.map_err(|e| format!("Error codificando TIFF: {}", e))?;
+
+        Ok(EncodingResult {
+            data: bytes,
+            mime_type: "image/tiff".to_string(),
+            extension: "tiff".to_string(),
+        })
+    }
+
+    fn options_schema(&self) -> Value {
+        json!({
+            "compression": {
+                "type": "select",
+                "label": "Compression",
+                "options": ["none", "lzw", "deflate", "packbits"],
+                "default": "lzw"
+            },
+            "embed_icc": {
+                "type": "checkbox",
+                "label": "Keep Color Profile (ICC)",
+                "default": true
+            },
+            "strip_metadata": {
+                "type": "checkbox",
+                "label": "Strip Metadata (EXIF/XMP/ICC)",
+                "default": true
+            }
+        })
+    }
+}
+
+/// Escribe una imagen (un solo IFD) con las muestras ya en el tipo de color `C`
+fn write_tiff<C: ColorType<Inner = u8>>(
+    encoder: &mut TiffEncoder<Cursor<&mut Vec<u8>>>,
+    width: u32,
+    height: u32,
+    samples: &[u8],
+    icc: Option<&[u8]>,
+) -> tiff::TiffResult<()> {
+    let mut tiff_image = encoder.new_image::<C>(width, height)?;
+    if let Some(profile) = icc {
+        tiff_image.encoder().write_tag(Tag::IccProfile, IccProfile(profile))?;
+    }
+    tiff_image.write_data(samples)
+}
+
+/// Perfil ICC como valor UNDEFINED, el tipo que define la especificación para el tag 34675
+/// (escrito como BYTE, algunos lectores no lo reconocen)
+struct IccProfile<'a>(&'a [u8]);
+
+impl TiffValue for IccProfile<'_> {
+    const BYTE_LEN: u8 = 1;
+    const FIELD_TYPE: Type = Type::UNDEFINED;
+
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0)
+    }
+}
@@ -1,5 +1,9 @@
-use image::DynamicImage;
+use image::{DynamicImage, RgbaImage};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::sync::Arc;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EncodingResult {
@@ -8,6 +12,60 @@ pub struct EncodingResult {
     pub extension: String,
 }
 
+/// Frame de una animación: canvas completo ya compuesto y su duración
+#[derive(Debug, Clone)]
+pub struct AnimationFrame {
+    pub image: RgbaImage,
+    pub delay_ms: u32,
+}
+
+/// Metadatos capturados de la imagen original que los encoders pueden incrustar
+#[derive(Debug, Clone, Default)]
+pub struct ImageMetadata {
+    /// Perfil de color ICC (sin él, las fotos wide-gamut se ven desaturadas)
+    pub icc_profile: Option<Vec<u8>>,
+    /// Bloque EXIF (TIFF) del original tal cual
+    pub source_exif: Option<Vec<u8>>,
+    /// EXIF (TIFF) a incrustar, ya filtrado por metadata_policy; None = no escribir EXIF
+    pub exif_to_embed: Option<Vec<u8>>,
+    /// Paleta RGBA (PLTE + tRNS) si el original es un PNG indexado
+    pub source_palette: Option<Vec<[u8; 4]>>,
+    /// Bytes del archivo si el original es un JPEG cuyos píxeles no se alteraron al cargar
+    /// (sin rotación EXIF ni CMYK): permiten re-optimizarlo sin pérdida
+    pub source_jpeg: Option<Arc<Vec<u8>>>,
+    /// Original con más de 8 bits por canal; solo se pasa si la imagen a codificar no se alteró
+    pub high_depth_source: Option<Arc<DynamicImage>>,
+}
+
+impl ImageMetadata {
+    /// Perfil ICC a incrustar; `strip_metadata` tiene prioridad sobre `embed_icc`
+    pub fn icc_to_embed(&self, strip_metadata: bool, embed_icc: bool) -> Option<&[u8]> {
+        if strip_metadata || !embed_icc {
+            return None;
+        }
+        self.icc_profile.as_deref()
+    }
+}
+
+/// Píxeles RGBA8 de la imagen: prestados si ya es RGBA8 (lo habitual tras resize/quantize)
+/// Solo se convierte (y copia) cuando el tipo de píxel es otro
+pub fn rgba_pixels(image: &DynamicImage) -> Cow<'_, RgbaImage> {
+    match image.as_rgba8() {
+        Some(rgba) => Cow::Borrowed(rgba),
+        None => Cow::Owned(image.to_rgba8()),
+    }
+}
+
+/// true si ningún píxel tiene transparencia (el alpha se puede omitir al codificar)
+pub fn is_opaque(image: &RgbaImage) -> bool {
+    image.pixels().all(|p| p[3] == 255)
+}
+
+/// true si todos los píxeles son grises (R = G = B): basta un canal de luminancia
+pub fn is_grayscale(image: &RgbaImage) -> bool {
+    image.pixels().all(|p| p[0] == p[1] && p[1] == p[2])
+}
+
 #[allow(dead_code)] // Métodos planeados para API futura
 pub trait ImageEncoder: Send + Sync {
     /// Nombre identificador del encoder (ej: "mozjpeg", "oxipng")
@@ -17,8 +75,160 @@ pub trait ImageEncoder: Send + Sync {
     fn supported_formats(&self) -> Vec<&str>;
 
     /// Comprime la imagen con las opciones dadas
-    fn encode(&self, image: &DynamicImage, options: &serde_json::Value) -> Result<EncodingResult, String>;
+    /// `image` llega ya en RGBA8 y en propiedad: el pipeline convierte una sola vez y el encoder
+    /// puede consumir el buffer sin copiarlo. Canales de sobra (alpha opaco, gris) los decide
+    /// cada encoder mirando los píxeles (ver is_opaque/is_grayscale)
+    /// `metadata` contiene lo capturado del original (ICC); cada encoder decide si lo incrusta
+    fn encode(
+        &self,
+        image: RgbaImage,
+        options: &serde_json::Value,
+        metadata: &ImageMetadata,
+    ) -> Result<EncodingResult, String>;
+
+    /// Como encode, pero informando con `progress` de las fases de una codificación larga
+    /// Por defecto no informa de nada; solo lo implementan los encoders con fases separables
+    fn encode_with_progress(
+        &self,
+        image: RgbaImage,
+        options: &serde_json::Value,
+        metadata: &ImageMetadata,
+        progress: &(dyn Fn(&str) + Sync),
+    ) -> Result<EncodingResult, String> {
+        let _ = progress;
+        self.encode(image, options, metadata)
+    }
     
     /// Retorna el esquema de opciones soportadas para generar la UI en el frontend
     fn options_schema(&self) -> serde_json::Value;
+
+    /// Si el formato puede guardar transparencia; si no, el pipeline aplana el alpha antes de codificar
+    fn supports_alpha(&self) -> bool {
+        true
+    }
+}
+
+/// Valida las opciones contra los límites del schema y las deserializa
+/// Ninguna clave es obligatoria a propósito: el frontend solo envía los controles que muestra
+/// (ej: `{ quality: 75 }` al cambiar de encoder) y los presets guardados antes de añadir una opción
+/// no la traen. Las ausentes toman el default del struct (`#[serde(default)]`, igual al del schema);
+/// las que no están en el schema se ignoran
+/// Un valor fuera de rango o de tipo incorrecto es un error (antes se volvía a los defaults en silencio)
+pub fn parse_options<T: DeserializeOwned>(options: &Value, schema: &Value) -> Result<T, String> {
+    let empty = serde_json::Map::new();
+    let entries = match options {
+        Value::Object(map) => map,
+        Value::Null => &empty,
+        _ => return Err("Las opciones del encoder deben ser un objeto".to_string()),
+    };
+
+    for (key, value) in entries {
+        let Some(field) = schema.get(key) else {
+            continue;
+        };
+        // null solo es válido si el schema lo usa como default (opción desactivada)
+        if value.is_null() {
+            if field.get("default").is_some_and(Value::is_null) {
+                continue;
+            }
+            return Err(format!("La opción '{}' no puede ser null", key));
+        }
+
+        match field.get("type").and_then(Value::as_str) {
+            Some("slider") => {
+                let number = value
+                    .as_f64()
+                    .ok_or_else(|| format!("La opción '{}' debe ser numérica (recibido {})", key, value))?;
+                let min = field.get("min").and_then(Value::as_f64);
+                let max = field.get("max").and_then(Value::as_f64);
+                if min.is_some_and(|min| number < min) || max.is_some_and(|max| number > max) {
+                    return Err(format!(
+                        "La opción '{}' está fuera de rango: {} (rango {}-{})",
+                        key,
+                        value,
+                        field.get("min").unwrap_or(&Value::Null),
+                        field.get("max").unwrap_or(&Value::Null),
+                    ));
+                }
+            }
+            Some("checkbox") if !value.is_boolean() => {
+                return Err(format!("La opción '{}' debe ser true/false (recibido {})", key, value));
+            }
+            Some("multiselect") => {
+                let allowed = field.get("options").and_then(Value::as_array);
+                let values = value
+                    .as_array()
+                    .ok_or_else(|| format!("La opción '{}' debe ser una lista (recibido {})", key, value))?;
+                if let Some(invalid) = values
+                    .iter()
+                    .find(|v| allowed.is_some_and(|allowed| !allowed.contains(v)))
+                {
+                    return Err(format!(
+                        "Valor no válido para '{}': {} (opciones: {})",
+                        key,
+                        invalid,
+                        field["options"]
+                    ));
+                }
+            }
+            Some("select") => {
+                let allowed = field.get("options").and_then(Value::as_array);
+                if allowed.is_some_and(|allowed| !allowed.contains(value)) {
+                    return Err(format!(
+                        "Valor no válido para '{}': {} (opciones: {})",
+                        key,
+                        value,
+                        field["options"]
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    serde_json::from_value(Value::Object(entries.clone()))
+        .map_err(|e| format!("Opciones del encoder inválidas: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(default)]
+    struct Options {
+        quality: u8,
+        progressive: bool,
+    }
+
+    impl Default for Options {
+        fn default() -> Self {
+            Self {
+                quality: 75,
+                progressive: true,
+            }
+        }
+    }
+
+    fn schema() -> Value {
+        json!({
+            "quality": { "type": "slider", "min": 0, "max": 100, "default": 75 },
+            "progressive": { "type": "checkbox", "default": true }
+        })
+    }
+
+    #[test]
+    fn missing_keys_take_their_default() {
+        let opts: Options = parse_options(&json!({ "quality": 40 }), &schema()).unwrap();
+        assert_eq!(opts, Options { quality: 40, progressive: true });
+        let opts: Options = parse_options(&Value::Null, &schema()).unwrap();
+        assert_eq!(opts, Options::default());
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!(parse_options::<Options>(&json!({ "quality": 150 }), &schema()).is_err());
+        assert!(parse_options::<Options>(&json!({ "progressive": "yes" }), &schema()).is_err());
+    }
 }
@@ -1,23 +1,76 @@
-use super::traits::{EncodingResult, ImageEncoder};
-use image::DynamicImage;
+use super::traits::{
+    AnimationFrame, EncodingResult, ImageEncoder, ImageMetadata, is_opaque, parse_options,
+};
+use image::RgbaImage;
+use libwebp_sys::WebPImageHint;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
 pub struct WebPCodec;
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 struct WebPOptions {
     quality: f32, // 0.0 - 100.0
+    alpha_quality: f32, // 0.0 - 100.0, compresión del canal alpha en lossy (independiente de quality)
     lossless: bool,
-    // method: i32, // Removed: Not supported by webp 0.3 crate
+    method: i32, // 0-6 (más lento = archivos más pequeños)
+    near_lossless: Option<u8>, // 0-100, implica lossless (100 = sin preprocesado)
+    exact_transparency: bool,  // Conservar el RGB bajo píxeles totalmente transparentes (flag "exact")
+    image_hint: String,        // "default" | "picture" | "photo" | "graph": tipo de contenido para lossless
+    embed_icc: bool,           // Incrustar el perfil ICC del original (chunk ICCP)
+    strip_metadata: bool,      // No escribir EXIF/XMP/ICC (tiene prioridad sobre embed_icc)
 }
 
 impl Default for WebPOptions {
     fn default() -> Self {
         Self {
             quality: 75.0,
+            alpha_quality: 100.0,
             lossless: false,
-            // method: 4,
+            method: 4,
+            near_lossless: None,
+            exact_transparency: false,
+            image_hint: "default".to_string(),
+            embed_icc: true,
+            strip_metadata: true,
+        }
+    }
+}
+
+impl WebPOptions {
+    /// Config avanzada de libwebp: la API simple encode(quality) no expone method
+    fn config(&self) -> Result<webp::WebPConfig, String> {
+        // near_lossless es un preprocesado del modo lossless
+        let lossless = self.lossless || self.near_lossless.is_some();
+
+        let mut config = webp::WebPConfig::new()
+            .map_err(|_| "Error inicializando WebPConfig".to_string())?;
+        config.lossless = i32::from(lossless);
+        config.alpha_compression = i32::from(!lossless);
+        // En lossless, quality es el esfuerzo de compresión (75 como encode_lossless)
+        config.quality = if lossless { 75.0 } else { self.quality.clamp(0.0, 100.0) };
+        config.alpha_quality = self.alpha_quality.clamp(0.0, 100.0).round() as i32;
+        config.method = self.method.clamp(0, 6);
+        if let Some(level) = self.near_lossless {
+            config.near_lossless = i32::from(level.min(100));
+        }
+        // Sin exact, libwebp reescribe el color oculto bajo alpha 0 para comprimir mejor
+        config.exact = i32::from(self.exact_transparency);
+        config.image_hint = self.image_hint();
+        // libwebp solo paraleliza con un hilo extra; se respeta set_thread_count (pool de rayon actual)
+        config.thread_level = i32::from(rayon::current_num_threads() > 1);
+        Ok(config)
+    }
+
+    /// Pista de contenido para el encoder lossless
+    /// La libwebp incluida solo la usa para dimensionar su buffer de salida; el archivo no cambia
+    fn image_hint(&self) -> WebPImageHint {
+        match self.image_hint.as_str() {
+            "picture" => WebPImageHint::WEBP_HINT_PICTURE,
+            "photo" => WebPImageHint::WEBP_HINT_PHOTO,
+            "graph" => WebPImageHint::WEBP_HINT_GRAPH,
+            _ => WebPImageHint::WEBP_HINT_DEFAULT,
         }
     }
 }
@@ -31,26 +84,37 @@ impl ImageEncoder for WebPCodec {
         vec!["webp"]
     }
 
-    fn encode(&self, image: &DynamicImage, options: &Value) -> Result<EncodingResult, String> {
-        let opts: WebPOptions = serde_json::from_value(options.clone()).unwrap_or_default();
+    fn encode(
+        &self,
+        image: RgbaImage,
+        options: &Value,
+        metadata: &ImageMetadata,
+    ) -> Result<EncodingResult, String> {
+        let opts: WebPOptions = parse_options(options, &self.options_schema())?;
+
+        // libwebp descarta por su cuenta un alpha totalmente opaco
+        let encoder = webp::Encoder::from_rgba(image.as_raw(), image.width(), image.height());
+        let config = opts.config()?;
 
-        let encoder = webp::Encoder::from_image(image)
-            .map_err(|e| format!("Error creando WebP encoder: {}", e))?;
+        let memory = encoder
+            .encode_advanced(&config)
+            .map_err(|e| format!("Error codificando WebP: {:?}", e))?;
 
-        let memory = if opts.lossless {
-            encoder.encode_lossless()
+        let icc = metadata.icc_to_embed(opts.strip_metadata, opts.embed_icc);
+        let exif = metadata.exif_to_embed.as_deref();
+        let bytes = if icc.is_some() || exif.is_some() {
+            embed_metadata_chunks(
+                &memory,
+                icc,
+                exif,
+                image.width(),
+                image.height(),
+                !is_opaque(&image),
+            )?
         } else {
-            encoder.encode(opts.quality)
+            memory.to_vec()
         };
 
-        // Note: 'webp' crate handling related to 'method' is implicit in standard encode for simple API,
-        // to use advanced config (method, thread_level) we might need unsafe access or waiting for crate update.
-        // For now, simpler implementation with just Quality/Lossless.
-        // If exact parity with Squoosh method (0-6) is needed, we need to check if 'webp' crate exposes Config or advanced encoding.
-        // Currently 'webp' crate 0.3 exposes simple encode(quality) and encode_lossless().
-
-        let bytes = memory.to_vec();
-
         Ok(EncodingResult {
             data: bytes,
             mime_type: "image/webp".to_string(),
@@ -67,11 +131,237 @@ impl ImageEncoder for WebPCodec {
                 "max": 100,
                 "default": 75
             },
+            "alpha_quality": {
+                "type": "slider",
+                "label": "Alpha Quality",
+                "min": 0,
+                "max": 100,
+                "default": 100
+            },
             "lossless": {
                 "type": "checkbox",
                 "label": "Lossless",
                 "default": false
+            },
+            "method": {
+                "type": "slider",
+                "label": "Effort (Method)",
+                "min": 0,
+                "max": 6,
+                "default": 4
+            },
+            "near_lossless": {
+                "type": "slider",
+                "label": "Near Lossless (implies Lossless)",
+                "min": 0,
+                "max": 100,
+                "default": null
+            },
+            "exact_transparency": {
+                "type": "checkbox",
+                "label": "Preserve Transparent Colors (larger file)",
+                "default": false
+            },
+            "image_hint": {
+                "type": "select",
+                "label": "Image Hint (lossless)",
+                "options": ["default", "picture", "photo", "graph"],
+                "default": "default"
+            },
+            "embed_icc": {
+                "type": "checkbox",
+                "label": "Keep Color Profile (ICC)",
+                "default": true
+            },
+            "strip_metadata": {
+                "type": "checkbox",
+                "label": "Strip Metadata (EXIF/XMP/ICC)",
+                "default": true
             }
         })
     }
 }
+
+/// WebP animado con las mismas opciones que el estático (sin ICC: libwebp mux no lo expone aquí)
+/// Los tiempos se pasan como marcas de inicio acumuladas; libwebp deduce la duración de cada frame
+/// (el crate webp no pasa la marca final, así que el último frame repite la duración del anterior)
+pub fn encode_animated(frames: &[AnimationFrame], options: &Value) -> Result<EncodingResult, String> {
+    let opts: WebPOptions = parse_options(options, &WebPCodec.options_schema())?;
+    let config = opts.config()?;
+
+    let first = frames.first().ok_or("La animación no tiene frames")?;
+    let (width, height) = first.image.dimensions();
+
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    // 0 = repetir indefinidamente
+    encoder.set_loop_count(0);
+    let mut timestamp: i32 = 0;
+    for frame in frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            frame.image.as_raw(),
+            width,
+            height,
+            timestamp,
+        ));
+        timestamp = timestamp.saturating_add(frame.delay_ms.min(i32::MAX as u32) as i32);
+    }
+
+    let memory = encoder
+        .try_encode()
+        .map_err(|e| format!("Error codificando WebP animado: {:?}", e))?;
+
+    Ok(EncodingResult {
+        data: memory.to_vec(),
+        mime_type: "image/webp".to_string(),
+        extension: "webp".to_string(),
+    })
+}
+
+/// Inserta chunks ICCP y EXIF en el contenedor RIFF de libwebp
+/// Solo son válidos en el formato extendido, así que se añade VP8X si no existe
+fn embed_metadata_chunks(
+    webp: &[u8],
+    icc: Option<&[u8]>,
+    exif: Option<&[u8]>,
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+) -> Result<Vec<u8>, String> {
+    const ICC_FLAG: u8 = 0x20;
+    const ALPHA_FLAG: u8 = 0x10;
+    const EXIF_FLAG: u8 = 0x08;
+
+    if webp.len() < 20 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        return Err("WebP inválido: cabecera RIFF no encontrada".to_string());
+    }
+    let chunks = &webp[12..];
+
+    let metadata_len = icc.map_or(0, <[u8]>::len) + exif.map_or(0, <[u8]>::len);
+    let mut body = Vec::with_capacity(webp.len() + metadata_len + 48);
+    body.extend_from_slice(b"WEBP");
+    let mut metadata_flags = 0;
+    if icc.is_some() {
+        metadata_flags |= ICC_FLAG;
+    }
+    if exif.is_some() {
+        metadata_flags |= EXIF_FLAG;
+    }
+
+    let rest = if chunks.starts_with(b"VP8X") && chunks.len() >= 18 {
+        // Ya es extendido (ej: lossy con ALPH): solo activar los flags
+        let mut vp8x = chunks[..18].to_vec();
+        vp8x[8] |= metadata_flags;
+        body.extend_from_slice(&vp8x);
+        &chunks[18..]
+    } else {
+        // VP8/VP8L simple: crear cabecera VP8X (canvas en 24 bits, valor - 1)
+        let mut flags = metadata_flags;
+        if has_alpha && chunks.starts_with(b"VP8L") {
+            flags |= ALPHA_FLAG;
+        }
+        body.extend_from_slice(b"VP8X");
+        body.extend_from_slice(&10u32.to_le_bytes());
+        body.extend_from_slice(&[flags, 0, 0, 0]);
+        body.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        body.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        chunks
+    };
+
+    // ICCP debe ir justo después de VP8X y EXIF tras los datos de imagen
+    if let Some(icc) = icc {
+        push_chunk(&mut body, b"ICCP", icc);
+    }
+    body.extend_from_slice(rest);
+    if let Some(exif) = exif {
+        push_chunk(&mut body, b"EXIF", exif);
+    }
+
+    let mut output = Vec::with_capacity(body.len() + 8);
+    output.extend_from_slice(b"RIFF");
+    output.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    output.extend_from_slice(&body);
+    Ok(output)
+}
+
+/// Añade un chunk RIFF (los chunks se rellenan a tamaño par)
+fn push_chunk(body: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    body.extend_from_slice(fourcc);
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        body.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, Rgb, RgbImage, Rgba};
+
+    fn encoded_len(image: &DynamicImage, options: Value) -> usize {
+        WebPCodec
+            .encode(image.to_rgba8(), &options, &ImageMetadata::default())
+            .expect("encode")
+            .data
+            .len()
+    }
+
+    /// Degradado suave con textura fina, similar a una foto
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let grain = ((x * 7 + y * 13) % 5) as u8;
+            Rgb([(x * 250 / width) as u8 + grain, (y * 255 / height) as u8, ((x + y) / 4) as u8])
+        }))
+    }
+
+    /// Paneles planos con bordes suavizados y una barra degradada, como una captura de pantalla
+    fn screenshot(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            if y < height / 8 {
+                Rgb([(x * 200 / width) as u8 + 30, 60, 120])
+            } else if (x / 6 + y / 9) % 7 == 0 {
+                let edge = ((x * 31 + y * 17) % 96) as u8;
+                Rgb([20 + edge, 20 + edge, 24 + edge])
+            } else {
+                Rgb([240, 240, 236])
+            }
+        }))
+    }
+
+    #[test]
+    fn method_6_is_not_larger_than_method_0() {
+        let image = gradient(256, 256);
+        let fast = encoded_len(&image, json!({ "quality": 75, "method": 0 }));
+        let slow = encoded_len(&image, json!({ "quality": 75, "method": 6 }));
+        assert!(slow <= fast, "method 6 = {} bytes, method 0 = {} bytes", slow, fast);
+    }
+
+    #[test]
+    fn near_lossless_changes_size_on_screenshot() {
+        let image = screenshot(256, 256);
+        let lossless = encoded_len(&image, json!({ "lossless": true }));
+        let near = encoded_len(&image, json!({ "near_lossless": 60 }));
+        assert_ne!(near, lossless);
+    }
+
+    #[test]
+    fn lower_alpha_quality_shrinks_soft_alpha() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(256, 256, |x, y| {
+            let grain = ((x * 7 + y * 13) % 9) as u8;
+            Rgba([200, 120, 40, (x * 240 / 256) as u8 + grain])
+        }));
+        let full = encoded_len(&image, json!({ "alpha_quality": 100 }));
+        let low = encoded_len(&image, json!({ "alpha_quality": 10 }));
+        assert!(low < full, "alpha_quality 10 = {} bytes, 100 = {} bytes", low, full);
+    }
+
+    #[test]
+    fn image_hint_reaches_libwebp_config() {
+        let options = json!({ "lossless": true, "image_hint": "graph" });
+        let opts: WebPOptions = parse_options(&options, &WebPCodec.options_schema()).unwrap();
+        let config = opts.config().unwrap();
+        assert!(matches!(config.image_hint, WebPImageHint::WEBP_HINT_GRAPH));
+        // El esfuerzo lossless no depende de la pista
+        assert_eq!(config.quality, 75.0);
+    }
+}
@@ -11,18 +11,37 @@
 
 mod codecs;
 
-use codecs::{EncodingResult, ImageEncoder, JpegCodec, OxiPngCodec, WebPCodec};
-use fast_image_resize::{images::Image, PixelType, ResizeAlg, ResizeOptions, Resizer};
-use image::{DynamicImage, ImageReader, RgbaImage};
-use parking_lot::RwLock;
+/// Etapas del pipeline para benches/ (no es API estable)
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::codecs::{ImageEncoder, ImageMetadata, OxiPngCodec};
+    pub use crate::{apply_quantize, extract_rgba_data, resize_with_simd};
+}
+
+use codecs::{
+    rgba_pixels, AnimationFrame, AvifCodec, BmpCodec, EncodingResult, GifCodec, IcoCodec,
+    ImageEncoder, ImageMetadata, JpegCodec, OxiPngCodec, QoiCodec, TiffCodec, WebPCodec,
+};
+use fast_image_resize::images::{Image, ImageRef};
+use fast_image_resize::{IntoImageView, MulDiv, PixelType, ResizeAlg, ResizeOptions, Resizer};
+use image::{DynamicImage, ExtendedColorType, ImageDecoder, ImageFormat, ImageReader, RgbaImage};
+use parking_lot::{Mutex, RwLock};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::io::Cursor;
-use std::path::PathBuf;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, Cursor, Seek};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tauri::{Emitter, State};
+use std::time::Instant;
+use tauri::{Emitter, Manager, State};
 use thiserror::Error;
+use uuid::Uuid;
 
+#[cfg(target_os = "windows")]
+use std::path::PathBuf;
 #[cfg(target_os = "windows")]
 use winreg::{enums::*, RegKey};
 
@@ -44,6 +63,14 @@ pub enum WindooshError {
     NoImage,
     #[error("Error de concurrencia: {0}")]
     Concurrency(String),
+    #[error("Operación cancelada")]
+    Cancelled,
+    #[error("Documento no encontrado: {0}")]
+    DocumentNotFound(String),
+    #[error("El archivo ya existe: {0}")]
+    FileExists(String),
+    #[error("Resultado descartado: se inició un procesamiento más reciente")]
+    Superseded,
 }
 
 impl From<WindooshError> for String {
@@ -56,32 +83,745 @@ impl From<WindooshError> for String {
 // Estado Global de la Aplicación (Zero-Copy Architecture)
 // ============================================================================
 
-/// Estado optimizado con Arc para zero-copy sharing entre threads
-pub struct AppState {
+/// Profundidad por defecto del historial undo/redo
+/// Cada entrada es una imagen a resolución completa: el límite acota la memoria
+const DEFAULT_HISTORY_DEPTH: usize = 10;
+
+/// Máximo de píxeles por defecto al cargar (256 MP ≈ 1 GB en RGBA)
+/// Protege de cabeceras que declaran dimensiones enormes (decompression bombs)
+const DEFAULT_MAX_PIXELS: u64 = 256_000_000;
+
+/// Presupuesto de memoria por defecto: sin límite (set_memory_budget lo activa)
+const UNLIMITED_MEMORY_BUDGET: u64 = u64::MAX;
+
+/// Imágenes que deja un procesamiento: la entrada del encoder y el resultado re-decodificado
+/// En formatos sin pérdida ambas son el mismo Arc
+#[derive(Clone)]
+pub struct ProcessedImages {
+    /// Imagen ya redimensionada/ajustada, antes de codificar
+    pub preencode: Arc<DynamicImage>,
+    /// Imagen con los artefactos de compresión (lo que se ve tras decodificar el archivo)
+    pub preview: Arc<DynamicImage>,
+}
+
+impl ProcessedImages {
+    /// Bytes de píxeles retenidos (la misma imagen compartida cuenta una vez)
+    fn size_bytes(&self) -> usize {
+        let preview = self.preview.as_bytes().len();
+        if Arc::ptr_eq(&self.preencode, &self.preview) {
+            preview
+        } else {
+            preview + self.preencode.as_bytes().len()
+        }
+    }
+}
+
+/// Historial de imágenes procesadas para undo/redo
+/// Una sola estructura bajo un RwLock para que entries e index nunca se desincronicen
+pub struct ProcessedHistory {
+    entries: Vec<ProcessedImages>,
+    /// Posición de la entrada actual (solo válida si entries no está vacío)
+    index: usize,
+    max_depth: usize,
+}
+
+impl ProcessedHistory {
+    fn new(max_depth: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            index: 0,
+            max_depth: max_depth.max(1),
+        }
+    }
+
+    /// Agrega un resultado; descarta la rama de redo y las entradas más antiguas sobre el límite
+    fn push(&mut self, img: ProcessedImages) {
+        if !self.entries.is_empty() {
+            self.entries.truncate(self.index + 1);
+        }
+        self.entries.push(img);
+        self.trim();
+        self.index = self.entries.len() - 1;
+    }
+
+    fn undo(&mut self) -> Option<ProcessedImages> {
+        if self.entries.is_empty() || self.index == 0 {
+            return None;
+        }
+        self.index -= 1;
+        Some(self.entries[self.index].clone())
+    }
+
+    fn redo(&mut self) -> Option<ProcessedImages> {
+        if self.index + 1 >= self.entries.len() {
+            return None;
+        }
+        self.index += 1;
+        Some(self.entries[self.index].clone())
+    }
+
+    fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth.max(1);
+        self.trim();
+    }
+
+    /// Ajusta al límite eliminando primero las más antiguas y luego la rama de redo
+    /// La entrada actual nunca se descarta
+    fn trim(&mut self) {
+        let excess = self.entries.len().saturating_sub(self.max_depth);
+        let oldest = excess.min(self.index);
+        self.entries.drain(..oldest);
+        self.index -= oldest;
+        self.entries.truncate(self.max_depth);
+    }
+
+    /// Conserva solo la entrada actual (se pierde undo/redo para liberar memoria)
+    fn retain_current(&mut self) {
+        if self.entries.is_empty() {
+            return;
+        }
+        self.entries.drain(..self.index);
+        self.entries.truncate(1);
+        self.index = 0;
+    }
+}
+
+/// Límite por defecto de la caché de resultados (bytes codificados + píxeles procesados)
+const DEFAULT_RESULT_CACHE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Resultado de process_image guardado en caché
+#[derive(Clone)]
+struct CachedResult {
+    encoded: Arc<EncodingResult>,
+    images: ProcessedImages,
+}
+
+impl CachedResult {
+    fn size_bytes(&self) -> usize {
+        self.encoded.data.len() + self.images.size_bytes()
+    }
+}
+
+/// Caché LRU de resultados acotada por bytes totales
+/// El VecDeque mantiene el orden de uso: al frente la entrada usada hace más tiempo
+pub struct ResultCache {
+    entries: VecDeque<(u64, CachedResult)>,
+    total_bytes: usize,
+    max_bytes: usize,
+}
+
+impl ResultCache {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            total_bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Clave: hash de la fuente (path del original) y del request serializado
+    /// serde_json::Map es un BTreeMap, así que la serialización es determinista
+    fn key(source: &str, request: &OptimizationRequest) -> Option<u64> {
+        let serialized = serde_json::to_string(request).ok()?;
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        serialized.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    /// Devuelve la entrada y la marca como la más reciente
+    fn get(&mut self, key: u64) -> Option<CachedResult> {
+        let pos = self.entries.iter().position(|(k, _)| *k == key)?;
+        let entry = self.entries.remove(pos)?;
+        let value = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(value)
+    }
+
+    /// Inserta desalojando las entradas más antiguas hasta que quepa
+    /// Un resultado mayor que el límite completo no se cachea
+    fn insert(&mut self, key: u64, value: CachedResult) {
+        let size = value.size_bytes();
+        if size > self.max_bytes {
+            return;
+        }
+        if let Some(pos) = self.entries.iter().position(|(k, _)| *k == key) {
+            if let Some((_, old)) = self.entries.remove(pos) {
+                self.total_bytes -= old.size_bytes();
+            }
+        }
+        while self.total_bytes + size > self.max_bytes {
+            match self.entries.pop_front() {
+                Some((_, old)) => self.total_bytes -= old.size_bytes(),
+                None => break,
+            }
+        }
+        self.entries.push_back((key, value));
+        self.total_bytes += size;
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.total_bytes = 0;
+    }
+
+    fn values(&self) -> impl Iterator<Item = &CachedResult> {
+        self.entries.iter().map(|(_, value)| value)
+    }
+}
+
+/// Suma de bytes de buffers compartidos: cada Arc cuenta una sola vez
+/// (historial, caché y documento suelen apuntar a las mismas imágenes)
+#[derive(Default)]
+struct ArcTally {
+    seen: HashSet<usize>,
+    bytes: u64,
+}
+
+impl ArcTally {
+    fn add<T>(&mut self, arc: &Arc<T>, len: usize) {
+        if self.seen.insert(Arc::as_ptr(arc) as usize) {
+            self.bytes += len as u64;
+        }
+    }
+
+    fn add_image(&mut self, img: &Arc<DynamicImage>) {
+        self.add(img, img.as_bytes().len());
+    }
+
+    fn add_images(&mut self, images: &ProcessedImages) {
+        self.add_image(&images.preencode);
+        self.add_image(&images.preview);
+    }
+}
+
+/// Documento abierto (una pestaña del editor): original, resultado y estado asociado
+pub struct Document {
     /// Imagen original envuelta en Arc para compartir sin clonar bytes
-    pub original_image: RwLock<Option<Arc<DynamicImage>>>,
+    pub original_image: Arc<DynamicImage>,
     /// Última imagen procesada (para preview canvas)
-    pub processed_image: RwLock<Option<Arc<DynamicImage>>>,
-    /// Path del archivo original
-    pub original_path: RwLock<Option<String>>,
+    pub processed_image: Option<Arc<DynamicImage>>,
+    /// La misma imagen antes de codificar (comparación A/B sin artefactos)
+    pub preencode_image: Option<Arc<DynamicImage>>,
+    /// Path del archivo original (None para imágenes del portapapeles)
+    pub original_path: Option<String>,
     /// Tamaño original en bytes
-    pub original_size: RwLock<usize>,
+    pub original_size: usize,
     /// Última metadata de optimización
-    pub last_optimization: RwLock<Option<OptimizationMetadata>>,
+    pub last_optimization: Option<OptimizationMetadata>,
+    /// Perfil ICC del original (se re-incrusta al codificar)
+    pub original_icc: Option<Vec<u8>>,
+    /// EXIF (TIFF) del original; solo se re-incrusta filtrado por metadata_policy
+    pub original_exif: Option<Vec<u8>>,
+    /// Paleta RGBA (PLTE + tRNS) si el original es un PNG indexado
+    pub original_palette: Option<Vec<[u8; 4]>>,
+    /// Formato real según el contenido (None si no vino de un archivo codificado, p. ej. portapapeles)
+    pub detected_format: Option<ImageFormat>,
+    /// Hash de los píxeles RGBA del original (ver content_hash)
+    pub content_hash: String,
+    /// Token de cancelación: se revisa entre etapas del pipeline
+    pub cancel_flag: Arc<AtomicBool>,
+    /// Historial de resultados de process_image para undo/redo
+    pub history: ProcessedHistory,
+    /// Archivo original comprimido, solo para imágenes animadas
+    pub animation_source: Option<Arc<Vec<u8>>>,
+    /// Archivo original si es un JPEG re-optimizable sin pérdida (ver jpeg_lossless_optimize)
+    pub jpeg_source: Option<Arc<Vec<u8>>>,
+    /// Original con su profundidad de más de 8 bits por canal (PNG keep_16bit)
+    pub high_depth_source: Option<Arc<DynamicImage>>,
+    /// RGBA del original ya extraído para el canvas (se llena en la primera petición)
+    pub original_rgba: Option<Arc<ImageDataRaw>>,
+    /// RGBA de processed_image; se vacía cada vez que cambia la imagen procesada
+    pub processed_rgba: Option<Arc<ImageDataRaw>>,
+    /// Versión del último trabajo que reemplaza la imagen procesada (process_image, optimize_to_target,
+    /// preview_quantize, undo/redo); uno más antiguo no puede pisar su resultado
+    pub process_version: u64,
+}
+
+/// Datos que necesita un trabajo del pipeline, copiados del documento (Arc::clone = O(1))
+/// Así el lock del mapa de documentos no se mantiene durante el procesamiento
+struct PipelineJob {
+    image: Arc<DynamicImage>,
+    original_size: usize,
+    metadata: ImageMetadata,
+    cancel_flag: Arc<AtomicBool>,
+}
+
+impl Document {
+    fn pipeline_job(&self) -> PipelineJob {
+        PipelineJob {
+            image: Arc::clone(&self.original_image),
+            original_size: self.original_size,
+            metadata: ImageMetadata {
+                icc_profile: self.original_icc.clone(),
+                source_exif: self.original_exif.clone(),
+                exif_to_embed: None,
+                source_palette: self.original_palette.clone(),
+                source_jpeg: self.jpeg_source.clone(),
+                high_depth_source: self.high_depth_source.clone(),
+            },
+            cancel_flag: Arc::clone(&self.cancel_flag),
+        }
+    }
+
+    /// Registra un nuevo resultado como actual y lo agrega al historial
+    fn set_processed(&mut self, images: ProcessedImages, metadata: OptimizationMetadata) {
+        self.show_processed(images.clone());
+        self.history.push(images);
+        self.last_optimization = Some(metadata);
+    }
+
+    /// Cambia la imagen procesada visible e invalida su RGBA cacheado
+    fn show_processed(&mut self, images: ProcessedImages) {
+        self.processed_image = Some(images.preview);
+        self.preencode_image = Some(images.preencode);
+        self.processed_rgba = None;
+    }
+
+    fn tally_memory(&self, tally: &mut ArcTally) {
+        tally.add_image(&self.original_image);
+        if let Some(source) = &self.high_depth_source {
+            tally.add_image(source);
+        }
+        for img in [&self.processed_image, &self.preencode_image].into_iter().flatten() {
+            tally.add_image(img);
+        }
+        for images in &self.history.entries {
+            tally.add_images(images);
+        }
+        for rgba in [&self.original_rgba, &self.processed_rgba].into_iter().flatten() {
+            tally.add(rgba, rgba.data.len());
+        }
+        for source in [&self.animation_source, &self.jpeg_source].into_iter().flatten() {
+            tally.add(source, source.len());
+        }
+    }
+
+    /// Descarta lo que se puede regenerar: RGBA del canvas e historial fuera de la entrada actual
+    fn release_derived(&mut self) {
+        self.original_rgba = None;
+        self.processed_rgba = None;
+        self.history.retain_current();
+    }
+}
+
+impl PipelineJob {
+    /// Estimación de lo que reserva process_pipeline (RGBA8): una copia de trabajo a tamaño
+    /// de entrada más la imagen pre-encode y el preview a tamaño de salida
+    fn estimated_bytes(&self, request: &OptimizationRequest) -> u64 {
+        let ((w, h), (out_w, out_h)) = self.stage_dimensions(request);
+        rgba_bytes(w, h) + 2 * rgba_bytes(out_w, out_h)
+    }
+
+    /// Dimensiones tras crop/transform/aspect_crop (entrada del resize) y de salida (resize y lienzo), sin procesar nada
+    fn stage_dimensions(&self, request: &OptimizationRequest) -> ((u32, u32), (u32, u32)) {
+        let (src_w, src_h) = (self.image.width(), self.image.height());
+        let (mut w, mut h) = request.crop.as_ref().map_or((src_w, src_h), |crop| {
+            (crop.width.min(src_w), crop.height.min(src_h))
+        });
+        if request
+            .transform
+            .as_ref()
+            .is_some_and(|t| t.rotate == 90 || t.rotate == 270)
+        {
+            std::mem::swap(&mut w, &mut h);
+        }
+        if let Some(rect) = request
+            .aspect_crop
+            .as_deref()
+            .and_then(|ratio| parse_aspect_ratio(ratio).ok())
+            .and_then(|ratio| aspect_crop_rect(w, h, ratio))
+        {
+            (w, h) = (rect.width, rect.height);
+        }
+        let (out_w, out_h) = request
+            .resize
+            .as_ref()
+            .and_then(|opts| resolve_resize_options(w, h, opts))
+            .map_or((w, h), |opts| plan_resize_dimensions(w, h, &opts));
+        let (out_w, out_h) = request
+            .pad_to
+            .as_ref()
+            .map_or((out_w, out_h), |pad| (pad.width, pad.height));
+        ((w, h), (out_w, out_h))
+    }
+}
+
+/// Constructor de un encoder registrado
+type EncoderFactory = Box<dyn Fn() -> Box<dyn ImageEncoder> + Send + Sync>;
+
+/// Encoders disponibles por nombre, poblado al arrancar
+/// Añadir un códec es una línea de `register`; un nombre desconocido es un error, no JPEG
+pub struct EncoderRegistry {
+    factories: HashMap<String, EncoderFactory>,
+}
+
+impl Default for EncoderRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            factories: HashMap::new(),
+        };
+        registry.register("mozjpeg", || Box::new(JpegCodec));
+        // Alias histórico de mozjpeg
+        registry.register("jpeg", || Box::new(JpegCodec));
+        registry.register("oxipng", || Box::new(OxiPngCodec));
+        registry.register("webp", || Box::new(WebPCodec));
+        registry.register("avif", || Box::new(AvifCodec));
+        registry.register("gif", || Box::new(GifCodec));
+        registry.register("qoi", || Box::new(QoiCodec));
+        registry.register("bmp", || Box::new(BmpCodec));
+        registry.register("tiff", || Box::new(TiffCodec));
+        registry.register("ico", || Box::new(IcoCodec));
+        #[cfg(feature = "jxl")]
+        registry.register("jxl", || Box::new(codecs::JxlCodec));
+        registry
+    }
+}
+
+impl EncoderRegistry {
+    /// Registra (o reemplaza) el encoder asociado a `name`
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn() -> Box<dyn ImageEncoder> + Send + Sync + 'static,
+    {
+        self.factories.insert(name.to_string(), Box::new(factory));
+    }
+
+    /// Crea una instancia del encoder pedido
+    pub fn create(&self, name: &str) -> Result<Box<dyn ImageEncoder>, WindooshError> {
+        self.factories
+            .get(name)
+            .map(|factory| factory())
+            .ok_or_else(|| WindooshError::Encoding(format!("unknown encoder: {}", name)))
+    }
+
+    /// Encoders registrados (sin alias), ordenados por nombre
+    pub fn encoders(&self) -> Vec<Box<dyn ImageEncoder>> {
+        let mut encoders: Vec<_> = self
+            .factories
+            .iter()
+            .map(|(name, factory)| (name, factory()))
+            .filter(|(name, encoder)| encoder.name() == name.as_str())
+            .map(|(_, encoder)| encoder)
+            .collect();
+        encoders.sort_by(|a, b| a.name().cmp(b.name()));
+        encoders
+    }
+}
+
+/// Hilos por defecto para procesar y codificar: el paralelismo disponible
+fn default_thread_count() -> usize {
+    num_cpus::get().max(1)
+}
+
+/// Hilos que usan el pipeline y los encoders (set_thread_count)
+/// Con el valor por defecto no se crea pool propio: se usa el global de rayon
+#[derive(Clone)]
+pub struct WorkerPool {
+    threads: usize,
+    pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+impl Default for WorkerPool {
+    fn default() -> Self {
+        Self {
+            threads: default_thread_count(),
+            pool: None,
+        }
+    }
+}
+
+impl WorkerPool {
+    fn new(threads: usize) -> Result<Self, WindooshError> {
+        let threads = threads.max(1);
+        if threads == default_thread_count() {
+            return Ok(Self::default());
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(|e| WindooshError::Concurrency(e.to_string()))?;
+        Ok(Self {
+            threads,
+            pool: Some(Arc::new(pool)),
+        })
+    }
+
+    /// Ejecuta `op` dentro del pool: el trabajo rayon anidado (oxipng, AVIF, imagequant, resize)
+    /// y el multihilo de libwebp quedan limitados a `threads` hilos
+    fn install<T: Send>(&self, op: impl FnOnce() -> T + Send) -> T {
+        match &self.pool {
+            Some(pool) => pool.install(op),
+            None => op(),
+        }
+    }
+}
+
+/// Estado optimizado con Arc para zero-copy sharing entre threads
+/// Cada imagen abierta es un Document identificado por UUID
+pub struct AppState {
+    pub documents: RwLock<HashMap<Uuid, Document>>,
+    /// Profundidad del historial undo/redo para documentos nuevos
+    pub history_depth: RwLock<usize>,
+    /// Máximo de píxeles (ancho × alto) que se aceptan al decodificar
+    pub max_pixels: RwLock<u64>,
+    /// Aplicar la orientación EXIF al decodificar (false = píxeles tal cual vienen en el archivo)
+    pub auto_orient: RwLock<bool>,
+    /// Caché LRU de process_image; get() reordena, por eso Mutex y no RwLock
+    pub result_cache: Mutex<ResultCache>,
+    /// Encoders disponibles por nombre (solo lectura tras el arranque)
+    pub encoders: EncoderRegistry,
+    /// Serializa las lecturas-modificaciones de presets.json
+    pub presets_lock: Mutex<()>,
+    /// Bytes máximos retenidos en imágenes y cachés antes de rechazar decodes/procesados
+    pub memory_budget: RwLock<u64>,
+    /// Hilos de procesado/codificación; se clona (Arc) para usarlo fuera del lock
+    pub worker_pool: RwLock<WorkerPool>,
+    /// Contador de versiones de la imagen procesada (monótono, compartido por todos los documentos)
+    pub process_version: AtomicU64,
 }
 
 impl Default for AppState {
     fn default() -> Self {
         Self {
-            original_image: RwLock::new(None),
-            processed_image: RwLock::new(None),
-            original_path: RwLock::new(None),
-            original_size: RwLock::new(0),
-            last_optimization: RwLock::new(None),
+            documents: RwLock::new(HashMap::new()),
+            history_depth: RwLock::new(DEFAULT_HISTORY_DEPTH),
+            max_pixels: RwLock::new(DEFAULT_MAX_PIXELS),
+            auto_orient: RwLock::new(true),
+            result_cache: Mutex::new(ResultCache::new(DEFAULT_RESULT_CACHE_BYTES)),
+            encoders: EncoderRegistry::default(),
+            presets_lock: Mutex::new(()),
+            memory_budget: RwLock::new(UNLIMITED_MEMORY_BUDGET),
+            worker_pool: RwLock::new(WorkerPool::default()),
+            process_version: AtomicU64::new(0),
+        }
+    }
+}
+
+impl AppState {
+    /// Ajustes de decodificación vigentes (se copian para usarlos fuera de los locks)
+    fn load_settings(&self) -> LoadSettings {
+        LoadSettings {
+            max_pixels: *self.max_pixels.read(),
+            auto_orient: *self.auto_orient.read(),
+        }
+    }
+
+    /// Registra una imagen recién decodificada como documento nuevo
+    fn open_document(&self, loaded: LoadedImage, path: Option<String>, name: String) -> ImageInfo {
+        let id = Uuid::new_v4();
+        let palette_colors = loaded.palette.as_ref().map_or(0, |p| p.len() as u32);
+        let has_alpha = loaded.image.color().has_alpha();
+        let uses_transparency = uses_transparency(&loaded.image);
+        let content_hash = content_hash(&loaded.image);
+        let document = Document {
+            original_image: loaded.image,
+            processed_image: None,
+            preencode_image: None,
+            original_path: path,
+            original_size: loaded.file_size,
+            last_optimization: None,
+            original_icc: loaded.icc_profile,
+            original_exif: loaded.exif,
+            original_palette: loaded.palette,
+            detected_format: loaded.format,
+            content_hash: content_hash.clone(),
+            cancel_flag: Arc::new(AtomicBool::new(false)),
+            history: ProcessedHistory::new(*self.history_depth.read()),
+            animation_source: loaded.animation_source,
+            jpeg_source: loaded.jpeg_source,
+            high_depth_source: loaded.high_depth_source,
+            original_rgba: None,
+            processed_rgba: None,
+            process_version: 0,
+        };
+        self.documents.write().insert(id, document);
+
+        ImageInfo {
+            id: id.to_string(),
+            width: loaded.width,
+            height: loaded.height,
+            original_size: loaded.file_size,
+            name,
+            orientation_corrected: loaded.orientation_corrected,
+            exif_orientation: loaded.exif_orientation,
+            original_color_type: format!("{:?}", loaded.original_color_type),
+            palette_colors,
+            detected_format: loaded.format.map(format_name),
+            has_alpha,
+            uses_transparency,
+            content_hash,
+            is_animated: loaded.frame_count > 1,
+            frame_count: loaded.frame_count,
+            read_ms: 0.0,
+            decode_ms: 0.0,
+        }
+    }
+
+    /// Ejecuta `f` con el documento en modo lectura (el lock solo dura lo que dura `f`)
+    fn with_document<T>(
+        &self,
+        doc_id: &str,
+        f: impl FnOnce(&Document) -> Result<T, WindooshError>,
+    ) -> Result<T, WindooshError> {
+        let id = parse_doc_id(doc_id)?;
+        let documents = self.documents.read();
+        let document = documents
+            .get(&id)
+            .ok_or_else(|| WindooshError::DocumentNotFound(doc_id.to_string()))?;
+        f(document)
+    }
+
+    /// Ejecuta `f` con el documento en modo escritura
+    fn with_document_mut<T>(
+        &self,
+        doc_id: &str,
+        f: impl FnOnce(&mut Document) -> Result<T, WindooshError>,
+    ) -> Result<T, WindooshError> {
+        let id = parse_doc_id(doc_id)?;
+        let mut documents = self.documents.write();
+        let document = documents
+            .get_mut(&id)
+            .ok_or_else(|| WindooshError::DocumentNotFound(doc_id.to_string()))?;
+        f(document)
+    }
+
+    /// Versión para un trabajo que va a reemplazar la imagen procesada de un documento
+    fn next_process_version(&self) -> u64 {
+        self.process_version.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Inicia un trabajo que reemplazará la imagen procesada: `f` lee lo que necesita del documento
+    /// y la versión devuelta deja sin efecto a los trabajos iniciados antes (ver commit_processed)
+    fn begin_processing<T>(
+        &self,
+        doc_id: &str,
+        f: impl FnOnce(&Document) -> Result<T, WindooshError>,
+    ) -> Result<(u64, T), WindooshError> {
+        let version = self.next_process_version();
+        let value = self.with_document_mut(doc_id, |doc| {
+            doc.process_version = version;
+            f(doc)
+        })?;
+        Ok((version, value))
+    }
+
+    /// Reemplaza la imagen procesada con `f` (set_processed/show_processed) salvo que otro trabajo
+    /// más nuevo haya empezado después de `version`
+    fn commit_processed(
+        &self,
+        doc_id: &str,
+        version: u64,
+        f: impl FnOnce(&mut Document),
+    ) -> Result<(), WindooshError> {
+        self.with_document_mut(doc_id, |doc| {
+            if doc.process_version != version {
+                return Err(WindooshError::Superseded);
+            }
+            f(doc);
+            Ok(())
+        })
+    }
+
+    /// RGBA de la original (o de la procesada) para el canvas
+    /// Se extrae una sola vez por imagen y las siguientes peticiones se sirven desde el documento
+    async fn document_rgba(
+        &self,
+        doc_id: &str,
+        processed: bool,
+    ) -> Result<Arc<ImageDataRaw>, WindooshError> {
+        let (img_arc, cached) = self.with_document(doc_id, |doc| {
+            if processed {
+                let img = doc.processed_image.clone().ok_or(WindooshError::NoImage)?;
+                Ok((img, doc.processed_rgba.clone()))
+            } else {
+                Ok((Arc::clone(&doc.original_image), doc.original_rgba.clone()))
+            }
+        })?;
+        if let Some(data) = cached {
+            return Ok(data);
+        }
+
+        // Extraer RGBA en thread pool (puede ser pesado para imágenes 4K+)
+        // El documento conserva la imagen: se extrae de una copia
+        let source = Arc::clone(&img_arc);
+        let data = tauri::async_runtime::spawn_blocking(move || {
+            extract_rgba_data(Arc::unwrap_or_clone(source))
+        })
+            .await
+            .map_err(|e| WindooshError::Concurrency(e.to_string()))?;
+        let data = Arc::new(data);
+
+        // Si la procesada cambió mientras se extraía (process_image, undo...), no se cachea
+        self.with_document_mut(doc_id, |doc| {
+            if !processed {
+                doc.original_rgba = Some(Arc::clone(&data));
+            } else if doc
+                .processed_image
+                .as_ref()
+                .is_some_and(|current| Arc::ptr_eq(current, &img_arc))
+            {
+                doc.processed_rgba = Some(Arc::clone(&data));
+            }
+            Ok(())
+        })?;
+
+        Ok(data)
+    }
+}
+
+impl AppState {
+    /// Bytes retenidos por documentos y caché de resultados
+    fn memory_usage(&self) -> MemoryUsage {
+        let mut tally = ArcTally::default();
+        for doc in self.documents.read().values() {
+            doc.tally_memory(&mut tally);
+        }
+        let documents_bytes = tally.bytes;
+        for entry in self.result_cache.lock().values() {
+            tally.add(&entry.encoded, entry.encoded.data.len());
+            tally.add_images(&entry.images);
+        }
+        let budget = *self.memory_budget.read();
+
+        MemoryUsage {
+            documents_bytes,
+            cache_bytes: tally.bytes - documents_bytes,
+            total_bytes: tally.bytes,
+            budget: (budget != UNLIMITED_MEMORY_BUDGET).then_some(budget),
+        }
+    }
+
+    /// Comprueba que caben `needed` bytes más antes de reservarlos
+    /// Si no caben se libera primero la caché de resultados y después RGBA e historial de los documentos;
+    /// originales e imágenes procesadas visibles no se tocan
+    fn reserve_memory(&self, needed: u64) -> Result<(), WindooshError> {
+        let budget = *self.memory_budget.read();
+        let fits = || self.memory_usage().total_bytes.saturating_add(needed) <= budget;
+        if fits() {
+            return Ok(());
+        }
+
+        self.result_cache.lock().clear();
+        if fits() {
+            return Ok(());
+        }
+
+        for doc in self.documents.write().values_mut() {
+            doc.release_derived();
+        }
+        if fits() {
+            return Ok(());
         }
+
+        Err(WindooshError::Processing("out of memory budget".into()))
     }
 }
 
+fn parse_doc_id(doc_id: &str) -> Result<Uuid, WindooshError> {
+    Uuid::parse_str(doc_id).map_err(|_| WindooshError::DocumentNotFound(doc_id.to_string()))
+}
+
 // ============================================================================
 // DTOs (Data Transfer Objects)
 // ============================================================================
@@ -89,10 +829,36 @@ impl Default for AppState {
 /// Información básica de la imagen (sin datos de píxeles)
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ImageInfo {
+    /// Id del documento (doc_id en el resto de comandos)
+    pub id: String,
     pub width: u32,
     pub height: u32,
     pub original_size: usize,
     pub name: String,
+    /// true si se rotó/espejó la imagen según su orientación EXIF
+    pub orientation_corrected: bool,
+    /// Tag Orientation del EXIF (1-8) sin aplicar porque auto_orient está desactivado
+    pub exif_orientation: Option<u32>,
+    /// Tipo de color del archivo ("Rgb8", "Rgba16", "Cmyk8"...); todo se procesa en 8 bits RGB(A)
+    pub original_color_type: String,
+    /// Entradas de la paleta si el original es un PNG indexado (0 si no lo es)
+    pub palette_colors: u32,
+    /// Formato real detectado por contenido ("PNG", "JPEG"...), sin importar la extensión
+    /// None para imágenes sin archivo codificado (portapapeles)
+    pub detected_format: Option<String>,
+    /// La imagen tiene canal alpha (aunque sea totalmente opaco)
+    pub has_alpha: bool,
+    /// Algún píxel es realmente transparente (alpha < 255); JPEG lo perdería
+    pub uses_transparency: bool,
+    /// Hash de los píxeles decodificados: mismos píxeles = mismo hash aunque cambien formato o metadatos
+    pub content_hash: String,
+    /// GIF/WebP/APNG con más de un frame; solo se carga el primero
+    pub is_animated: bool,
+    pub frame_count: u32,
+    /// ms leyendo el archivo de disco (0 si la imagen no vino de un archivo)
+    pub read_ms: f64,
+    /// ms decodificando (0 si no se midió)
+    pub decode_ms: f64,
 }
 
 /// Datos raw de imagen para canvas rendering (RGBA)
@@ -105,25 +871,275 @@ pub struct ImageDataRaw {
     pub data: Vec<u8>,
 }
 
+/// Memoria retenida en imágenes y cachés (los buffers compartidos cuentan una vez)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MemoryUsage {
+    /// Originales, procesadas, historial y RGBA del canvas de todos los documentos
+    pub documents_bytes: u64,
+    /// Caché de resultados que no está ya en algún documento
+    pub cache_bytes: u64,
+    pub total_bytes: u64,
+    /// None = sin límite
+    pub budget: Option<u64>,
+}
+
+/// Fragmento del buffer RGBA para transferir imágenes grandes por partes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImageDataChunk {
+    pub width: u32,
+    pub height: u32,
+    /// Tamaño total del buffer RGBA (width × height × 4)
+    pub total_len: usize,
+    /// Offset en bytes del inicio de este fragmento
+    pub offset: usize,
+    /// RGBA raw bytes del rango [offset, offset + data.len())
+    pub data: Vec<u8>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ResizeOptionsDto {
     pub width: u32,
     pub height: u32,
+    /// "Lanczos3" | "CatmullRom" | "Mitchell" | "Gaussian" | "Hamming" | "Bilinear" (o "Triangle")
+    /// | "Box" | "Nearest"; otro valor es un error. Vacío = según resize_quality
+    #[serde(default)]
     pub filter: String,
+    /// "fast" | "balanced" | "best" (Bilinear, CatmullRom, Lanczos3); `filter` tiene prioridad
+    #[serde(default)]
+    pub resize_quality: String,
+    /// Mantener la proporción del original dentro de la caja width×height
+    #[serde(default)]
+    pub lock_aspect: bool,
+    /// "stretch" | "contain" | "cover" (vacío = "stretch")
+    #[serde(default)]
+    pub fit: String,
+    /// Escala relativa al original (50.0 = 50%); si está presente ignora width/height/fit
+    #[serde(default)]
+    pub scale_percent: Option<f32>,
+    /// Límite para el lado más largo preservando proporción (no-op si ya es menor)
+    #[serde(default)]
+    pub max_dimension: Option<u32>,
+    /// Redimensionar en luz lineal en lugar de sRGB (reducciones sin oscurecer el detalle fino)
+    #[serde(default)]
+    pub linear_light: bool,
+    /// No ampliar: si el objetivo supera al original, la imagen pasa sin cambios
+    #[serde(default)]
+    pub no_upscale: bool,
+}
+
+impl ResizeOptionsDto {
+    /// Filtro efectivo: el explícito si lo hay, si no el de resize_quality (por defecto Lanczos3)
+    fn effective_filter(&self) -> &str {
+        if !self.filter.is_empty() {
+            return &self.filter;
+        }
+        match self.resize_quality.as_str() {
+            "fast" => "Bilinear",
+            "balanced" => "CatmullRom",
+            _ => "Lanczos3",
+        }
+    }
+}
+
+/// Rectángulo de recorte en píxeles del original (se ajusta a los límites)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CropOptionsDto {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Rotación (0/90/180/270, sentido horario) y espejado
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransformDto {
+    #[serde(default)]
+    pub rotate: u16,
+    #[serde(default)]
+    pub flip_h: bool,
+    #[serde(default)]
+    pub flip_v: bool,
+}
+
+/// Recorte automático de bordes de color uniforme (capturas de pantalla)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrimDto {
+    /// Diferencia máxima por canal con el color de la esquina para considerar un píxel borde
+    pub tolerance: u8,
+}
+
+/// Lienzo de tamaño exacto sobre el que se coloca la imagen tras el resize (huecos publicitarios)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PadDto {
+    pub width: u32,
+    pub height: u32,
+    /// Color RGBA del relleno
+    pub color: [u8; 4],
+    /// "center" | "top-left" | "top" | "top-right" | "left" | "right" | "bottom-left" | "bottom" | "bottom-right" (vacío = "center")
+    #[serde(default)]
+    pub anchor: String,
+}
+
+/// Marca de agua: imagen superpuesta en una esquina antes de codificar
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WatermarkDto {
+    /// Archivo de la marca (PNG con alpha, normalmente) tal cual se leyó
+    pub image: Vec<u8>,
+    /// Mismos valores que PadDto::anchor (vacío = "bottom-right")
+    #[serde(default)]
+    pub anchor: String,
+    /// 0.0 (invisible) a 1.0 (alpha original de la marca)
+    pub opacity: f32,
+    /// Separación en píxeles con los bordes de la imagen
+    #[serde(default)]
+    pub margin: u32,
+    /// Tamaño máximo de la marca como fracción del ancho/alto de la imagen (0.2 = 20%)
+    pub scale: f32,
+}
+
+/// Máscara de enfoque (unsharp mask) tras el resize
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SharpenDto {
+    /// Intensidad como multiplicador (1.0 = 100%); 0 desactiva el paso
+    pub amount: f32,
+    /// Sigma del desenfoque gaussiano en píxeles
+    pub radius: f32,
+    /// Diferencia mínima por canal para enfocar (evita realzar ruido)
+    #[serde(default)]
+    pub threshold: u8,
+}
+
+/// Ajustes tonales en rango -100..100 (0 = sin cambio)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AdjustmentsDto {
+    #[serde(default)]
+    pub brightness: f32,
+    #[serde(default)]
+    pub contrast: f32,
+    #[serde(default)]
+    pub saturation: f32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct QuantizeOptionsDto {
     pub num_colors: u32,
+    /// Intensidad del tramado (0.0-1.0)
     pub dither: f32,
+    /// "floyd_steinberg" | "ordered" | "none" (vacío = "floyd_steinberg")
+    #[serde(default)]
+    pub dither_algorithm: String,
+    /// Paleta RGBA fija (2-256 colores); si está, num_colors se ignora y no se genera paleta
+    pub fixed_palette: Option<Vec<[u8; 4]>>,
+    /// Velocidad de imagequant 1-10 (10 = preview rápido, 1 = exportación); sin valor, según el tamaño
+    pub speed: Option<u8>,
+    /// Calidad mínima 0-100: por debajo la quantización falla en lugar de dar una paleta pobre
+    pub min_quality: Option<u8>,
+    /// Calidad máxima 0-100: imagequant usa menos colores si la alcanza antes
+    pub max_quality: Option<u8>,
+}
+
+impl QuantizeOptionsDto {
+    /// Nivel de difusión de error para imagequant: con "ordered" el tramado se hace aparte
+    fn diffusion_level(&self) -> Result<f32, WindooshError> {
+        match self.dither_algorithm.as_str() {
+            "" | "floyd_steinberg" => Ok(self.dither),
+            "ordered" | "none" => Ok(0.0),
+            other => Err(WindooshError::Processing(format!(
+                "Algoritmo de tramado inválido: {}",
+                other
+            ))),
+        }
+    }
+
+    fn ordered_dither(&self) -> bool {
+        self.dither_algorithm == "ordered"
+    }
+
+    fn tuning(&self) -> QuantizerTuning {
+        let default = QuantizerTuning::default();
+        QuantizerTuning {
+            speed: self.speed.unwrap_or(default.speed),
+            min_quality: self.min_quality.unwrap_or(default.min_quality),
+            max_quality: self.max_quality.unwrap_or(default.max_quality),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OptimizationRequest {
     pub encoder_name: String,
     pub options: Value,
+    pub crop: Option<CropOptionsDto>,
+    pub transform: Option<TransformDto>,
+    /// Recorta bordes uniformes tras crop/transform y antes del resize
+    pub auto_trim: Option<TrimDto>,
+    /// Proporción "ancho:alto" ("16:9", "1:1", "1.91:1"): recorte centrado más grande antes del resize
+    pub aspect_crop: Option<String>,
     pub resize: Option<ResizeOptionsDto>,
+    pub sharpen: Option<SharpenDto>,
+    pub adjustments: Option<AdjustmentsDto>,
+    /// Extiende el lienzo a un tamaño exacto; error si el contenido no cabe
+    pub pad_to: Option<PadDto>,
+    pub watermark: Option<WatermarkDto>,
     pub quantize: Option<QuantizeOptionsDto>,
+    /// Color RGB sobre el que se compone el alpha; sin él, solo se aplana (sobre el color del borde) para formatos sin alpha
+    pub flatten_background: Option<[u8; 3]>,
+    /// Convertir a escala de grises; JPEG y PNG escriben entonces un solo canal
+    #[serde(default)]
+    pub grayscale: bool,
+    /// Original JPEG sin operaciones que alteren píxeles: re-optimizar sus bytes sin pérdida
+    /// (Huffman óptimo + progresivo, sin metadatos) en lugar de decodificar y re-codificar
+    #[serde(default)]
+    pub jpeg_lossless_optimize: bool,
+    /// save_image escribe el archivo original si la re-codificación ocupa más
+    /// (solo si el formato de salida es el del original y ninguna etapa cambia los píxeles;
+    /// si el encoder quita o filtra metadatos, solo un JPEG y re-optimizado sin ellos)
+    #[serde(default)]
+    pub keep_original_if_larger: bool,
+    /// Categorías EXIF del original a conservar ("datetime", "camera", "gps", "copyright")
+    /// JPEG y WebP re-incrustan solo esos tags aunque strip_metadata esté activo; None = según el encoder
+    pub metadata_policy: Option<Vec<String>>,
+}
+
+impl OptimizationRequest {
+    /// true si alguna etapa cambia los píxeles del original
+    /// Un flatten_background explícito cuenta aunque la imagen sea opaca (el aplanado implícito
+    /// para encoders sin alpha depende del encoder y lo comprueba el pipeline)
+    fn alters_pixels(&self) -> bool {
+        self.crop.is_some()
+            || self.transform.is_some()
+            || self.auto_trim.is_some()
+            || self.aspect_crop.is_some()
+            || self.resize.is_some()
+            || self.sharpen.is_some()
+            || self.adjustments.is_some()
+            || self.pad_to.is_some()
+            || self.watermark.is_some()
+            || self.quantize.is_some()
+            || self.flatten_background.is_some()
+            || self.grayscale
+    }
+
+    /// Bytes del JPEG original a re-optimizar sin pérdida; None si el modo no aplica a esta petición
+    fn lossless_jpeg_source<'a>(&self, metadata: &'a ImageMetadata) -> Option<&'a [u8]> {
+        let jpeg_output = matches!(self.encoder_name.as_str(), "mozjpeg" | "jpeg");
+        if !self.jpeg_lossless_optimize || !jpeg_output || self.alters_pixels() {
+            return None;
+        }
+        metadata.source_jpeg.as_deref().map(Vec::as_slice)
+    }
+
+    /// Perfil ICC que escribe el encoder según strip_metadata/embed_icc (ambos true por defecto)
+    fn icc_to_embed<'a>(&self, metadata: &'a ImageMetadata) -> Option<&'a [u8]> {
+        let option = |key: &str| self.options.get(key).and_then(Value::as_bool).unwrap_or(true);
+        metadata.icc_to_embed(option("strip_metadata"), option("embed_icc"))
+    }
+
+    /// true si el encoder conserva los metadatos: strip_metadata = false explícito y sin metadata_policy
+    fn keeps_metadata(&self) -> bool {
+        self.metadata_policy.is_none()
+            && self.options.get("strip_metadata").and_then(Value::as_bool) == Some(false)
+    }
 }
 
 /// Resultado de optimización - ya no incluye preview_base64
@@ -132,411 +1148,4241 @@ pub struct OptimizationRequest {
 pub struct OptimizationResult {
     pub optimized_size: usize,
     pub savings_percent: f32,
+    /// El resultado ocupa más que el original (savings_percent negativo)
+    pub size_increased: bool,
     pub mime_type: String,
     pub extension: String,
+    #[serde(flatten)]
+    pub timings: StageTimings,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OptimizationMetadata {
     pub optimized_size: usize,
     pub savings_percent: f32,
+    /// El resultado ocupa más que el original (savings_percent negativo)
+    pub size_increased: bool,
     pub mime_type: String,
     pub extension: String,
+    #[serde(flatten)]
+    pub timings: StageTimings,
+}
+
+/// Duración de las etapas costosas del pipeline en ms (0 si la etapa no se ejecutó)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct StageTimings {
+    pub resize_ms: f64,
+    pub quantize_ms: f64,
+    pub encode_ms: f64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SaveResult {
     pub path: String,
     pub final_size: usize,
+    /// Se escribió el archivo original porque la re-codificación ocupaba más (keep_original_if_larger)
+    pub kept_original: bool,
 }
 
-// ============================================================================
+/// Payload del evento "process-progress": etapa del pipeline que empieza
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProcessProgress {
+    pub stage: String,
+}
+
+/// Payload del evento "process-complete" (process_image_async_event)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProcessComplete {
+    pub job_id: String,
+    pub result: OptimizationResult,
+}
+
+/// Payload del evento "process-error" (process_image_async_event)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProcessError {
+    pub job_id: String,
+    pub error: String,
+}
+
+/// Métricas objetivas entre original y procesada
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QualityMetrics {
+    /// PSNR en dB sobre RGB (imágenes idénticas = 100)
+    pub psnr: f64,
+    /// SSIM medio de luminancia, ventanas 8×8 (1.0 = idénticas)
+    pub ssim: f64,
+}
+
+/// Histograma por canal (256 buckets cada uno)
+/// Vec en lugar de [u32; 256]: serde solo implementa arrays de hasta 32 elementos
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistogramData {
+    pub r: Vec<u32>,
+    pub g: Vec<u32>,
+    pub b: Vec<u32>,
+    pub luma: Vec<u32>,
+}
+
+/// Número de colores RGBA distintos del original (count_unique_colors)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UniqueColors {
+    pub count: usize,
+    /// true si se dejó de contar al superar el tope: hay al menos `count` colores
+    pub capped: bool,
+}
+
+/// Encoder recomendado para el original, con el motivo en una línea (suggest_encoder)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EncoderSuggestion {
+    pub encoder: String,
+    pub reason: String,
+}
+
+/// Resultado de un encoder en compare_all_codecs (error = Some si ese encoder falló)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CodecComparison {
+    pub size: usize,
+    pub savings_percent: f32,
+    pub mime_type: String,
+    pub error: Option<String>,
+}
+
+/// Resultado por archivo de process_batch (error = Some si ese archivo falló)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchItemResult {
+    pub path: String,
+    pub output_path: Option<String>,
+    pub original_size: usize,
+    pub optimized_size: usize,
+    pub error: Option<String>,
+}
+
+/// Payload del evento "batch-progress", emitido al terminar cada archivo
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchProgress {
+    pub completed: usize,
+    pub total: usize,
+    pub path: String,
+}
+
+/// Resultado de optimize_to_target
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TargetSizeResult {
+    pub optimized_size: usize,
+    pub savings_percent: f32,
+    /// Calidad final usada (1-100)
+    pub quality: u8,
+    /// Número de codificaciones realizadas en la búsqueda
+    pub iterations: u32,
+    pub mime_type: String,
+    pub extension: String,
+}
+
+/// Resultado de estimate_size
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SizeEstimate {
+    pub estimated_size: usize,
+    pub savings_percent: f32,
+    /// false si la extrapolación es poco fiable (formatos sin pérdida o proxy diminuto)
+    /// Las salidas pequeñas se codifican enteras: el tamaño es exacto y confident = true
+    pub confident: bool,
+}
+
+/// Resultado de process_animated
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AnimatedResult {
+    pub frame_count: u32,
+    pub total_size: usize,
+    pub savings_percent: f32,
+    pub mime_type: String,
+    pub extension: String,
+}
+
+/// Encoder disponible y los formatos que produce (list_encoders)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EncoderInfo {
+    pub name: String,
+    pub formats: Vec<String>,
+}
+
+// ============================================================================
 // Helpers
 // ============================================================================
 
-fn get_encoder(name: &str) -> Box<dyn ImageEncoder> {
-    match name {
-        "oxipng" => Box::new(OxiPngCodec),
-        "mozjpeg" | "jpeg" => Box::new(JpegCodec),
-        "webp" => Box::new(WebPCodec),
-        _ => Box::new(JpegCodec),
+/// Porcentaje de ahorro respecto al original (0 si no se conoce el tamaño original)
+fn savings_percent(original_size: usize, optimized_size: usize) -> f32 {
+    if original_size > 0 {
+        ((original_size as f32 - optimized_size as f32) / original_size as f32) * 100.0
+    } else {
+        0.0
+    }
+}
+
+/// Escala `value` por `num / den` redondeando la mitad hacia arriba (mínimo 1px)
+/// Aritmética entera para que el resultado sea determinista: 1999 * 1/2 → 1000
+fn scale_dim(value: u32, num: u32, den: u32) -> u32 {
+    let scaled = (value as u64 * num as u64 * 2 + den as u64) / (2 * den as u64);
+    scaled.max(1) as u32
+}
+
+/// Calcula las dimensiones del resize según scale_percent/lock_aspect/fit
+/// - scale_percent: proporcional al original, mínimo 1×1
+/// - stretch sin lock: dimensiones exactas pedidas
+/// - stretch con lock / contain: la imagen cabe dentro de la caja
+/// - cover: la imagen cubre la caja (luego se recorta al centro)
+fn plan_resize_dimensions(src_w: u32, src_h: u32, opts: &ResizeOptionsDto) -> (u32, u32) {
+    if let Some(percent) = opts.scale_percent {
+        let factor = percent.max(0.0) as f64 / 100.0;
+        let scale = |v: u32| ((v as f64 * factor).round() as u32).max(1);
+        return (scale(src_w), scale(src_h));
+    }
+
+    let (box_w, box_h) = (opts.width.max(1), opts.height.max(1));
+    let cover = opts.fit == "cover";
+    if !opts.lock_aspect && !cover && opts.fit != "contain" {
+        return (box_w, box_h);
+    }
+
+    // El lado limitante es el que tiene menor escala (contain) o mayor (cover)
+    let width_limited = (box_w as u64 * src_h as u64 <= box_h as u64 * src_w as u64) != cover;
+    if width_limited {
+        (box_w, scale_dim(src_h, box_w, src_w))
+    } else {
+        (scale_dim(src_w, box_h, src_h), box_h)
+    }
+}
+
+/// Resuelve max_dimension a dimensiones concretas antes de llamar a resize_with_simd
+/// Retorna None si no hace falta redimensionar (el lado más largo ya cabe)
+fn resolve_resize_options(
+    src_w: u32,
+    src_h: u32,
+    opts: &ResizeOptionsDto,
+) -> Option<ResizeOptionsDto> {
+    let Some(cap) = opts.max_dimension else {
+        return Some(opts.clone());
+    };
+
+    let longest = src_w.max(src_h);
+    if longest <= cap {
+        return None;
+    }
+
+    let cap = cap.max(1);
+    let (width, height) = if src_w >= src_h {
+        (cap, scale_dim(src_h, cap, src_w))
+    } else {
+        (scale_dim(src_w, cap, src_h), cap)
+    };
+
+    Some(ResizeOptionsDto {
+        width,
+        height,
+        lock_aspect: false,
+        fit: String::new(),
+        scale_percent: None,
+        max_dimension: None,
+        ..opts.clone()
+    })
+}
+
+/// Resize completo: calcula dimensiones, redimensiona con SIMD y aplica el fit
+/// "cover" recorta al centro de la caja, "contain" rellena con padding transparente
+#[doc(hidden)]
+pub fn resize_with_simd(
+    src: &DynamicImage,
+    opts: &ResizeOptionsDto,
+) -> Result<DynamicImage, WindooshError> {
+    let (src_w, src_h) = (src.width(), src.height());
+    let (mut target_width, mut target_height) = plan_resize_dimensions(src_w, src_h, opts);
+
+    // no_upscale se evalúa antes del resize: así el caso "igual al original" no toca la imagen
+    // Con proporción fija se conserva el original tal cual (sin fit); estirando, se limita cada eje
+    let mut skip_fit = false;
+    if opts.no_upscale && (target_width > src_w || target_height > src_h) {
+        let stretch = opts.scale_percent.is_none()
+            && !opts.lock_aspect
+            && opts.fit != "cover"
+            && opts.fit != "contain";
+        if stretch {
+            target_width = target_width.min(src_w);
+            target_height = target_height.min(src_h);
+        } else {
+            (target_width, target_height) = (src_w, src_h);
+            skip_fit = true;
+        }
+    }
+
+    let resized = if opts.linear_light {
+        resize_linear_light(src, target_width, target_height, opts.effective_filter())?
+    } else if src_w as u64 * src_h as u64 >= TILED_RESIZE_MIN_PIXELS {
+        resize_tiled(src, target_width, target_height, opts.effective_filter())?
+    } else {
+        resize_exact_simd(src, target_width, target_height, opts.effective_filter())?
+    };
+
+    // En modo porcentaje no hay caja a la que ajustar
+    if opts.scale_percent.is_some() || skip_fit {
+        return Ok(resized);
+    }
+
+    let (box_w, box_h) = (opts.width.max(1), opts.height.max(1));
+    match opts.fit.as_str() {
+        "cover" if (target_width, target_height) != (box_w, box_h) => {
+            let x = (target_width - box_w) / 2;
+            let y = (target_height - box_h) / 2;
+            Ok(resized.crop_imm(x, y, box_w, box_h))
+        }
+        "contain" if (target_width, target_height) != (box_w, box_h) => {
+            let mut canvas = RgbaImage::new(box_w, box_h);
+            let x = ((box_w - target_width) / 2) as i64;
+            let y = ((box_h - target_height) / 2) as i64;
+            image::imageops::replace(&mut canvas, &*rgba_pixels(&resized), x, y);
+            Ok(DynamicImage::ImageRgba8(canvas))
+        }
+        _ => Ok(resized),
     }
 }
 
-/// Resize usando fast_image_resize con SIMD automático
+/// Resize exacto usando fast_image_resize con SIMD automático
 /// Detecta y usa AVX2, SSE4.1, o NEON según disponibilidad
-fn resize_with_simd(
+fn resize_exact_simd(
     src: &DynamicImage,
     target_width: u32,
     target_height: u32,
     filter: &str,
 ) -> Result<DynamicImage, WindooshError> {
-    let src_rgba = src.to_rgba8();
+    // Si ya es RGBA8 (lo habitual tras crop/transform de un PNG) se lee sin convertir ni copiar
+    let src_rgba = rgba_pixels(src);
     let (src_w, src_h) = src_rgba.dimensions();
 
     // Si las dimensiones son iguales, no hay que hacer resize
     if src_w == target_width && src_h == target_height {
-        return Ok(DynamicImage::ImageRgba8(src_rgba));
+        return Ok(DynamicImage::ImageRgba8(src_rgba.into_owned()));
     }
 
-    // Crear imagen fuente para fast_image_resize
-    let src_image = Image::from_vec_u8(src_w, src_h, src_rgba.into_raw(), PixelType::U8x4)
+    // Imágenes totalmente opacas no necesitan premultiplicar
+    let has_transparency = src_rgba.pixels().any(|p| p[3] < 255);
+
+    // Crear imagen fuente para fast_image_resize sobre el buffer prestado
+    let src_image = ImageRef::new(src_w, src_h, src_rgba.as_raw(), PixelType::U8x4)
         .map_err(|e| WindooshError::Processing(format!("Error creando imagen fuente: {}", e)))?;
 
     // Crear imagen destino
     let mut dst_image = Image::new(target_width, target_height, PixelType::U8x4);
 
+    resize_buffer(&src_image, &mut dst_image, filter, has_transparency, None)?;
+
+    // Convertir de vuelta a DynamicImage
+    let dst_buffer = dst_image.into_vec();
+    let rgba_image = RgbaImage::from_raw(target_width, target_height, dst_buffer)
+        .ok_or_else(|| WindooshError::Processing("Error creando imagen de destino".into()))?;
+
+    Ok(DynamicImage::ImageRgba8(rgba_image))
+}
+
+/// Píxeles de origen a partir de los cuales el resize se hace por teselas
+/// Por debajo, una sola pasada de fast_image_resize es más rápida
+const TILED_RESIZE_MIN_PIXELS: u64 = 100_000_000;
+
+/// Lado aproximado (en píxeles del origen) de cada tesela del resize por teselas
+const RESIZE_TILE_SOURCE_SIDE: f64 = 2048.0;
+
+/// Mayor soporte (radio) de los filtros disponibles: Lanczos3 y Gaussian
+const RESIZE_MAX_FILTER_SUPPORT: f64 = 3.0;
+
+/// Resize por teselas para entradas gigantes (panorámicas de varios gigapíxeles)
+/// Cada tesela del destino se calcula desde su ventana del origen más un margen igual al radio
+/// del filtro, así el resultado coincide con el de una sola pasada y no hay costuras.
+/// El origen nunca se convierte a RGBA completo: solo la ventana de cada tesela
+fn resize_tiled(
+    src: &DynamicImage,
+    target_width: u32,
+    target_height: u32,
+    filter: &str,
+) -> Result<DynamicImage, WindooshError> {
+    let (src_w, src_h) = (src.width(), src.height());
+    if src_w == target_width && src_h == target_height {
+        return Ok(DynamicImage::ImageRgba8(src.to_rgba8()));
+    }
+
+    let scale_x = src_w as f64 / target_width as f64;
+    let scale_y = src_h as f64 / target_height as f64;
+    // Al reducir, el kernel se ensancha en proporción a la escala (igual que en fast_image_resize)
+    let margin_x = (RESIZE_MAX_FILTER_SUPPORT * scale_x.max(1.0)).ceil() as u32 + 1;
+    let margin_y = (RESIZE_MAX_FILTER_SUPPORT * scale_y.max(1.0)).ceil() as u32 + 1;
+    let tile_w = ((RESIZE_TILE_SOURCE_SIDE / scale_x) as u32).clamp(1, target_width);
+    let tile_h = ((RESIZE_TILE_SOURCE_SIDE / scale_y) as u32).clamp(1, target_height);
+
+    let row_bytes = target_width as usize * 4;
+    let mut out = vec![0u8; row_bytes * target_height as usize];
+
+    // Una franja de teselas por tarea: cada una escribe solo sus filas del destino
+    out.par_chunks_mut(row_bytes * tile_h as usize)
+        .enumerate()
+        .try_for_each(|(band, band_pixels)| -> Result<(), WindooshError> {
+            let dy = band as u32 * tile_h;
+            let h = (band_pixels.len() / row_bytes) as u32;
+            let (y0, y1) = (dy as f64 * scale_y, (dy + h) as f64 * scale_y);
+            let top = (y0.floor() as u32).saturating_sub(margin_y);
+            let bottom = (y1.ceil() as u32 + margin_y).min(src_h);
+
+            for dx in (0..target_width).step_by(tile_w as usize) {
+                let w = tile_w.min(target_width - dx);
+                let (x0, x1) = (dx as f64 * scale_x, (dx + w) as f64 * scale_x);
+                let left = (x0.floor() as u32).saturating_sub(margin_x);
+                let right = (x1.ceil() as u32 + margin_x).min(src_w);
+
+                let window = src.crop_imm(left, top, right - left, bottom - top).into_rgba8();
+                let has_transparency = window.pixels().any(|p| p[3] < 255);
+                let (window_w, window_h) = window.dimensions();
+                let src_image =
+                    Image::from_vec_u8(window_w, window_h, window.into_raw(), PixelType::U8x4)
+                        .map_err(|e| {
+                            WindooshError::Processing(format!("Error creando tesela: {}", e))
+                        })?;
+                let mut dst_image = Image::new(w, h, PixelType::U8x4);
+                let crop = [x0 - left as f64, y0 - top as f64, x1 - x0, y1 - y0];
+                resize_buffer(&src_image, &mut dst_image, filter, has_transparency, Some(crop))?;
+
+                let tile_row_bytes = w as usize * 4;
+                let offset = dx as usize * 4;
+                for (dst_row, tile_row) in band_pixels
+                    .chunks_exact_mut(row_bytes)
+                    .zip(dst_image.buffer().chunks_exact(tile_row_bytes))
+                {
+                    dst_row[offset..offset + tile_row_bytes].copy_from_slice(tile_row);
+                }
+            }
+            Ok(())
+        })?;
+
+    RgbaImage::from_raw(target_width, target_height, out)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| WindooshError::Processing("Error creando imagen de destino".into()))
+}
+
+/// Tabla sRGB (8 bits) → luz lineal (16 bits), calculada una sola vez
+fn srgb_to_linear_lut() -> &'static [u16; 256] {
+    static LUT: std::sync::OnceLock<[u16; 256]> = std::sync::OnceLock::new();
+    LUT.get_or_init(|| {
+        let mut lut = [0u16; 256];
+        for (i, entry) in lut.iter_mut().enumerate() {
+            let c = i as f32 / 255.0;
+            let linear = if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            };
+            *entry = (linear * 65535.0).round() as u16;
+        }
+        lut
+    })
+}
+
+/// Inversa de la tabla: valor sRGB cuyo lineal es el más cercano (búsqueda binaria, tabla monótona)
+fn linear_to_srgb(lut: &[u16; 256], value: u16) -> u8 {
+    match lut.binary_search(&value) {
+        Ok(i) => i as u8,
+        Err(0) => 0,
+        Err(256) => 255,
+        Err(i) => {
+            if value - lut[i - 1] <= lut[i] - value {
+                (i - 1) as u8
+            } else {
+                i as u8
+            }
+        }
+    }
+}
+
+/// Resize en luz lineal: sRGB → lineal de 16 bits, resize en U16x4 y vuelta a sRGB
+/// 16 bits evitan el banding en sombras que tendría un lineal de 8 bits
+fn resize_linear_light(
+    src: &DynamicImage,
+    target_width: u32,
+    target_height: u32,
+    filter: &str,
+) -> Result<DynamicImage, WindooshError> {
+    // Solo se lee: el buffer lineal de 16 bits es nuevo de todos modos
+    let src_rgba = rgba_pixels(src);
+    let (src_w, src_h) = src_rgba.dimensions();
+    if src_w == target_width && src_h == target_height {
+        return Ok(DynamicImage::ImageRgba8(src_rgba.into_owned()));
+    }
+
+    let lut = srgb_to_linear_lut();
+    let has_transparency = src_rgba.pixels().any(|p| p[3] < 255);
+
+    let mut linear = vec![0u8; src_rgba.as_raw().len() * 2];
+    linear
+        .par_chunks_exact_mut(8)
+        .zip(src_rgba.as_raw().par_chunks_exact(4))
+        .for_each(|(dst, px)| {
+            let values = [
+                lut[px[0] as usize],
+                lut[px[1] as usize],
+                lut[px[2] as usize],
+                // Alpha es lineal por definición: solo se expande a 16 bits
+                px[3] as u16 * 257,
+            ];
+            for (bytes, v) in dst.chunks_exact_mut(2).zip(values) {
+                bytes.copy_from_slice(&v.to_ne_bytes());
+            }
+        });
+
+    let src_image = Image::from_vec_u8(src_w, src_h, linear, PixelType::U16x4)
+        .map_err(|e| WindooshError::Processing(format!("Error creando imagen fuente: {}", e)))?;
+    let mut dst_image = Image::new(target_width, target_height, PixelType::U16x4);
+
+    resize_buffer(&src_image, &mut dst_image, filter, has_transparency, None)?;
+
+    let dst_linear = dst_image.into_vec();
+    let mut srgb = vec![0u8; target_width as usize * target_height as usize * 4];
+    srgb.par_chunks_exact_mut(4)
+        .zip(dst_linear.par_chunks_exact(8))
+        .for_each(|(dst, px)| {
+            let value = |c: usize| u16::from_ne_bytes([px[c * 2], px[c * 2 + 1]]);
+            dst[0] = linear_to_srgb(lut, value(0));
+            dst[1] = linear_to_srgb(lut, value(1));
+            dst[2] = linear_to_srgb(lut, value(2));
+            dst[3] = ((value(3) as u32 + 128) / 257) as u8;
+        });
+
+    let rgba_image = RgbaImage::from_raw(target_width, target_height, srgb)
+        .ok_or_else(|| WindooshError::Processing("Error creando imagen de destino".into()))?;
+
+    Ok(DynamicImage::ImageRgba8(rgba_image))
+}
+
+thread_local! {
+    /// Resizer reutilizado por hilo: conserva sus buffers internos y la detección de CPU (AVX2/SSE4.1)
+    /// Es seguro reutilizarlo con tamaños y tipos de píxel distintos en cada llamada
+    static RESIZER: std::cell::RefCell<Resizer> = std::cell::RefCell::new(Resizer::new());
+}
+
+/// Ejecuta `f` con el Resizer del hilo actual
+/// Si ya está en uso (rayon puede robar otra tarea de resize en este hilo mientras espera), usa uno nuevo
+fn with_resizer<T>(f: impl FnOnce(&mut Resizer) -> T) -> T {
+    RESIZER.with(|cell| match cell.try_borrow_mut() {
+        Ok(mut resizer) => f(&mut resizer),
+        Err(_) => f(&mut Resizer::new()),
+    })
+}
+
+/// Ejecuta el resize de fast_image_resize sobre buffers ya creados (U8x4 o U16x4)
+/// El origen puede ser prestado: si hay que premultiplicar, se hace sobre una copia
+/// `crop` = [left, top, width, height] de la ventana del origen a redimensionar; los píxeles
+/// fuera de ella siguen contando como soporte del filtro
+fn resize_buffer(
+    src_image: &impl IntoImageView,
+    dst_image: &mut Image<'_>,
+    filter: &str,
+    has_transparency: bool,
+    crop: Option<[f64; 4]>,
+) -> Result<(), WindooshError> {
     // Seleccionar algoritmo
     let algorithm = match filter {
         "Lanczos3" => ResizeAlg::Convolution(fast_image_resize::FilterType::Lanczos3),
         "CatmullRom" => ResizeAlg::Convolution(fast_image_resize::FilterType::CatmullRom),
         "Mitchell" => ResizeAlg::Convolution(fast_image_resize::FilterType::Mitchell),
+        "Gaussian" => ResizeAlg::Convolution(fast_image_resize::FilterType::Gaussian),
+        "Hamming" => ResizeAlg::Convolution(fast_image_resize::FilterType::Hamming),
         "Bilinear" | "Triangle" => ResizeAlg::Convolution(fast_image_resize::FilterType::Bilinear),
+        "Box" => ResizeAlg::Convolution(fast_image_resize::FilterType::Box),
         "Nearest" => ResizeAlg::Nearest,
-        _ => ResizeAlg::Convolution(fast_image_resize::FilterType::Lanczos3),
+        other => {
+            return Err(WindooshError::Processing(format!(
+                "Filtro de resize desconocido: '{}'",
+                other
+            )))
+        }
     };
 
-    // Crear resizer (detecta automáticamente AVX2/SSE4.1)
-    let mut resizer = Resizer::new();
+    // Convolución sobre alpha directo mezcla el color de píxeles transparentes (halos oscuros)
+    // Se premultiplica antes y se divide después; Nearest no mezcla píxeles
+    let premultiply = has_transparency && !matches!(algorithm, ResizeAlg::Nearest);
+    let mul_div = MulDiv::default();
+    let premultiplied = if premultiply {
+        let (width, height) = (src_image.width(), src_image.height());
+        let pixel_type = src_image
+            .pixel_type()
+            .ok_or_else(|| WindooshError::Processing("Tipo de píxel no soportado".into()))?;
+        let mut premultiplied = Image::new(width, height, pixel_type);
+        mul_div
+            .multiply_alpha(src_image, &mut premultiplied)
+            .map_err(|e| WindooshError::Processing(format!("Error premultiplicando alpha: {}", e)))?;
+        Some(premultiplied)
+    } else {
+        None
+    };
 
-    // Ejecutar resize
-    let options = ResizeOptions::new().resize_alg(algorithm);
-    resizer
-        .resize(&src_image, &mut dst_image, Some(&options))
-        .map_err(|e| WindooshError::Processing(format!("Error en resize: {}", e)))?;
+    // Ejecutar resize (use_alpha(false): el MulDiv ya lo hacemos nosotros, solo si hace falta)
+    let mut options = ResizeOptions::new().resize_alg(algorithm).use_alpha(false);
+    if let Some([left, top, width, height]) = crop {
+        options = options.crop(left, top, width, height);
+    }
+    with_resizer(|resizer| match premultiplied {
+        Some(ref premultiplied) => resizer.resize(premultiplied, dst_image, Some(&options)),
+        None => resizer.resize(src_image, dst_image, Some(&options)),
+    })
+    .map_err(|e| WindooshError::Processing(format!("Error en resize: {}", e)))?;
 
-    // Convertir de vuelta a DynamicImage
-    let dst_buffer = dst_image.into_vec();
-    let rgba_image = RgbaImage::from_raw(target_width, target_height, dst_buffer)
-        .ok_or_else(|| WindooshError::Processing("Error creando imagen de destino".into()))?;
+    if premultiply {
+        mul_div
+            .divide_alpha_inplace(dst_image)
+            .map_err(|e| WindooshError::Processing(format!("Error dividiendo alpha: {}", e)))?;
+    }
 
-    Ok(DynamicImage::ImageRgba8(rgba_image))
+    Ok(())
 }
 
-/// Aplica quantización de colores (reducción de paleta)
-fn apply_quantize(
-    img: DynamicImage,
-    opts: &QuantizeOptionsDto,
-) -> Result<DynamicImage, WindooshError> {
-    let mut liq = imagequant::new();
-    liq.set_speed(3)
-        .map_err(|e| WindooshError::Processing(format!("Liq speed error: {:?}", e)))?;
-    liq.set_quality(0, 100)
-        .map_err(|e| WindooshError::Processing(format!("Liq quality error: {:?}", e)))?;
-    liq.set_max_colors(opts.num_colors.clamp(2, 256))
-        .map_err(|e| WindooshError::Processing(format!("Liq max colors error: {:?}", e)))?;
+/// Recorta la imagen ajustando el rectángulo a sus límites
+/// Solo falla si el área resultante es cero
+fn apply_crop(img: &DynamicImage, opts: &CropOptionsDto) -> Result<DynamicImage, WindooshError> {
+    let (img_w, img_h) = (img.width(), img.height());
+    let x = opts.x.min(img_w);
+    let y = opts.y.min(img_h);
+    let width = opts.width.min(img_w - x);
+    let height = opts.height.min(img_h - y);
 
-    let rgba = img.to_rgba8();
-    let width = rgba.width() as usize;
-    let height = rgba.height() as usize;
+    if width == 0 || height == 0 {
+        return Err(WindooshError::Processing(format!(
+            "El recorte ({}, {}, {}x{}) queda fuera de la imagen {}x{}",
+            opts.x, opts.y, opts.width, opts.height, img_w, img_h
+        )));
+    }
 
-    let pixels: Vec<imagequant::RGBA> = rgba
-        .pixels()
-        .map(|p| {
-            let [r, g, b, a] = p.0;
-            imagequant::RGBA::new(r, g, b, a)
-        })
-        .collect();
+    Ok(img.crop_imm(x, y, width, height))
+}
+
+/// Interpreta "ancho:alto" como la proporción ancho / alto (se admiten decimales, p. ej. "1.91:1")
+fn parse_aspect_ratio(ratio: &str) -> Result<f64, WindooshError> {
+    let invalid = || {
+        WindooshError::Processing(format!(
+            "Proporción inválida: '{}' (formato esperado \"ancho:alto\", p. ej. \"16:9\")",
+            ratio
+        ))
+    };
+    let (w, h) = ratio.split_once(':').ok_or_else(invalid)?;
+    let parse = |v: &str| v.trim().parse::<f64>().ok().filter(|v| v.is_finite() && *v > 0.0);
+    match (parse(w), parse(h)) {
+        (Some(w), Some(h)) => Ok(w / h),
+        _ => Err(invalid()),
+    }
+}
+
+/// Rectángulo centrado más grande con la proporción dada; None si la imagen ya la tiene
+fn aspect_crop_rect(width: u32, height: u32, ratio: f64) -> Option<CropOptionsDto> {
+    let (crop_w, crop_h) = if width as f64 / height as f64 > ratio {
+        (((height as f64 * ratio).round() as u32).clamp(1, width), height)
+    } else {
+        (width, ((width as f64 / ratio).round() as u32).clamp(1, height))
+    };
+    if (crop_w, crop_h) == (width, height) {
+        return None;
+    }
+    Some(CropOptionsDto {
+        x: (width - crop_w) / 2,
+        y: (height - crop_h) / 2,
+        width: crop_w,
+        height: crop_h,
+    })
+}
 
-    let mut img_attr = liq
-        .new_image(pixels, width, height, 0.0)
-        .map_err(|e| WindooshError::Processing(format!("Liq new image error: {:?}", e)))?;
+/// Recorta desde cada borde las filas/columnas cuyos píxeles están a `tolerance` del color de la
+/// esquina superior izquierda. None si no hay borde que quitar o la imagen es uniforme entera
+/// (se deja como está en lugar de quedar en 0×0)
+fn apply_auto_trim(img: &DynamicImage, opts: &TrimDto) -> Option<DynamicImage> {
+    let rgba = rgba_pixels(img);
+    let (width, height) = rgba.dimensions();
+    if width == 0 || height == 0 {
+        return None;
+    }
 
-    let mut res = liq
-        .quantize(&mut img_attr)
-        .map_err(|e| WindooshError::Processing(format!("Quantization failed: {:?}", e)))?;
+    let corner = rgba.get_pixel(0, 0).0;
+    let is_border = |x: u32, y: u32| {
+        rgba.get_pixel(x, y)
+            .0
+            .iter()
+            .zip(corner)
+            .all(|(&a, b)| a.abs_diff(b) <= opts.tolerance)
+    };
 
-    res.set_dithering_level(opts.dither.clamp(0.0, 1.0))
-        .map_err(|e| WindooshError::Processing(format!("Liq dither error: {:?}", e)))?;
+    let row_is_border = |y: u32| (0..width).all(|x| is_border(x, y));
+    let top = (0..height).find(|&y| !row_is_border(y))?;
+    let bottom = (top..height).rev().find(|&y| !row_is_border(y))?;
 
-    let (palette, pixels_idx) = res
-        .remapped(&mut img_attr)
-        .map_err(|e| WindooshError::Processing(format!("Remapping failed: {:?}", e)))?;
+    // Las columnas solo se miran dentro de las filas que quedan
+    let column_is_border = |x: u32| (top..=bottom).all(|y| is_border(x, y));
+    let left = (0..width).find(|&x| !column_is_border(x))?;
+    let right = (left..width).rev().find(|&x| !column_is_border(x))?;
 
-    let mut new_rgba = Vec::with_capacity(width * height * 4);
-    for &pixel_idx in &pixels_idx {
-        let color = palette[pixel_idx as usize];
-        new_rgba.push(color.r);
-        new_rgba.push(color.g);
-        new_rgba.push(color.b);
-        new_rgba.push(color.a);
+    if (left, top, right, bottom) == (0, 0, width - 1, height - 1) {
+        return None;
     }
+    Some(img.crop_imm(left, top, right - left + 1, bottom - top + 1))
+}
 
-    RgbaImage::from_vec(width as u32, height as u32, new_rgba)
-        .map(DynamicImage::ImageRgba8)
-        .ok_or_else(|| WindooshError::Processing("Error reconstruyendo imagen quantizada".into()))
+/// Aplica rotación y luego espejado (imageops vía DynamicImage, conserva el tipo de píxel)
+fn apply_transform(img: &DynamicImage, opts: &TransformDto) -> Result<DynamicImage, WindooshError> {
+    let mut out = match opts.rotate {
+        0 => img.clone(),
+        90 => img.rotate90(),
+        180 => img.rotate180(),
+        270 => img.rotate270(),
+        other => {
+            return Err(WindooshError::Processing(format!(
+                "Rotación inválida: {} (valores permitidos: 0, 90, 180, 270)",
+                other
+            )))
+        }
+    };
+
+    if opts.flip_h {
+        out = out.fliph();
+    }
+    if opts.flip_v {
+        out = out.flipv();
+    }
+
+    Ok(out)
 }
 
-/// Extrae raw RGBA bytes de una imagen para renderizado en canvas
-/// Esta es la clave para full-resolution previews sin pérdida
-fn extract_rgba_data(img: &DynamicImage) -> ImageDataRaw {
-    let rgba = img.to_rgba8();
-    let (width, height) = rgba.dimensions();
-    ImageDataRaw {
-        width,
-        height,
-        data: rgba.into_raw(),
+/// Posición (x, y) del contenido dentro de un lienzo con `free_w`×`free_h` píxeles libres
+fn pad_offset(anchor: &str, free_w: u32, free_h: u32) -> Result<(u32, u32), WindooshError> {
+    // Fracción del espacio libre a la izquierda/arriba, en mitades
+    let (h, v) = match anchor {
+        "" | "center" => (1, 1),
+        "top-left" => (0, 0),
+        "top" => (1, 0),
+        "top-right" => (2, 0),
+        "left" => (0, 1),
+        "right" => (2, 1),
+        "bottom-left" => (0, 2),
+        "bottom" => (1, 2),
+        "bottom-right" => (2, 2),
+        other => return Err(WindooshError::Processing(format!("Anclaje inválido: {}", other))),
+    };
+    Ok((free_w * h / 2, free_h * v / 2))
+}
+
+/// Compone la imagen sobre un lienzo liso de opts.width×opts.height en el anclaje pedido
+/// El relleno nunca recorta: si el contenido es mayor que el lienzo es un error
+fn apply_pad(img: DynamicImage, opts: &PadDto) -> Result<DynamicImage, WindooshError> {
+    let (width, height) = (img.width(), img.height());
+    if width > opts.width || height > opts.height {
+        return Err(WindooshError::Processing(format!(
+            "La imagen ({}x{}) no cabe en el lienzo de {}x{}",
+            width, height, opts.width, opts.height
+        )));
     }
+    let (x, y) = pad_offset(&opts.anchor, opts.width - width, opts.height - height)?;
+    if (width, height) == (opts.width, opts.height) {
+        return Ok(img);
+    }
+
+    let mut canvas = RgbaImage::from_pixel(opts.width, opts.height, image::Rgba(opts.color));
+    image::imageops::replace(&mut canvas, &*rgba_pixels(&img), x as i64, y as i64);
+    Ok(DynamicImage::ImageRgba8(canvas))
 }
 
-/// Pipeline de procesamiento completo - ahora retorna la imagen procesada
-/// IMPORTANTE: Para mostrar artefactos de compresión (como Squoosh),
-/// re-decodificamos la imagen comprimida para preview
-/// Retorna: (EncodingResult, DynamicImage para preview)
-fn process_pipeline(
-    img: &Arc<DynamicImage>,
-    request: &OptimizationRequest,
-) -> Result<(EncodingResult, DynamicImage), WindooshError> {
-    // 1. Resize con SIMD (si es necesario)
-    let processed = if let Some(ref resize_opts) = request.resize {
-        resize_with_simd(
-            img,
-            resize_opts.width,
-            resize_opts.height,
-            &resize_opts.filter,
-        )?
+/// Superpone la marca de agua escalada a `scale` de la imagen (conservando su proporción)
+/// con su alpha multiplicado por `opacity`; el tipo de color de la base se conserva
+fn apply_watermark(img: DynamicImage, opts: &WatermarkDto) -> Result<DynamicImage, WindooshError> {
+    let mark = ImageReader::new(Cursor::new(&opts.image))
+        .with_guessed_format()
+        .map_err(|e| WindooshError::ImageDecode(e.to_string()))?
+        .decode()
+        .map_err(|e| WindooshError::ImageDecode(format!("Marca de agua: {}", e)))?;
+
+    let (width, height) = (img.width(), img.height());
+    let scale = opts.scale.clamp(0.0, 1.0) as f64;
+    let fit = (width as f64 * scale / mark.width() as f64).min(height as f64 * scale / mark.height() as f64);
+    let mark_w = (mark.width() as f64 * fit).round() as u32;
+    let mark_h = (mark.height() as f64 * fit).round() as u32;
+    let opacity = opts.opacity.clamp(0.0, 1.0);
+    if mark_w == 0 || mark_h == 0 || opacity == 0.0 {
+        return Ok(img);
+    }
+
+    let mut mark = resize_exact_simd(&mark, mark_w, mark_h, "Lanczos3")?.into_rgba8();
+    if opacity < 1.0 {
+        for pixel in mark.pixels_mut() {
+            pixel[3] = (pixel[3] as f32 * opacity).round() as u8;
+        }
+    }
+
+    // El margen se descuenta del espacio libre; si no cabe, la marca llega hasta el borde
+    let anchor = if opts.anchor.is_empty() { "bottom-right" } else { &opts.anchor };
+    let margin = opts
+        .margin
+        .min((width - mark_w) / 2)
+        .min((height - mark_h) / 2);
+    let (x, y) = pad_offset(
+        anchor,
+        width - mark_w - 2 * margin,
+        height - mark_h - 2 * margin,
+    )?;
+
+    let had_alpha = img.color().has_alpha();
+    let mut base = img.into_rgba8();
+    image::imageops::overlay(&mut base, &mark, (x + margin) as i64, (y + margin) as i64);
+    Ok(if had_alpha {
+        DynamicImage::ImageRgba8(base)
     } else {
-        (**img).clone()
-    };
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(base).into_rgb8())
+    })
+}
+
+/// Unsharp mask: original + amount × (original − desenfocada), solo en RGB
+fn apply_sharpen(img: DynamicImage, opts: &SharpenDto) -> DynamicImage {
+    let sigma = opts.radius.max(0.1);
+    match img {
+        DynamicImage::ImageRgb8(mut buf) => {
+            let blurred = image::imageops::fast_blur(&buf, sigma);
+            unsharp_channels(&mut buf, blurred.as_raw(), 3, opts);
+            DynamicImage::ImageRgb8(buf)
+        }
+        other => {
+            let mut buf = other.into_rgba8();
+            let blurred = image::imageops::fast_blur(&buf, sigma);
+            unsharp_channels(&mut buf, blurred.as_raw(), 4, opts);
+            DynamicImage::ImageRgba8(buf)
+        }
+    }
+}
+
+/// Aplica la máscara sobre los 3 primeros canales de cada píxel (el alpha se conserva)
+fn unsharp_channels(pixels: &mut [u8], blurred: &[u8], channels: usize, opts: &SharpenDto) {
+    let threshold = opts.threshold as f32;
+    pixels
+        .par_chunks_exact_mut(channels)
+        .zip(blurred.par_chunks_exact(channels))
+        .for_each(|(px, blur)| {
+            for c in 0..3 {
+                let diff = px[c] as f32 - blur[c] as f32;
+                if diff.abs() > threshold {
+                    px[c] = (px[c] as f32 + opts.amount * diff).round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        });
+}
+
+/// Aplica brillo, contraste y saturación; None si todos son neutros (evita copiar la imagen)
+fn apply_adjustments(img: &DynamicImage, opts: &AdjustmentsDto) -> Option<DynamicImage> {
+    let brightness = opts.brightness.clamp(-100.0, 100.0);
+    let contrast = opts.contrast.clamp(-100.0, 100.0);
+    let saturation = opts.saturation.clamp(-100.0, 100.0);
+    if brightness == 0.0 && contrast == 0.0 && saturation == 0.0 {
+        return None;
+    }
+
+    let mut out = if brightness != 0.0 {
+        // -100..100 → desplazamiento de -255..255 por canal
+        img.brighten((brightness * 2.55).round() as i32)
+    } else {
+        img.clone()
+    };
+
+    if contrast != 0.0 {
+        out = out.adjust_contrast(contrast);
+    }
+
+    if saturation != 0.0 {
+        let factor = 1.0 + saturation / 100.0;
+        out = match out {
+            DynamicImage::ImageRgb8(mut buf) => {
+                buf.par_chunks_exact_mut(3)
+                    .for_each(|px| saturate_pixel(px, factor));
+                DynamicImage::ImageRgb8(buf)
+            }
+            other => {
+                let mut buf = other.into_rgba8();
+                buf.par_chunks_exact_mut(4)
+                    .for_each(|px| saturate_pixel(px, factor));
+                DynamicImage::ImageRgba8(buf)
+            }
+        };
+    }
+
+    Some(out)
+}
+
+/// Si algún píxel tiene alpha < 255 (un canal alpha todo opaco no cuenta)
+fn uses_transparency(img: &DynamicImage) -> bool {
+    img.color().has_alpha()
+        && rgba_pixels(img)
+            .as_raw()
+            .par_chunks_exact(4)
+            .any(|p| p[3] < 255)
+}
+
+/// Hash XXH3 de 128 bits (hex) de las dimensiones y los píxeles en RGBA8
+/// Se normaliza a RGBA para que un PNG RGB y su versión RGBA opaca coincidan
+fn content_hash(img: &DynamicImage) -> String {
+    let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+    hasher.update(&img.width().to_le_bytes());
+    hasher.update(&img.height().to_le_bytes());
+    hasher.update(rgba_pixels(img).as_raw());
+    format!("{:032x}", hasher.digest128())
+}
+
+/// Fondo al aplanar para formatos sin alpha cuando el borde no sugiere ninguno (antes quedaba negro)
+const DEFAULT_FLATTEN_BACKGROUND: [u8; 3] = [255, 255, 255];
+
+/// Alpha mínimo para que un píxel del borde cuente al buscar el color de fondo
+const BACKGROUND_MIN_ALPHA: u8 = 128;
+
+/// Color dominante del borde de la imagen: el fondo más probable para aplanar el alpha
+/// Los píxeles se agrupan en celdas de 16 niveles por canal y se promedia la más poblada
+/// Un borde transparente no dice nada del fondo: entonces se usa DEFAULT_FLATTEN_BACKGROUND
+fn border_background_color(img: &DynamicImage) -> [u8; 3] {
+    let rgba = rgba_pixels(img);
+    let (width, height) = rgba.dimensions();
+    if width == 0 || height == 0 {
+        return DEFAULT_FLATTEN_BACKGROUND;
+    }
+
+    let border = (0..width)
+        .flat_map(|x| [(x, 0), (x, height - 1)])
+        .chain((1..height.saturating_sub(1)).flat_map(|y| [(0, y), (width - 1, y)]));
+
+    // BTreeMap: con empates el resultado no depende del orden de un hash (la caché lo necesita)
+    let mut cells: BTreeMap<[u8; 3], (u64, [u64; 3])> = BTreeMap::new();
+    for (x, y) in border {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        if a < BACKGROUND_MIN_ALPHA {
+            continue;
+        }
+        let (count, sum) = cells.entry([r >> 4, g >> 4, b >> 4]).or_default();
+        *count += 1;
+        for (total, channel) in sum.iter_mut().zip([r, g, b]) {
+            *total += channel as u64;
+        }
+    }
+
+    cells
+        .into_values()
+        .max_by_key(|&(count, _)| count)
+        .map_or(DEFAULT_FLATTEN_BACKGROUND, |(count, sum)| {
+            sum.map(|total| ((total + count / 2) / count) as u8)
+        })
+}
+
+/// Compone la imagen sobre un color sólido y descarta el alpha
+/// Imágenes sin canal alpha se devuelven tal cual
+fn apply_flatten(img: DynamicImage, background: [u8; 3]) -> Result<DynamicImage, WindooshError> {
+    if !img.color().has_alpha() {
+        return Ok(img);
+    }
+
+    let rgba = rgba_pixels(&img);
+    let (width, height) = rgba.dimensions();
+    let mut rgb = vec![0u8; width as usize * height as usize * 3];
+    rgb.par_chunks_exact_mut(3)
+        .zip(rgba.as_raw().par_chunks_exact(4))
+        .for_each(|(dst, src)| {
+            let alpha = src[3] as u32;
+            for c in 0..3 {
+                let blended = src[c] as u32 * alpha + background[c] as u32 * (255 - alpha);
+                dst[c] = ((blended + 127) / 255) as u8;
+            }
+        });
+
+    image::RgbImage::from_raw(width, height, rgb)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| WindooshError::Processing("Error creando imagen aplanada".into()))
+}
+
+/// Escala la saturación HSL de un píxel (los canales extra, como alpha, no se tocan)
+fn saturate_pixel(px: &mut [u8], factor: f32) {
+    let (r, g, b) = (
+        px[0] as f32 / 255.0,
+        px[1] as f32 / 255.0,
+        px[2] as f32 / 255.0,
+    );
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    if max == min {
+        return; // Gris: sin tono que saturar
+    }
+
+    // RGB → HSL
+    let lightness = (max + min) / 2.0;
+    let delta = max - min;
+    let sat = if lightness > 0.5 {
+        delta / (2.0 - max - min)
+    } else {
+        delta / (max + min)
+    };
+    let hue = if max == r {
+        (g - b) / delta + if g < b { 6.0 } else { 0.0 }
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    } / 6.0;
+
+    // HSL → RGB con la saturación escalada
+    let sat = (sat * factor).clamp(0.0, 1.0);
+    let q = if lightness < 0.5 {
+        lightness * (1.0 + sat)
+    } else {
+        lightness + sat - lightness * sat
+    };
+    let p = 2.0 * lightness - q;
+
+    px[0] = (hue_to_channel(p, q, hue + 1.0 / 3.0) * 255.0).round() as u8;
+    px[1] = (hue_to_channel(p, q, hue) * 255.0).round() as u8;
+    px[2] = (hue_to_channel(p, q, hue - 1.0 / 3.0) * 255.0).round() as u8;
+}
+
+fn hue_to_channel(p: f32, q: f32, mut t: f32) -> f32 {
+    if t < 0.0 {
+        t += 1.0;
+    }
+    if t > 1.0 {
+        t -= 1.0;
+    }
+    if t < 1.0 / 6.0 {
+        p + (q - p) * 6.0 * t
+    } else if t < 0.5 {
+        q
+    } else if t < 2.0 / 3.0 {
+        p + (q - p) * (2.0 / 3.0 - t) * 6.0
+    } else {
+        p
+    }
+}
+
+/// Ajustes de imagequant que elige el usuario (QuantizeOptionsDto y opciones del GIF)
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct QuantizerTuning {
+    /// 1-10, más alto = más rápido y algo menos preciso; 0 = según el tamaño (quantizer_speed)
+    pub speed: u8,
+    pub min_quality: u8,
+    pub max_quality: u8,
+}
+
+impl Default for QuantizerTuning {
+    fn default() -> Self {
+        Self {
+            speed: 0,
+            min_quality: 0,
+            max_quality: 100,
+        }
+    }
+}
+
+/// Error de imagequant al generar la paleta; QualityTooLow es un min_quality no alcanzado
+fn quantization_error(e: imagequant::Error) -> WindooshError {
+    match e {
+        imagequant::Error::QualityTooLow => WindooshError::Processing(
+            "La paleta no alcanza la calidad mínima pedida (min_quality)".to_string(),
+        ),
+        other => WindooshError::Processing(format!("Quantization failed: {:?}", other)),
+    }
+}
+
+/// Quantiza con imagequant y retorna (paleta, índice de paleta por píxel)
+/// Compartido por apply_quantize y los encoders indexados (GIF)
+pub(crate) fn quantize_indexed(
+    rgba: &RgbaImage,
+    num_colors: u32,
+    dither: f32,
+    tuning: QuantizerTuning,
+) -> Result<(Vec<imagequant::RGBA>, Vec<u8>), WindooshError> {
+    let liq = new_quantizer(num_colors, rgba.width() as u64 * rgba.height() as u64, tuning)?;
+    let mut img_attr = new_liq_image(&liq, rgba)?;
+
+    let mut res = liq.quantize(&mut img_attr).map_err(quantization_error)?;
+
+    res.set_dithering_level(dither.clamp(0.0, 1.0))
+        .map_err(|e| WindooshError::Processing(format!("Liq dither error: {:?}", e)))?;
+
+    res.remapped(&mut img_attr)
+        .map_err(|e| WindooshError::Processing(format!("Remapping failed: {:?}", e)))
+}
+
+/// Velocidad de imagequant según el tamaño: las imágenes grandes tienen de sobra para el histograma
+/// 1-10; más alto = más rápido y algo menos preciso
+fn quantizer_speed(pixels: u64) -> i32 {
+    match pixels {
+        0..=4_000_000 => 3,
+        4_000_001..=16_000_000 => 5,
+        _ => 7,
+    }
+}
+
+/// Configuración de imagequant común a todas las quantizaciones
+/// `pixels` es el total a quantizar (todos los frames en una paleta compartida)
+fn new_quantizer(
+    num_colors: u32,
+    pixels: u64,
+    tuning: QuantizerTuning,
+) -> Result<imagequant::Attributes, WindooshError> {
+    let (min_quality, max_quality) = (tuning.min_quality.min(100), tuning.max_quality.min(100));
+    if min_quality > max_quality {
+        return Err(WindooshError::Processing(format!(
+            "La calidad mínima ({}) supera la máxima ({})",
+            min_quality, max_quality
+        )));
+    }
+    let speed = match tuning.speed {
+        0 => quantizer_speed(pixels),
+        speed => speed.clamp(1, 10) as i32,
+    };
+
+    let mut liq = imagequant::new();
+    liq.set_speed(speed)
+        .map_err(|e| WindooshError::Processing(format!("Liq speed error: {:?}", e)))?;
+    liq.set_quality(min_quality, max_quality)
+        .map_err(|e| WindooshError::Processing(format!("Liq quality error: {:?}", e)))?;
+    liq.set_max_colors(num_colors.clamp(2, 256))
+        .map_err(|e| WindooshError::Processing(format!("Liq max colors error: {:?}", e)))?;
+    Ok(liq)
+}
+
+fn new_liq_image(
+    liq: &imagequant::Attributes,
+    rgba: &RgbaImage,
+) -> Result<imagequant::Image<'static>, WindooshError> {
+    let pixels: Vec<imagequant::RGBA> = rgba
+        .pixels()
+        .map(|p| {
+            let [r, g, b, a] = p.0;
+            imagequant::RGBA::new(r, g, b, a)
+        })
+        .collect();
+
+    liq.new_image(pixels, rgba.width() as usize, rgba.height() as usize, 0.0)
+        .map_err(|e| WindooshError::Processing(format!("Liq new image error: {:?}", e)))
+}
+
+/// Quantiza varios frames con una única paleta (paleta global de un GIF animado)
+/// La paleta sale del histograma de todos los frames y se fija antes de remapear:
+/// remapped() refina la paleta en cada llamada y los frames quedarían desalineados
+pub(crate) fn quantize_shared(
+    frames: &[&RgbaImage],
+    num_colors: u32,
+    dither: f32,
+    tuning: QuantizerTuning,
+) -> Result<(Vec<imagequant::RGBA>, Vec<Vec<u8>>), WindooshError> {
+    let pixels = frames
+        .iter()
+        .map(|rgba| rgba.width() as u64 * rgba.height() as u64)
+        .sum();
+    let liq = new_quantizer(num_colors, pixels, tuning)?;
+    let mut histogram = imagequant::Histogram::new(&liq);
+    let mut images = Vec::with_capacity(frames.len());
+    for rgba in frames {
+        let mut img_attr = new_liq_image(&liq, rgba)?;
+        histogram
+            .add_image(&liq, &mut img_attr)
+            .map_err(|e| WindooshError::Processing(format!("Liq histogram error: {:?}", e)))?;
+        images.push(img_attr);
+    }
+
+    let mut res = histogram.quantize(&liq).map_err(quantization_error)?;
+    let palette = res.palette_vec();
+
+    // Colores fijos: imagequant no los mueve al remapear
+    let mut fixed = imagequant::QuantizationResult::from_palette(&liq, &palette, 0.0)
+        .map_err(|e| WindooshError::Processing(format!("Liq palette error: {:?}", e)))?;
+    fixed
+        .set_dithering_level(dither.clamp(0.0, 1.0))
+        .map_err(|e| WindooshError::Processing(format!("Liq dither error: {:?}", e)))?;
+
+    let mut shared_palette = palette;
+    let mut indexed = Vec::with_capacity(images.len());
+    for img_attr in images.iter_mut() {
+        let (frame_palette, indices) = fixed
+            .remapped(img_attr)
+            .map_err(|e| WindooshError::Processing(format!("Remapping failed: {:?}", e)))?;
+        shared_palette = frame_palette;
+        indexed.push(indices);
+    }
+
+    Ok((shared_palette, indexed))
+}
+
+/// Matriz de Bayer 8×8 (umbrales 0-63) del tramado ordenado
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Remapea a la paleta con tramado ordenado (Bayer): imagequant solo hace difusión de error
+/// Cada píxel se desplaza según su umbral antes de buscar el color más cercano; el desplazamiento
+/// máximo es la distancia media (por canal) de cada color de la paleta a su vecino × `strength`
+/// El alpha no se trama
+fn ordered_dither_indices(rgba: &RgbaImage, palette: &[imagequant::RGBA], strength: f32) -> Vec<u8> {
+    let width = rgba.width() as usize;
+    let rgb_distance = |a: &imagequant::RGBA, b: &imagequant::RGBA| {
+        let d = |x: u8, y: u8| (x as f32 - y as f32).powi(2);
+        ((d(a.r, b.r) + d(a.g, b.g) + d(a.b, b.b)) / 3.0).sqrt()
+    };
+    let neighbor_distances: Vec<f32> = palette
+        .iter()
+        .enumerate()
+        .filter_map(|(i, a)| {
+            palette
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(_, b)| rgb_distance(a, b))
+                .min_by(f32::total_cmp)
+        })
+        .collect();
+    let spread = neighbor_distances.iter().sum::<f32>() / neighbor_distances.len().max(1) as f32
+        * strength.clamp(0.0, 1.0);
+
+    let nearest = |[r, g, b, a]: [u8; 4]| -> u8 {
+        let distance = |c: &imagequant::RGBA| {
+            let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2);
+            d(r, c.r) + d(g, c.g) + d(b, c.b) + d(a, c.a)
+        };
+        (0..palette.len())
+            .min_by_key(|&i| distance(&palette[i]))
+            .unwrap_or(0) as u8
+    };
+
+    let mut indices = vec![0u8; rgba.as_raw().len() / 4];
+    indices
+        .par_chunks_exact_mut(width.max(1))
+        .zip(rgba.as_raw().par_chunks_exact(width.max(1) * 4))
+        .enumerate()
+        .for_each(|(y, (idx_row, src_row))| {
+            // Las zonas planas repiten los mismos colores desplazados: caché por fila
+            let mut cache: HashMap<[u8; 4], u8> = HashMap::new();
+            let thresholds = &BAYER_8X8[y % 8];
+            for (x, (idx, px)) in idx_row.iter_mut().zip(src_row.chunks_exact(4)).enumerate() {
+                let offset = ((thresholds[x % 8] as f32 + 0.5) / 64.0 - 0.5) * spread;
+                let shift = |v: u8| (v as f32 + offset).round().clamp(0.0, 255.0) as u8;
+                let key = [shift(px[0]), shift(px[1]), shift(px[2]), px[3]];
+                *idx = *cache.entry(key).or_insert_with(|| nearest(key));
+            }
+        });
+
+    indices
+}
+
+/// Remapea los frames a una paleta dada (colores de marca) sin que imagequant la genere ni la mueva
+pub(crate) fn remap_fixed_palette(
+    frames: &[&RgbaImage],
+    colors: &[[u8; 4]],
+    dither: f32,
+) -> Result<(Vec<imagequant::RGBA>, Vec<Vec<u8>>), WindooshError> {
+    if !(2..=256).contains(&colors.len()) {
+        return Err(WindooshError::Processing(format!(
+            "La paleta fija debe tener entre 2 y 256 colores (tiene {})",
+            colors.len()
+        )));
+    }
+    let pixels = frames
+        .iter()
+        .map(|rgba| rgba.width() as u64 * rgba.height() as u64)
+        .sum();
+    // La paleta no se genera: velocidad y calidad no influyen en el remapeo
+    let liq = new_quantizer(colors.len() as u32, pixels, QuantizerTuning::default())?;
+    let palette: Vec<imagequant::RGBA> = colors
+        .iter()
+        .map(|&[r, g, b, a]| imagequant::RGBA::new(r, g, b, a))
+        .collect();
+
+    let mut fixed = imagequant::QuantizationResult::from_palette(&liq, &palette, 0.0)
+        .map_err(|e| WindooshError::Processing(format!("Liq palette error: {:?}", e)))?;
+    fixed
+        .set_dithering_level(dither.clamp(0.0, 1.0))
+        .map_err(|e| WindooshError::Processing(format!("Liq dither error: {:?}", e)))?;
+
+    let mut remapped_palette = palette;
+    let mut indexed = Vec::with_capacity(frames.len());
+    for rgba in frames {
+        let mut img_attr = new_liq_image(&liq, rgba)?;
+        let (frame_palette, indices) = fixed
+            .remapped(&mut img_attr)
+            .map_err(|e| WindooshError::Processing(format!("Remapping failed: {:?}", e)))?;
+        remapped_palette = frame_palette;
+        indexed.push(indices);
+    }
+
+    Ok((remapped_palette, indexed))
+}
+
+/// Aplica quantización de colores (reducción de paleta)
+#[doc(hidden)]
+pub fn apply_quantize(
+    img: DynamicImage,
+    opts: &QuantizeOptionsDto,
+) -> Result<DynamicImage, WindooshError> {
+    // Tras resize/sharpen la imagen ya es RGBA8: into_rgba8 la reutiliza sin copiar
+    let rgba = img.into_rgba8();
+    let width = rgba.width() as usize;
+    let height = rgba.height() as usize;
+
+    let diffusion = opts.diffusion_level()?;
+    let (palette, mut pixels_idx) = match opts.fixed_palette {
+        Some(ref colors) => {
+            let (palette, mut indexed) = remap_fixed_palette(&[&rgba], colors, diffusion)?;
+            (palette, indexed.remove(0))
+        }
+        None => quantize_indexed(&rgba, opts.num_colors, diffusion, opts.tuning())?,
+    };
+    if opts.ordered_dither() {
+        pixels_idx = ordered_dither_indices(&rgba, &palette, opts.dither);
+    }
+
+    // Lookup en la paleta por filas en paralelo (cada fila es independiente)
+    let mut new_rgba = vec![0u8; width * height * 4];
+    new_rgba
+        .par_chunks_exact_mut(width.max(1) * 4)
+        .zip(pixels_idx.par_chunks_exact(width.max(1)))
+        .for_each(|(dst_row, idx_row)| {
+            for (dst, &pixel_idx) in dst_row.chunks_exact_mut(4).zip(idx_row) {
+                let color = palette[pixel_idx as usize];
+                dst.copy_from_slice(&[color.r, color.g, color.b, color.a]);
+            }
+        });
+
+    RgbaImage::from_vec(width as u32, height as u32, new_rgba)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| WindooshError::Processing("Error reconstruyendo imagen quantizada".into()))
+}
+
+/// Extrae raw RGBA bytes de una imagen para renderizado en canvas
+/// Esta es la clave para full-resolution previews sin pérdida
+/// Consume la imagen: si ya es RGBA8 (salida de resize/quantize) el buffer se reutiliza sin copiar
+#[doc(hidden)]
+pub fn extract_rgba_data(img: DynamicImage) -> ImageDataRaw {
+    let rgba = img.into_rgba8();
+    let (width, height) = rgba.dimensions();
+    ImageDataRaw {
+        width,
+        height,
+        data: rgba.into_raw(),
+    }
+}
+
+/// Histograma RGB + luminancia (BT.601) en una sola pasada
+/// RGB/RGBA de 8 bits se recorren en su buffer original sin convertir
+fn compute_histogram(img: &DynamicImage) -> HistogramData {
+    let mut r = vec![0u32; 256];
+    let mut g = vec![0u32; 256];
+    let mut b = vec![0u32; 256];
+    let mut luma = vec![0u32; 256];
+
+    let mut accumulate = |raw: &[u8], channels: usize| {
+        for px in raw.chunks_exact(channels) {
+            let (pr, pg, pb) = (px[0] as u32, px[1] as u32, px[2] as u32);
+            r[pr as usize] += 1;
+            g[pg as usize] += 1;
+            b[pb as usize] += 1;
+            luma[((299 * pr + 587 * pg + 114 * pb + 500) / 1000) as usize] += 1;
+        }
+    };
+
+    match img {
+        DynamicImage::ImageRgba8(buf) => accumulate(buf.as_raw(), 4),
+        DynamicImage::ImageRgb8(buf) => accumulate(buf.as_raw(), 3),
+        other => accumulate(other.to_rgb8().as_raw(), 3),
+    }
+
+    HistogramData { r, g, b, luma }
+}
+
+/// Tope del conteo de colores: por encima ya no cabe en paleta y el HashSet no crece más
+const UNIQUE_COLORS_CAP: usize = 100_000;
+
+/// Cuenta colores RGBA distintos; se detiene al superar `cap` (devuelve cap y capped = true)
+fn count_unique_colors_capped(rgba: &RgbaImage, cap: usize) -> UniqueColors {
+    let mut seen = std::collections::HashSet::new();
+    for pixel in rgba.pixels() {
+        seen.insert(u32::from_ne_bytes(pixel.0));
+        if seen.len() > cap {
+            return UniqueColors {
+                count: cap,
+                capped: true,
+            };
+        }
+    }
+    UniqueColors {
+        count: seen.len(),
+        capped: false,
+    }
+}
+
+/// Por encima de esta fracción de vecinos idénticos la imagen se trata como gráfico (no foto)
+const FLAT_NEIGHBOR_THRESHOLD: f64 = 0.5;
+
+/// Fracción de pares de píxeles vecinos (horizontales) idénticos
+/// Alta en capturas, logos e ilustraciones planas; baja en fotos (ruido y gradientes)
+/// En imágenes grandes se muestrea una de cada N filas
+fn flat_neighbor_ratio(rgba: &RgbaImage) -> f64 {
+    let row_step = (rgba.height() as usize / 512).max(1);
+    let (mut flat, mut total) = (0u64, 0u64);
+    for row in rgba.rows().step_by(row_step) {
+        let pixels: Vec<_> = row.collect();
+        for pair in pixels.windows(2) {
+            total += 1;
+            if pair[0] == pair[1] {
+                flat += 1;
+            }
+        }
+    }
+    if total == 0 {
+        1.0
+    } else {
+        flat as f64 / total as f64
+    }
+}
+
+/// Encoders recomendados en orden según alpha, colores distintos y si parece una foto
+/// Heurística: solo orienta al usuario, no garantiza el archivo más pequeño
+fn rank_encoders(img: &DynamicImage) -> Vec<(&'static str, &'static str)> {
+    let rgba = rgba_pixels(img);
+    let has_alpha = rgba.pixels().any(|p| p[3] < 255);
+    let colors = count_unique_colors_capped(&rgba, UNIQUE_COLORS_CAP);
+
+    if colors.count <= 256 && !colors.capped {
+        return vec![
+            ("oxipng", "Pocos colores: PNG con paleta es sin pérdida y muy compacto"),
+            ("webp", "WebP lossless suele quedar aún más pequeño que PNG"),
+            ("gif", "Cabe en una paleta de 256 colores sin perder nada"),
+        ];
+    }
+
+    let photographic = flat_neighbor_ratio(&rgba) < FLAT_NEIGHBOR_THRESHOLD;
+    match (photographic, has_alpha) {
+        (true, false) => vec![
+            ("mozjpeg", "Foto sin transparencia: JPEG es rápido y compatible en todas partes"),
+            ("avif", "AVIF da el menor tamaño a igual calidad visual"),
+            ("jxl", "JPEG XL comprime fotos mejor que JPEG conservando detalle"),
+            ("webp", "WebP con pérdida: buen equilibrio entre tamaño y compatibilidad"),
+        ],
+        (true, true) => vec![
+            ("webp", "Foto con transparencia: WebP con pérdida conserva el alpha"),
+            ("avif", "AVIF con alpha: el menor tamaño si el destino lo soporta"),
+            ("jxl", "JPEG XL admite alpha con compresión de foto"),
+            ("oxipng", "PNG conserva todo sin pérdida, pero será mucho más grande"),
+        ],
+        (false, true) => vec![
+            ("oxipng", "Gráfico con transparencia: PNG sin pérdida mantiene bordes nítidos"),
+            ("webp", "WebP lossless conserva el alpha con menos bytes que PNG"),
+            ("avif", "AVIF con pérdida si el tamaño importa más que la nitidez"),
+        ],
+        (false, false) => vec![
+            ("oxipng", "Gráfico plano: PNG sin pérdida evita artefactos en bordes y texto"),
+            ("webp", "WebP lossless suele ganar a PNG en capturas e ilustraciones"),
+            ("mozjpeg", "JPEG solo si hay muchas zonas con degradados"),
+        ],
+    }
+}
+
+/// Amplificación por defecto del mapa de diferencias (diferencias de 1-2 niveles son invisibles)
+const DEFAULT_DIFF_AMPLIFICATION: f32 = 5.0;
+
+/// Mapa de diferencias en escala de grises: cada píxel = mayor |Δ| de R/G/B × amplificación
+/// Ambas imágenes deben tener las mismas dimensiones
+fn build_diff_image(a: &RgbaImage, b: &RgbaImage, amplification: f32) -> ImageDataRaw {
+    let mut data = vec![0u8; a.as_raw().len()];
+    data.par_chunks_exact_mut(4)
+        .zip(a.as_raw().par_chunks_exact(4))
+        .zip(b.as_raw().par_chunks_exact(4))
+        .for_each(|((out, pa), pb)| {
+            let diff = (0..3).map(|c| pa[c].abs_diff(pb[c])).max().unwrap_or(0);
+            let value = (diff as f32 * amplification).round().clamp(0.0, 255.0) as u8;
+            out.copy_from_slice(&[value, value, value, 255]);
+        });
+
+    ImageDataRaw {
+        width: a.width(),
+        height: a.height(),
+        data,
+    }
+}
+
+/// Extrae el rango [offset, offset + len) del buffer RGBA sin convertir la imagen completa
+/// RGBA8 se copia directo; otros formatos convierten solo los píxeles del rango
+fn extract_rgba_chunk(img: &DynamicImage, offset: usize, len: usize) -> ImageDataChunk {
+    let (width, height) = (img.width(), img.height());
+    let total_len = width as usize * height as usize * 4;
+    let start = offset.min(total_len);
+    let end = start.saturating_add(len).min(total_len);
+
+    let data = match img {
+        DynamicImage::ImageRgba8(buf) => buf.as_raw()[start..end].to_vec(),
+        other => {
+            use image::GenericImageView;
+
+            // Píxeles que cubren el rango (puede empezar/terminar a mitad de píxel)
+            let first_px = start / 4;
+            let last_px = end.div_ceil(4);
+            let mut pixels = Vec::with_capacity((last_px - first_px) * 4);
+            for i in first_px..last_px {
+                let (x, y) = ((i % width as usize) as u32, (i / width as usize) as u32);
+                pixels.extend_from_slice(&other.get_pixel(x, y).0);
+            }
+            let skip = start - first_px * 4;
+            pixels[skip..skip + (end - start)].to_vec()
+        }
+    };
+
+    ImageDataChunk {
+        width,
+        height,
+        total_len,
+        offset: start,
+        data,
+    }
+}
+
+/// Ventana del SSIM (8×8) y paso entre ventanas (solapadas a la mitad)
+const SSIM_WINDOW: usize = 8;
+const SSIM_STEP: usize = 4;
+
+/// PSNR sobre los canales RGB (el alpha no se compara); tope de 100 dB si son idénticas
+fn compute_psnr(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    let squared_error: f64 = a
+        .as_raw()
+        .par_chunks_exact(4)
+        .zip(b.as_raw().par_chunks_exact(4))
+        .map(|(pa, pb)| {
+            (0..3)
+                .map(|c| {
+                    let diff = pa[c] as f64 - pb[c] as f64;
+                    diff * diff
+                })
+                .sum::<f64>()
+        })
+        .sum();
+
+    let mse = squared_error / (a.width() as f64 * a.height() as f64 * 3.0);
+    if mse == 0.0 {
+        return 100.0;
+    }
+    (10.0 * (255.0 * 255.0 / mse).log10()).min(100.0)
+}
+
+/// Luminancia BT.601 de cada píxel
+fn luma_plane(img: &RgbaImage) -> Vec<f64> {
+    img.pixels()
+        .map(|p| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64)
+        .collect()
+}
+
+/// SSIM medio de luminancia con ventanas 8×8 (Wang et al. 2004, sin ponderación gaussiana)
+/// Imágenes menores que la ventana se evalúan como una sola ventana
+fn compute_ssim(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = (a.width() as usize, a.height() as usize);
+    let (luma_a, luma_b) = (luma_plane(a), luma_plane(b));
+    let win_w = SSIM_WINDOW.min(width);
+    let win_h = SSIM_WINDOW.min(height);
+    let n = (win_w * win_h) as f64;
+
+    let rows: Vec<usize> = (0..=height - win_h).step_by(SSIM_STEP).collect();
+    let (total, count) = rows
+        .par_iter()
+        .map(|&y0| {
+            let mut row_total = 0.0;
+            let mut row_count = 0usize;
+            for x0 in (0..=width - win_w).step_by(SSIM_STEP) {
+                let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                    (0.0, 0.0, 0.0, 0.0, 0.0);
+                for y in y0..y0 + win_h {
+                    let row = y * width;
+                    for x in x0..x0 + win_w {
+                        let (va, vb) = (luma_a[row + x], luma_b[row + x]);
+                        sum_a += va;
+                        sum_b += vb;
+                        sum_aa += va * va;
+                        sum_bb += vb * vb;
+                        sum_ab += va * vb;
+                    }
+                }
+
+                let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+                let var_a = sum_aa / n - mean_a * mean_a;
+                let var_b = sum_bb / n - mean_b * mean_b;
+                let covar = sum_ab / n - mean_a * mean_b;
+
+                row_total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covar + C2))
+                    / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+                row_count += 1;
+            }
+            (row_total, row_count)
+        })
+        .reduce(|| (0.0, 0), |x, y| (x.0 + y.0, x.1 + y.1));
+
+    if count == 0 {
+        1.0
+    } else {
+        total / count as f64
+    }
+}
+
+/// Control de ejecución del pipeline: reporte de etapas y cancelación cooperativa
+/// Default = sin cancelación ni progreso (batch, búsquedas internas)
+#[derive(Default)]
+struct PipelineControl<'a> {
+    cancel: Option<&'a AtomicBool>,
+    progress: Option<&'a (dyn Fn(&str) + Sync)>,
+}
+
+impl PipelineControl<'_> {
+    /// Marca el inicio de una etapa; falla con Cancelled si se pidió cancelar
+    fn stage(&self, label: &str) -> Result<(), WindooshError> {
+        if self.cancel.is_some_and(|flag| flag.load(Ordering::SeqCst)) {
+            return Err(WindooshError::Cancelled);
+        }
+        if let Some(progress) = self.progress {
+            progress(label);
+        }
+        Ok(())
+    }
+}
+
+/// Emite "process-progress" con la etapa actual
+fn emit_progress(app: &tauri::AppHandle, stage: &str) {
+    let _ = app.emit(
+        "process-progress",
+        ProcessProgress {
+            stage: stage.to_string(),
+        },
+    );
+}
+
+/// Pipeline de procesamiento completo - ahora retorna la imagen procesada
+/// IMPORTANTE: Para mostrar artefactos de compresión (como Squoosh),
+/// re-decodificamos la imagen comprimida para preview
+/// Retorna: (EncodingResult, imagen antes de codificar + preview, tiempos)
+fn process_pipeline(
+    img: &Arc<DynamicImage>,
+    request: &OptimizationRequest,
+    encoder: &dyn ImageEncoder,
+    metadata: &ImageMetadata,
+    control: &PipelineControl,
+) -> Result<(EncodingResult, ProcessedImages, StageTimings), WindooshError> {
+    let elapsed_ms = |start: Instant| start.elapsed().as_secs_f64() * 1000.0;
+    let mut timings = StageTimings::default();
+
+    // EXIF del original reducido a las categorías de metadata_policy
+    let with_exif;
+    let metadata = match request.metadata_policy {
+        Some(ref policy) => {
+            with_exif = ImageMetadata {
+                exif_to_embed: filter_exif(metadata.source_exif.as_deref(), policy)?,
+                ..metadata.clone()
+            };
+            &with_exif
+        }
+        None => metadata,
+    };
+
+    // 0. JPEG sin cambios de píxeles: se re-optimizan los bytes originales, sin decodificar
+    if let Some(source) = request.lossless_jpeg_source(metadata) {
+        control.stage("encode")?;
+        let start = Instant::now();
+        let icc = request.icc_to_embed(metadata);
+        let exif = metadata.exif_to_embed.as_deref();
+        let data =
+            codecs::jpeg::optimize_lossless(source, icc, exif).map_err(WindooshError::Encoding)?;
+        timings.encode_ms = elapsed_ms(start);
+        let result = EncodingResult {
+            data,
+            mime_type: "image/jpeg".to_string(),
+            extension: "jpg".to_string(),
+        };
+        // Los coeficientes DCT no cambian: el resultado se ve exactamente como el original
+        let images = ProcessedImages {
+            preencode: Arc::clone(img),
+            preview: Arc::clone(img),
+        };
+        return Ok((result, images, timings));
+    }
+
+    // 1. Crop (si es necesario) - antes de cualquier otra operación
+    let cropped = match request.crop {
+        Some(ref crop_opts) => {
+            control.stage("crop")?;
+            Some(apply_crop(img, crop_opts)?)
+        }
+        None => None,
+    };
+    let source: &DynamicImage = cropped.as_ref().unwrap_or(img);
+
+    // 2. Rotación / espejado (si es necesario)
+    let transformed = match request.transform {
+        Some(ref transform_opts) => {
+            control.stage("transform")?;
+            Some(apply_transform(source, transform_opts)?)
+        }
+        None => None,
+    };
+    let source: &DynamicImage = transformed.as_ref().unwrap_or(source);
+
+    // 3. Recorte de bordes uniformes (None si no hay nada que quitar)
+    let trimmed = match request.auto_trim {
+        Some(ref trim_opts) => {
+            control.stage("trim")?;
+            apply_auto_trim(source, trim_opts)
+        }
+        None => None,
+    };
+    let source: &DynamicImage = trimmed.as_ref().unwrap_or(source);
+
+    // 4. Recorte centrado a una proporción (None si ya la tiene)
+    let aspect_cropped = match request.aspect_crop {
+        Some(ref ratio) => {
+            control.stage("aspect_crop")?;
+            let ratio = parse_aspect_ratio(ratio)?;
+            aspect_crop_rect(source.width(), source.height(), ratio)
+                .map(|rect| apply_crop(source, &rect))
+                .transpose()?
+        }
+        None => None,
+    };
+    let source: &DynamicImage = aspect_cropped.as_ref().unwrap_or(source);
+
+    // 5. Resize con SIMD (si es necesario)
+    let resize_opts = request
+        .resize
+        .as_ref()
+        .and_then(|opts| resolve_resize_options(source.width(), source.height(), opts));
+    let processed = if let Some(ref resize_opts) = resize_opts {
+        control.stage("resize")?;
+        let start = Instant::now();
+        let resized = resize_with_simd(source, resize_opts)?;
+        timings.resize_ms = elapsed_ms(start);
+        resized
+    } else {
+        source.clone()
+    };
+
+    // 6. Enfoque tras el resize (también sin resize si se pidió)
+    let processed = match request.sharpen {
+        Some(ref sharpen_opts) if sharpen_opts.amount > 0.0 => {
+            control.stage("sharpen")?;
+            apply_sharpen(processed, sharpen_opts)
+        }
+        _ => processed,
+    };
+
+    // 7. Ajustes tonales (si hay valores no neutros)
+    let processed = match request.adjustments {
+        Some(ref adjust_opts) => {
+            control.stage("adjust")?;
+            apply_adjustments(&processed, adjust_opts).unwrap_or(processed)
+        }
+        None => processed,
+    };
+
+    // 8. Lienzo de tamaño exacto (tras el resize y los ajustes, para no alterar el color del relleno)
+    let processed = match request.pad_to {
+        Some(ref pad_opts) => {
+            control.stage("pad")?;
+            apply_pad(processed, pad_opts)?
+        }
+        None => processed,
+    };
+
+    // 9. Marca de agua sobre el resultado final (incluido el lienzo)
+    let processed = match request.watermark {
+        Some(ref watermark_opts) => {
+            control.stage("watermark")?;
+            apply_watermark(processed, watermark_opts)?
+        }
+        None => processed,
+    };
+
+    // 10. Aplanar alpha: con fondo explícito, o sobre el color del borde si el formato no tiene alpha
+    let background = request.flatten_background.or_else(|| {
+        (!encoder.supports_alpha() && processed.color().has_alpha())
+            .then(|| border_background_color(&processed))
+    });
+    let flattened = background.is_some() && processed.color().has_alpha();
+    let processed = match background {
+        Some(background) if flattened => {
+            control.stage("flatten")?;
+            apply_flatten(processed, background)?
+        }
+        _ => processed,
+    };
+
+    // 11. Escala de grises tras aplanar: los encoders ven R = G = B y escriben un canal (JPEG/PNG)
+    let processed = if request.grayscale {
+        control.stage("grayscale")?;
+        processed.grayscale()
+    } else {
+        processed
+    };
+
+    // 12. Quantize (si es necesario)
+    let final_img = if let Some(ref quant_opts) = request.quantize {
+        control.stage("quantize")?;
+        let start = Instant::now();
+        let quantized = apply_quantize(processed, quant_opts)?;
+        timings.quantize_ms = elapsed_ms(start);
+        quantized
+    } else {
+        processed
+    };
+
+    // 13. Encode con el códec seleccionado (los que tienen fases informan de cada una)
+    // El original de más de 8 bits solo equivale a final_img si ninguna etapa cambió los píxeles
+    let without_high_depth;
+    let metadata = if metadata.high_depth_source.is_some() && (flattened || request.alters_pixels()) {
+        without_high_depth = ImageMetadata {
+            high_depth_source: None,
+            ..metadata.clone()
+        };
+        &without_high_depth
+    } else {
+        metadata
+    };
+    control.stage("encode")?;
+    let start = Instant::now();
+    let report = |phase: &str| {
+        if let Some(progress) = control.progress {
+            progress(phase);
+        }
+    };
+    // Única conversión a RGBA8 del pipeline: tras resize/quantize ya lo es y into_rgba8 no copia
+    // El encoder recibe su propia copia porque final_img se conserva como imagen pre-encode
+    let rgba = final_img.into_rgba8();
+    let result = encoder
+        .encode_with_progress(rgba.clone(), &request.options, metadata, &report)
+        .map_err(WindooshError::Encoding)?;
+    timings.encode_ms = elapsed_ms(start);
+    let final_img = DynamicImage::ImageRgba8(rgba);
+
+    // 14. RE-DECODIFICAR la imagen comprimida para mostrar artefactos de compresión
+    // Esto es lo que hace Squoosh: muestra cómo se ve la imagen DESPUÉS de compresión
+    // No la imagen original pre-encoding
+    control.stage("preview")?;
+    #[cfg(feature = "jxl")]
+    if result.mime_type == "image/jxl" && !codecs::jxl::is_lossless(&request.options) {
+        let preview_img = codecs::jxl::decode(&result.data).map_err(WindooshError::ImageDecode)?;
+        let images = ProcessedImages {
+            preencode: Arc::new(final_img),
+            preview: Arc::new(preview_img),
+        };
+        return Ok((result, images, timings));
+    }
+
+    // AVIF no se re-decodifica: image-rs solo decodifica AVIF con la feature nativa (dav1d)
+    let final_img = Arc::new(final_img);
+    let preview_img = if result.mime_type.contains("jpeg")
+        || result.mime_type.contains("webp")
+        || result.mime_type.contains("gif")
+    {
+        // Para formatos con pérdida (GIF pierde color por la paleta), re-decodificar para ver artefactos
+        let decoded = ImageReader::new(Cursor::new(&result.data))
+            .with_guessed_format()
+            .map_err(|e| WindooshError::ImageDecode(e.to_string()))?
+            .decode()
+            .map_err(|e| WindooshError::ImageDecode(e.to_string()))?;
+        Arc::new(decoded)
+    } else {
+        // Para PNG/QOI/BMP/TIFF/ICO (sin pérdida), no hay artefactos visibles
+        Arc::clone(&final_img)
+    };
+
+    let images = ProcessedImages {
+        preencode: final_img,
+        preview: preview_img,
+    };
+    Ok((result, images, timings))
+}
+
+/// Pipeline de animaciones: decode de todos los frames, resize por frame y re-encode animado
+/// Solo WebP y GIF admiten animación; GIF usa una paleta global compartida por los frames
+fn process_animation(
+    bytes: &[u8],
+    request: &OptimizationRequest,
+    control: &PipelineControl,
+) -> Result<(EncodingResult, u32), WindooshError> {
+    let encoder_name = request.encoder_name.as_str();
+    if !matches!(encoder_name, "webp" | "gif") {
+        return Err(WindooshError::Processing(format!(
+            "La animación solo se puede conservar en WebP o GIF, no en '{}'",
+            encoder_name
+        )));
+    }
+
+    control.stage("decode")?;
+    let mut frames = decode_animation(bytes)?;
+    let frame_count = frames.len() as u32;
+
+    // Todos los frames tienen el tamaño del canvas: se planifica con el primero
+    let resize_opts = match (request.resize.as_ref(), frames.first()) {
+        (Some(opts), Some(first)) => {
+            resolve_resize_options(first.image.width(), first.image.height(), opts)
+        }
+        _ => None,
+    };
+    if let Some(ref resize_opts) = resize_opts {
+        control.stage("resize")?;
+        frames = frames
+            .into_par_iter()
+            .map(|frame| {
+                let resized =
+                    resize_with_simd(&DynamicImage::ImageRgba8(frame.image), resize_opts)?;
+                Ok(AnimationFrame {
+                    image: resized.into_rgba8(),
+                    delay_ms: frame.delay_ms,
+                })
+            })
+            .collect::<Result<Vec<_>, WindooshError>>()?;
+    }
+
+    let mut options = request.options.clone();
+    if let Some(ref quant_opts) = request.quantize {
+        let diffusion = quant_opts.diffusion_level()?;
+        // Con tramado ordenado o paleta fija el GIF no puede quantizar por su cuenta
+        let reduce_here = encoder_name != "gif"
+            || quant_opts.ordered_dither()
+            || quant_opts.fixed_palette.is_some();
+        if encoder_name == "gif" {
+            // El códec GIF ya quantiza con paleta global: solo se le pasan los parámetros
+            // Si los frames llegan ya reducidos, sin límite extra ni difusión de error
+            if !options.is_object() {
+                options = Value::Object(Default::default());
+            }
+            if reduce_here {
+                options["num_colors"] = Value::from(256);
+                options["dither"] = Value::from(0.0);
+            } else {
+                let tuning = quant_opts.tuning();
+                options["num_colors"] = Value::from(quant_opts.num_colors);
+                options["dither"] = Value::from(diffusion);
+                options["speed"] = Value::from(tuning.speed);
+                options["min_quality"] = Value::from(tuning.min_quality);
+                options["max_quality"] = Value::from(tuning.max_quality);
+            }
+        }
+        if reduce_here {
+            control.stage("quantize")?;
+            let images: Vec<_> = frames.iter().map(|f| &f.image).collect();
+            let (palette, mut indexed) = match quant_opts.fixed_palette {
+                Some(ref colors) => remap_fixed_palette(&images, colors, diffusion)?,
+                None => {
+                    quantize_shared(&images, quant_opts.num_colors, diffusion, quant_opts.tuning())?
+                }
+            };
+            if quant_opts.ordered_dither() {
+                indexed = images
+                    .iter()
+                    .map(|rgba| ordered_dither_indices(rgba, &palette, quant_opts.dither))
+                    .collect();
+            }
+            for (frame, indices) in frames.iter_mut().zip(indexed) {
+                for (pixel, idx) in frame.image.pixels_mut().zip(indices) {
+                    let c = palette[idx as usize];
+                    pixel.0 = [c.r, c.g, c.b, c.a];
+                }
+            }
+        }
+    }
+
+    control.stage("encode")?;
+    let result = match encoder_name {
+        "gif" => codecs::gif::encode_animated(&frames, &options),
+        _ => codecs::webp::encode_animated(&frames, &options),
+    }
+    .map_err(WindooshError::Encoding)?;
+
+    Ok((result, frame_count))
+}
+
+/// Archivos procesados en paralelo por process_batch
+/// Cada uno mantiene su imagen completa en memoria (y los códecs ya paralelizan internamente)
+const BATCH_MAX_CONCURRENCY: usize = 4;
+
+/// Resultado de un archivo del lote (los errores se reportan por archivo, no abortan el lote)
+fn batch_item_result(
+    path: &str,
+    request: &OptimizationRequest,
+    encoder: &dyn ImageEncoder,
+    output_dir: &std::path::Path,
+    settings: LoadSettings,
+) -> BatchItemResult {
+    match process_batch_item(path, request, encoder, output_dir, settings) {
+        Ok((output_path, original_size, optimized_size)) => BatchItemResult {
+            path: path.to_string(),
+            output_path: Some(output_path),
+            original_size,
+            optimized_size,
+            error: None,
+        },
+        Err(e) => BatchItemResult {
+            path: path.to_string(),
+            output_path: None,
+            original_size: 0,
+            optimized_size: 0,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// Calidad JPEG de las miniaturas (pequeñas: el tamaño importa poco)
+const THUMBNAIL_JPEG_QUALITY: u8 = 80;
+
+/// Decodifica un archivo y genera una miniatura JPEG como data URL base64
+fn build_thumbnail(path: &str, max_size: u32, settings: LoadSettings) -> Result<String, WindooshError> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    if max_size == 0 {
+        return Err(WindooshError::Processing(
+            "El tamaño de miniatura debe ser mayor que 0".to_string(),
+        ));
+    }
+
+    let bytes = std::fs::read(path).map_err(|e| WindooshError::FileRead(e.to_string()))?;
+    let loaded = load_image_logic(bytes, settings).map_err(|e| match e {
+        WindooshError::ImageDecode(msg) => {
+            WindooshError::ImageDecode(format!("Formato no soportado o archivo dañado ({})", msg))
+        }
+        other => other,
+    })?;
+
+    // Mismo cálculo que max_dimension: lado más largo = max_size
+    let thumb_opts = ResizeOptionsDto {
+        width: 0,
+        height: 0,
+        filter: "Bilinear".to_string(),
+        resize_quality: String::new(),
+        lock_aspect: false,
+        fit: String::new(),
+        scale_percent: None,
+        max_dimension: Some(max_size),
+        linear_light: false,
+        no_upscale: false,
+    };
+    let thumb = match resolve_resize_options(loaded.width, loaded.height, &thumb_opts) {
+        Some(ref opts) => resize_with_simd(&loaded.image, opts)?.into_rgba8(),
+        None => loaded.image.to_rgba8(),
+    };
+
+    let encoded = JpegCodec
+        .encode(
+            thumb,
+            &serde_json::json!({ "quality": THUMBNAIL_JPEG_QUALITY }),
+            &ImageMetadata::default(),
+        )
+        .map_err(WindooshError::Encoding)?;
+
+    Ok(format!("data:image/jpeg;base64,{}", STANDARD.encode(&encoded.data)))
+}
+
+/// Carga, procesa y guarda un archivo del lote sin tocar AppState
+/// Retorna: (ruta de salida, tamaño original, tamaño optimizado)
+fn process_batch_item(
+    path: &str,
+    request: &OptimizationRequest,
+    encoder: &dyn ImageEncoder,
+    output_dir: &std::path::Path,
+    settings: LoadSettings,
+) -> Result<(String, usize, usize), WindooshError> {
+    let bytes = std::fs::read(path).map_err(|e| WindooshError::FileRead(e.to_string()))?;
+    let loaded = load_image_logic(bytes, settings)?;
+    let metadata = ImageMetadata {
+        icc_profile: loaded.icc_profile,
+        source_exif: loaded.exif,
+        exif_to_embed: None,
+        source_palette: loaded.palette,
+        source_jpeg: loaded.jpeg_source,
+        high_depth_source: loaded.high_depth_source,
+    };
+
+    let (result, _, _) =
+        process_pipeline(&loaded.image, request, encoder, &metadata, &PipelineControl::default())?;
+
+    let stem = std::path::Path::new(path)
+        .file_stem()
+        .and_then(|n| n.to_str())
+        .unwrap_or("image");
+    let output_path = output_dir.join(format!("{}.{}", stem, result.extension));
+
+    // Mismo directorio y misma extensión: nunca pisar el original
+    if let (Ok(input), Ok(output)) = (
+        std::fs::canonicalize(path),
+        std::fs::canonicalize(&output_path),
+    ) {
+        if input == output {
+            return Err(WindooshError::FileRead(
+                "El archivo de salida sobrescribiría el original".to_string(),
+            ));
+        }
+    }
+
+    let output = output_path.to_string_lossy().into_owned();
+    write_atomic(&output, &result.data, true)?;
+
+    Ok((output, loaded.file_size, result.data.len()))
+}
+
+/// Máximo de codificaciones en la búsqueda de calidad (log2(100) ≈ 7)
+const TARGET_SEARCH_MAX_ITERATIONS: u32 = 8;
+
+/// Si el request usa un códec con pérdida controlado por "quality" (jpeg/webp/avif)
+fn is_lossy_request(request: &OptimizationRequest) -> bool {
+    let option_set = |key: &str| {
+        request
+            .options
+            .get(key)
+            .is_some_and(|v| !v.is_null() && v != &Value::Bool(false))
+    };
+    match request.encoder_name.as_str() {
+        "mozjpeg" | "jpeg" | "avif" => true,
+        // near_lossless implica lossless en el códec WebP
+        "webp" => !option_set("lossless") && !option_set("near_lossless"),
+        _ => false,
+    }
+}
+
+/// Resultado de la búsqueda de calidad: (resultado, imágenes, tiempos, calidad, iteraciones)
+type TargetSearchOutcome = (EncodingResult, ProcessedImages, StageTimings, u8, u32);
+
+/// Busca por bisección la calidad más alta cuyo resultado no supere `target_bytes`
+/// Solo para códecs con pérdida controlados por "quality" (jpeg/webp/avif)
+fn search_quality_for_target(
+    img: &Arc<DynamicImage>,
+    request: &OptimizationRequest,
+    encoder: &dyn ImageEncoder,
+    metadata: &ImageMetadata,
+    target_bytes: usize,
+    control: &PipelineControl,
+) -> Result<TargetSearchOutcome, WindooshError> {
+    if !is_lossy_request(request) {
+        return Err(WindooshError::Processing(format!(
+            "El tamaño objetivo solo está disponible para códecs con pérdida (jpeg/webp/avif), no para '{}'",
+            request.encoder_name
+        )));
+    }
+
+    let mut attempt = request.clone();
+    if !attempt.options.is_object() {
+        attempt.options = Value::Object(Default::default());
+    }
+
+    let (mut low, mut high) = (1u8, 100u8);
+    let mut best: Option<(EncodingResult, ProcessedImages, StageTimings, u8)> = None;
+    let mut smallest = usize::MAX;
+    let mut iterations = 0;
+
+    while low <= high && iterations < TARGET_SEARCH_MAX_ITERATIONS {
+        let quality = low + (high - low) / 2;
+        attempt.options["quality"] = Value::from(quality);
+
+        let (result, images, timings) = process_pipeline(img, &attempt, encoder, metadata, control)?;
+        iterations += 1;
+        smallest = smallest.min(result.data.len());
+
+        if result.data.len() <= target_bytes {
+            best = Some((result, images, timings, quality));
+            low = quality + 1;
+        } else {
+            high = quality - 1;
+        }
+    }
+
+    // Si se agotan las iteraciones nos quedamos con la mejor calidad que cabe
+    match best {
+        Some((result, images, timings, quality)) => {
+            Ok((result, images, timings, quality, iterations))
+        }
+        None => Err(WindooshError::Processing(format!(
+            "No se pudo llegar a {} bytes: el resultado más pequeño ocupa {} bytes",
+            target_bytes, smallest
+        ))),
+    }
+}
+
+/// Lado del proxy de estimate_size respecto a la salida (25% → 1/16 de los píxeles)
+/// Se codifica además un segundo proxy a la mitad de lado para ajustar la curva al contenido
+const ESTIMATE_PROXY_SCALE: f64 = 0.25;
+
+/// Salidas de hasta este número de píxeles se codifican enteras: el proxy apenas ahorraría tiempo
+const ESTIMATE_EXACT_MAX_PIXELS: u64 = 1_000_000;
+
+/// Por debajo de estos píxeles un proxy es demasiado pequeño para ajustar la curva
+const ESTIMATE_MIN_PROXY_PIXELS: u64 = 128 * 128;
+
+/// Rango admitido para el exponente ajustado (fuera de él el ajuste es ruido del encoder)
+const ESTIMATE_EXPONENT_RANGE: (f64, f64) = (0.5, 1.0);
+
+/// Exponente por códec de la extrapolación (bytes ∝ píxeles^k), cuando no se puede ajustar
+/// Al reducir, el detalle se concentra en menos píxeles y el proxy ocupa más por píxel (k < 1);
+/// los códecs con pérdida descartan más detalle fino a tamaño completo que los sin pérdida
+fn size_scaling_exponent(request: &OptimizationRequest) -> f64 {
+    if !is_lossy_request(request) {
+        return 0.95;
+    }
+    match request.encoder_name.as_str() {
+        "avif" => 0.8,
+        _ => 0.85,
+    }
+}
+
+/// Request del proxy: el mismo pipeline con la salida reducida a `scale` por lado
+/// `(w, h)` son las dimensiones tras crop/transform; sin resize (o si no ampliaría) el proxy
+/// es un porcentaje de esa imagen
+fn proxy_request(request: &OptimizationRequest, w: u32, h: u32, scale: f64) -> OptimizationRequest {
+    let scale_px = |v: u32| ((v as f64 * scale).round() as u32).max(1);
+    let resize = request
+        .resize
+        .as_ref()
+        .and_then(|opts| resolve_resize_options(w, h, opts))
+        .filter(|opts| {
+            let (target_w, target_h) = plan_resize_dimensions(w, h, opts);
+            !opts.no_upscale || (target_w <= w && target_h <= h)
+        });
+
+    let proxy_resize = match resize {
+        Some(opts) => ResizeOptionsDto {
+            width: scale_px(opts.width),
+            height: scale_px(opts.height),
+            scale_percent: opts.scale_percent.map(|p| p * scale as f32),
+            ..opts
+        },
+        None => ResizeOptionsDto {
+            width: 0,
+            height: 0,
+            filter: String::new(),
+            resize_quality: "balanced".to_string(),
+            lock_aspect: false,
+            fit: String::new(),
+            scale_percent: Some((scale * 100.0) as f32),
+            max_dimension: None,
+            linear_light: false,
+            no_upscale: false,
+        },
+    };
+
+    let pad_to = request.pad_to.as_ref().map(|pad| PadDto {
+        width: scale_px(pad.width),
+        height: scale_px(pad.height),
+        ..pad.clone()
+    });
+
+    let watermark = request.watermark.as_ref().map(|mark| WatermarkDto {
+        margin: (mark.margin as f64 * scale).round() as u32,
+        ..mark.clone()
+    });
+
+    OptimizationRequest {
+        resize: Some(proxy_resize),
+        pad_to,
+        watermark,
+        ..request.clone()
+    }
+}
+
+/// Tamaño estimado del resultado de `request` (ver estimate_size)
+fn estimate_encoded_size(
+    job: &PipelineJob,
+    request: &OptimizationRequest,
+    encoder: &dyn ImageEncoder,
+) -> Result<SizeEstimate, WindooshError> {
+    let ((w, h), (out_w, out_h)) = job.stage_dimensions(request);
+    let control = PipelineControl::default();
+
+    // Ni la re-optimización sin pérdida ni el original de 16 bits tienen proxy posible (el proxy ya redimensiona)
+    let lossless = request.lossless_jpeg_source(&job.metadata).is_some();
+    let high_depth = job.metadata.high_depth_source.is_some() && !request.alters_pixels();
+    if lossless || high_depth || out_w as u64 * out_h as u64 <= ESTIMATE_EXACT_MAX_PIXELS {
+        let (result, _, _) = process_pipeline(&job.image, request, encoder, &job.metadata, &control)?;
+        return Ok(SizeEstimate {
+            estimated_size: result.data.len(),
+            savings_percent: savings_percent(job.original_size, result.data.len()),
+            confident: true,
+        });
+    }
+
+    // Bytes y píxeles del pipeline completo sobre un proxy a `scale` por lado
+    let encode_proxy = |scale: f64| -> Result<(f64, u64), WindooshError> {
+        let proxy = proxy_request(request, w, h, scale);
+        let (result, images, _) =
+            process_pipeline(&job.image, &proxy, encoder, &job.metadata, &control)?;
+        let pixels = images.preencode.width() as u64 * images.preencode.height() as u64;
+        Ok((result.data.len() as f64, pixels))
+    };
+
+    let (proxy_bytes, proxy_pixels) = encode_proxy(ESTIMATE_PROXY_SCALE)?;
+
+    // Pendiente log-log entre los dos proxies: cómo crece este contenido con este códec
+    // Si el segundo proxy sería diminuto se usa el exponente fijo del códec (menos fiable)
+    let (exponent, fitted) = if proxy_pixels / 4 >= ESTIMATE_MIN_PROXY_PIXELS {
+        let (half_bytes, half_pixels) = encode_proxy(ESTIMATE_PROXY_SCALE / 2.0)?;
+        let slope = (proxy_bytes / half_bytes).ln() / (proxy_pixels as f64 / half_pixels as f64).ln();
+        let (min, max) = ESTIMATE_EXPONENT_RANGE;
+        (slope.clamp(min, max), (min..=max).contains(&slope))
+    } else {
+        (size_scaling_exponent(request), false)
+    };
+
+    // El proxy escala cada lado (también la caja de cover/contain): la salida tiene 1/scale² más píxeles
+    let pixel_ratio = 1.0 / (ESTIMATE_PROXY_SCALE * ESTIMATE_PROXY_SCALE);
+    let estimated_size = (proxy_bytes * pixel_ratio.powf(exponent)).round() as usize;
+
+    Ok(SizeEstimate {
+        estimated_size,
+        savings_percent: savings_percent(job.original_size, estimated_size),
+        confident: fitted && is_lossy_request(request),
+    })
+}
+
+// ============================================================================
+// Comandos Tauri - Async para no bloquear UI
+// ============================================================================
+
+/// Lee el tag Orientation (1-8) del EXIF; None si no está o no es válido
+fn read_exif_orientation(exif: &exif::Exif) -> Option<u32> {
+    let field = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?;
+    field.value.get_uint(0).filter(|o| (1..=8).contains(o))
+}
+
+/// Categorías de metadata_policy
+const METADATA_CATEGORIES: [&str; 4] = ["datetime", "camera", "gps", "copyright"];
+
+/// Categoría de metadata_policy a la que pertenece un tag EXIF
+/// Los tags sin categoría (orientación, dimensiones, miniatura...) nunca se re-incrustan:
+/// tras rotar o redimensionar ya no describirían la imagen
+fn exif_tag_category(tag: exif::Tag) -> Option<&'static str> {
+    use exif::Tag;
+
+    if tag.context() == exif::Context::Gps {
+        return Some("gps");
+    }
+    let category = match tag {
+        Tag::DateTime
+        | Tag::DateTimeOriginal
+        | Tag::DateTimeDigitized
+        | Tag::OffsetTime
+        | Tag::OffsetTimeOriginal
+        | Tag::OffsetTimeDigitized
+        | Tag::SubSecTime
+        | Tag::SubSecTimeOriginal
+        | Tag::SubSecTimeDigitized => "datetime",
+        // Cuerpo, objetivo y ajustes de la toma (ISO, apertura, exposición...)
+        Tag::Make
+        | Tag::Model
+        | Tag::BodySerialNumber
+        | Tag::LensSpecification
+        | Tag::LensMake
+        | Tag::LensModel
+        | Tag::LensSerialNumber
+        | Tag::ExposureTime
+        | Tag::FNumber
+        | Tag::ExposureProgram
+        | Tag::PhotographicSensitivity
+        | Tag::ISOSpeed
+        | Tag::ShutterSpeedValue
+        | Tag::ApertureValue
+        | Tag::ExposureBiasValue
+        | Tag::MaxApertureValue
+        | Tag::MeteringMode
+        | Tag::LightSource
+        | Tag::Flash
+        | Tag::FocalLength
+        | Tag::FocalLengthIn35mmFilm
+        | Tag::ExposureMode
+        | Tag::WhiteBalance => "camera",
+        Tag::Artist | Tag::Copyright | Tag::CameraOwnerName => "copyright",
+        _ => return None,
+    };
+    Some(category)
+}
+
+/// EXIF (TIFF) con solo los tags de las categorías de `policy`; None si no queda ninguno
+/// Una categoría desconocida es un error aunque el original no tenga EXIF
+fn filter_exif(source: Option<&[u8]>, policy: &[String]) -> Result<Option<Vec<u8>>, WindooshError> {
+    if let Some(unknown) = policy.iter().find(|c| !METADATA_CATEGORIES.contains(&c.as_str())) {
+        return Err(WindooshError::Processing(format!(
+            "Categoría de metadatos desconocida: '{}' (usar {})",
+            unknown,
+            METADATA_CATEGORIES.join(", ")
+        )));
+    }
+    let Some(source) = source else {
+        return Ok(None);
+    };
+    let exif = exif::Reader::new()
+        .read_raw(source.to_vec())
+        .map_err(|e| WindooshError::Encoding(format!("EXIF ilegible: {}", e)))?;
+
+    let mut writer = exif::experimental::Writer::new();
+    let mut retained = 0;
+    for field in exif.fields().filter(|f| f.ifd_num == exif::In::PRIMARY) {
+        if exif_tag_category(field.tag).is_some_and(|c| policy.iter().any(|p| p == c)) {
+            writer.push_field(field);
+            retained += 1;
+        }
+    }
+    if retained == 0 {
+        return Ok(None);
+    }
+    let mut output = Cursor::new(Vec::new());
+    writer
+        .write(&mut output, exif.little_endian())
+        .map_err(|e| WindooshError::Encoding(format!("Error escribiendo EXIF: {}", e)))?;
+    Ok(Some(output.into_inner()))
+}
+
+/// Campos EXIF del IFD principal: nombre del tag ("Model", "ISOSpeed", "GPSLatitude"...) → valor con unidades
+/// `tiff` es el bloque EXIF tal como se guardó al cargar; un EXIF dañado es un error
+fn read_exif_fields(tiff: &[u8]) -> Result<BTreeMap<String, String>, WindooshError> {
+    let exif = exif::Reader::new()
+        .read_raw(tiff.to_vec())
+        .map_err(|e| WindooshError::ImageDecode(format!("EXIF ilegible: {}", e)))?;
+    let mut fields = BTreeMap::new();
+    for field in exif.fields().filter(|f| f.ifd_num == exif::In::PRIMARY) {
+        // Los textos ASCII se muestran entre comillas: se quitan para la UI
+        let value = field.display_value().with_unit(&exif).to_string();
+        fields
+            .entry(field.tag.to_string())
+            .or_insert(value.trim_matches('"').to_string());
+    }
+    Ok(fields)
+}
+
+/// Aplica la orientación EXIF a la imagen
+/// Devuelve true si fue necesario rotar/espejar
+fn apply_exif_orientation(img: DynamicImage, orientation: u32) -> (DynamicImage, bool) {
+    let corrected = match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(), // Transpose
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(), // Transverse
+        8 => img.rotate270(),
+        _ => return (img, false),
+    };
+    (corrected, true)
+}
+
+/// Resultado de decodificar un archivo, listo para guardar en AppState
+struct LoadedImage {
+    image: Arc<DynamicImage>,
+    file_size: usize,
+    width: u32,
+    height: u32,
+    orientation_corrected: bool,
+    /// Tag Orientation del EXIF que no se aplicó (auto_orient desactivado)
+    exif_orientation: Option<u32>,
+    icc_profile: Option<Vec<u8>>,
+    /// Bloque EXIF (TIFF) del original, para re-incrustarlo filtrado (metadata_policy)
+    exif: Option<Vec<u8>>,
+    original_color_type: ExtendedColorType,
+    /// Paleta de un PNG indexado: image-rs la expande a RGB(A) al decodificar
+    palette: Option<Vec<[u8; 4]>>,
+    /// Formato según el contenido de los bytes
+    format: Option<ImageFormat>,
+    frame_count: u32,
+    /// Bytes del archivo si es animado (process_animated vuelve a decodificar todos los frames)
+    animation_source: Option<Arc<Vec<u8>>>,
+    /// Bytes del archivo si es un JPEG cuyos píxeles decodificados no se retocaron (ni EXIF ni CMYK)
+    jpeg_source: Option<Arc<Vec<u8>>>,
+    /// Imagen (ya orientada) antes de reducirla a 8 bits, si tenía más precisión
+    high_depth_source: Option<Arc<DynamicImage>>,
+}
+
+/// Frames (canvases ya compuestos) de un GIF/WebP/APNG; None si el formato no es animable
+fn animation_frames(bytes: &[u8]) -> Option<image::Frames<'_>> {
+    use image::codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder};
+    use image::AnimationDecoder;
+
+    match image::guess_format(bytes) {
+        Ok(ImageFormat::Gif) => GifDecoder::new(Cursor::new(bytes))
+            .ok()
+            .map(|d| d.into_frames()),
+        Ok(ImageFormat::WebP) => WebPDecoder::new(Cursor::new(bytes))
+            .ok()
+            .filter(|d| d.has_animation())
+            .map(|d| d.into_frames()),
+        Ok(ImageFormat::Png) => PngDecoder::new(Cursor::new(bytes))
+            .ok()
+            .filter(|d| d.is_apng().unwrap_or(false))
+            .and_then(|d| d.apng().ok())
+            .map(|d| d.into_frames()),
+        _ => None,
+    }
+}
+
+/// Número de frames de un GIF/WebP/APNG (1 para imágenes fijas)
+/// Los frames se recorren sin guardarlos: el documento sigue usando solo el primero
+fn count_frames(bytes: &[u8]) -> u32 {
+    animation_frames(bytes).map_or(1, |frames| {
+        frames.take_while(Result::is_ok).count().max(1) as u32
+    })
+}
+
+/// Decodifica todos los frames con su duración
+fn decode_animation(bytes: &[u8]) -> Result<Vec<AnimationFrame>, WindooshError> {
+    let frames = animation_frames(bytes)
+        .ok_or_else(|| WindooshError::Processing("La imagen no es animada".to_string()))?;
+
+    frames
+        .map(|frame| {
+            let frame = frame.map_err(|e| WindooshError::ImageDecode(e.to_string()))?;
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            Ok(AnimationFrame {
+                delay_ms: numer.checked_div(denom).unwrap_or(0),
+                image: frame.into_buffer(),
+            })
+        })
+        .collect()
+}
+
+/// Paleta RGBA de un PNG indexado (PLTE, con el alpha de tRNS); None si el PNG no es de paleta
+/// Se leen los chunks directamente: el decoder de image-rs solo entrega los píxeles expandidos
+fn read_png_palette(bytes: &[u8]) -> Option<Vec<[u8; 4]>> {
+    let mut rest = bytes.strip_prefix(b"\x89PNG\r\n\x1a\n")?;
+    let mut palette: Option<Vec<[u8; 4]>> = None;
+
+    // Chunk: longitud (4, big-endian) + tipo (4) + datos + CRC (4)
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let data = rest.get(8..8 + len)?;
+        match &rest[4..8] {
+            // Byte 9 de IHDR: tipo de color (3 = indexado)
+            b"IHDR" if data.get(9) != Some(&3) => return None,
+            b"PLTE" => {
+                palette = Some(data.chunks_exact(3).map(|c| [c[0], c[1], c[2], 255]).collect());
+            }
+            // Alpha por entrada; las que faltan son opacas
+            b"tRNS" => {
+                if let Some(palette) = palette.as_mut() {
+                    for (entry, &alpha) in palette.iter_mut().zip(data) {
+                        entry[3] = alpha;
+                    }
+                }
+            }
+            // PLTE y tRNS van siempre antes de los datos de imagen
+            b"IDAT" => break,
+            _ => {}
+        }
+        rest = rest.get(12 + len..)?;
+    }
+
+    palette.filter(|palette| !palette.is_empty() && palette.len() <= 256)
+}
+
+/// JPEG con 4 componentes (CMYK/YCCK): image-rs lo entrega ya convertido a RGB sin indicarlo
+fn is_cmyk_jpeg(bytes: &[u8]) -> bool {
+    use zune_jpeg::zune_core::colorspace::ColorSpace;
+
+    let mut decoder = zune_jpeg::JpegDecoder::new(bytes);
+    decoder.decode_headers().is_ok()
+        && matches!(
+            decoder.get_input_colorspace(),
+            Some(ColorSpace::CMYK | ColorSpace::YCCK)
+        )
+}
+
+/// Reduce imágenes de 16 bits / float a 8 bits por canal (image-rs redondea al convertir)
+/// El resto del pipeline (resize, quantize, códecs) trabaja en 8 bits
+fn to_8bit(img: DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageLuma16(_) => DynamicImage::ImageLuma8(img.to_luma8()),
+        DynamicImage::ImageLumaA16(_) => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgb32F(_) => {
+            DynamicImage::ImageRgb8(img.to_rgb8())
+        }
+        DynamicImage::ImageRgba16(_) | DynamicImage::ImageRgba32F(_) => {
+            DynamicImage::ImageRgba8(img.to_rgba8())
+        }
+        other => other,
+    }
+}
+
+/// Nombre del formato según los magic bytes, incluidos formatos conocidos que no se pueden abrir
+fn sniff_format(bytes: &[u8]) -> Option<String> {
+    if let Ok(format) = image::guess_format(bytes) {
+        return Some(format_name(format));
+    }
+    // JPEG XL: codestream desnudo o contenedor ISOBMFF
+    let unsupported = if bytes.starts_with(&[0xFF, 0x0A])
+        || bytes.get(4..12) == Some(b"JXL \r\n\x87\n")
+    {
+        "JXL"
+    } else if bytes.get(4..8) == Some(b"ftyp")
+        && matches!(bytes.get(8..12), Some(b"heic" | b"heix" | b"mif1" | b"msf1"))
+    {
+        "HEIC"
+    } else if bytes.starts_with(b"8BPS") {
+        "PSD"
+    } else if bytes.starts_with(b"<svg") || bytes.starts_with(b"<?xml") {
+        "SVG"
+    } else {
+        return None;
+    };
+    Some(unsupported.to_string())
+}
+
+/// Nombre de un formato de image-rs para la UI ("PNG", "JPEG", "WEBP"...)
+fn format_name(format: ImageFormat) -> String {
+    format!("{:?}", format).to_uppercase()
+}
+
+/// Traduce un error de image-rs a un mensaje accionable: formato desconocido, no soportado o archivo truncado
+fn describe_decode_error(bytes: &[u8], err: image::ImageError) -> WindooshError {
+    use image::error::ImageError;
+
+    let format = sniff_format(bytes);
+    let message = match (&err, format) {
+        (_, None) => {
+            let header: Vec<String> = bytes.iter().take(8).map(|b| format!("{:02X}", b)).collect();
+            format!(
+                "formato no reconocido (cabecera {}); se admiten PNG, JPEG, WebP, GIF, AVIF, TIFF, BMP y QOI",
+                header.join(" ")
+            )
+        }
+        // Formato reconocido solo por sniff_format: image-rs no tiene decoder para él
+        (ImageError::Unsupported(_), Some(format)) if image::guess_format(bytes).is_err() => {
+            format!("{} no está soportado para abrir", format)
+        }
+        (ImageError::Unsupported(_), Some(format)) => {
+            format!("esta variante de {} no está soportada ({})", format, err)
+        }
+        (ImageError::IoError(io), Some(format))
+            if io.kind() == std::io::ErrorKind::UnexpectedEof =>
+        {
+            format!("el archivo {} está truncado o incompleto", format)
+        }
+        (_, Some(format)) => {
+            let text = err.to_string();
+            let lower = text.to_lowercase();
+            if ["eof", "unexpected end", "truncat", "not enough"]
+                .iter()
+                .any(|hint| lower.contains(hint))
+            {
+                format!("el archivo {} está truncado o incompleto ({})", format, text)
+            } else {
+                format!("el archivo {} está dañado: {}", format, text)
+            }
+        }
+    };
+    WindooshError::ImageDecode(message)
+}
+
+/// Bytes de una imagen RGBA8 de w×h
+fn rgba_bytes(width: u32, height: u32) -> u64 {
+    width as u64 * height as u64 * 4
+}
+
+/// Memoria que ocupará la imagen decodificada (RGBA8), leyendo solo la cabecera
+/// None si no se reconoce el formato: el decode completo dará el error
+fn estimate_decoded_bytes<R: BufRead + Seek>(reader: ImageReader<R>) -> Option<u64> {
+    let (width, height) = reader.with_guessed_format().ok()?.into_dimensions().ok()?;
+    Some(rgba_bytes(width, height))
+}
+
+/// Ajustes de AppState que afectan a la decodificación
+#[derive(Debug, Clone, Copy)]
+struct LoadSettings {
+    /// Se comprueba con las dimensiones de la cabecera, antes de reservar el buffer
+    max_pixels: u64,
+    /// Rotar/espejar según el EXIF; si no, se informa del tag en exif_orientation
+    auto_orient: bool,
+}
+
+/// Helper para cargar imagen desde bytes y actualizar estado
+fn load_image_logic(bytes: Vec<u8>, settings: LoadSettings) -> Result<LoadedImage, WindooshError> {
+    let LoadSettings { max_pixels, auto_orient } = settings;
+    let file_size = bytes.len();
+    // Solo cuenta el contenido: la extensión del archivo (o lo que diga el SO) puede mentir
+    let reader = ImageReader::new(Cursor::new(&bytes))
+        .with_guessed_format()
+        .map_err(|e| WindooshError::ImageDecode(e.to_string()))?;
+    let format = reader.format();
+    let mut decoder = reader
+        .into_decoder()
+        .map_err(|e| describe_decode_error(&bytes, e))?;
+
+    let (width, height) = decoder.dimensions();
+    let pixels = width as u64 * height as u64;
+    if pixels > max_pixels {
+        return Err(WindooshError::ImageDecode(format!(
+            "imagen demasiado grande: {}x{} ({} MP, límite {} MP)",
+            width,
+            height,
+            pixels / 1_000_000,
+            max_pixels / 1_000_000
+        )));
+    }
+
+    // El ICC se lee del decoder antes de consumirlo; un perfil ilegible no impide cargar
+    let mut icc_profile = decoder.icc_profile().ok().flatten();
+
+    let mut original_color_type = decoder.original_color_type();
+    if original_color_type == ExtendedColorType::Rgb8
+        && format == Some(ImageFormat::Jpeg)
+        && is_cmyk_jpeg(&bytes)
+    {
+        original_color_type = ExtendedColorType::Cmyk8;
+    }
+
+    // CMYK: los decoders (tiff, zune-jpeg) ya convierten a RGB usando K, no descartan el canal
+    // El perfil ICC es CMYK y no describe los píxeles RGB resultantes: no se conserva
+    if matches!(
+        original_color_type,
+        ExtendedColorType::Cmyk8 | ExtendedColorType::Cmyk16
+    ) {
+        icc_profile = None;
+    }
+
+    let img = DynamicImage::from_decoder(decoder).map_err(|e| describe_decode_error(&bytes, e))?;
+
+    // from_decoder solo lee el primer frame: se cuenta el resto para avisar al usuario
+    let frame_count = count_frames(&bytes);
+
+    // Fotos de móvil: los píxeles vienen sin rotar y el EXIF indica cómo mostrarlos
+    // Con auto_orient desactivado el tag se guarda para que la UI ofrezca rotar a mano
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(&bytes))
+        .ok();
+    let orientation = exif.as_ref().and_then(read_exif_orientation);
+    let (img, orientation_corrected) = match orientation {
+        Some(orientation) if auto_orient => apply_exif_orientation(img, orientation),
+        _ => (img, false),
+    };
+    let exif_orientation = orientation.filter(|_| !auto_orient);
+
+    // Todo el pipeline trabaja en 8 bits; la versión de más precisión se guarda para PNG keep_16bit
+    let color = img.color();
+    let high_depth_source =
+        (color.bytes_per_pixel() > color.channel_count()).then(|| Arc::new(img.clone()));
+    let img = to_8bit(img);
+
+    let width = img.width();
+    let height = img.height();
+
+    // Re-optimizar el JPEG sin pérdida solo vale si sus píxeles son tal cual los de la imagen cargada
+    let lossless_jpeg = format == Some(ImageFormat::Jpeg)
+        && !orientation_corrected
+        && original_color_type != ExtendedColorType::Cmyk8;
+    let bytes = Arc::new(bytes);
+    let jpeg_source = lossless_jpeg.then(|| Arc::clone(&bytes));
+
+    Ok(LoadedImage {
+        image: Arc::new(img),
+        file_size,
+        width,
+        height,
+        orientation_corrected,
+        exif_orientation,
+        icc_profile,
+        exif: exif.map(|exif| exif.buf().to_vec()),
+        original_color_type,
+        palette: read_png_palette(&bytes),
+        format,
+        frame_count,
+        animation_source: (frame_count > 1).then_some(bytes),
+        jpeg_source,
+        high_depth_source,
+    })
+}
+
+/// Carga una imagen desde disco de forma asíncrona como documento nuevo
+/// NO devuelve preview - el frontend debe llamar a get_original_image_data con el id
+#[tauri::command]
+async fn load_image(path: String, state: State<'_, AppState>) -> Result<ImageInfo, String> {
+    let path_for_load = path.clone();
+    let settings = state.load_settings();
+    // ImageReader::new y no ::open: open fija el formato por la extensión
+    let header_reader = std::fs::File::open(&path)
+        .ok()
+        .map(|file| ImageReader::new(std::io::BufReader::new(file)));
+    if let Some(needed) = header_reader.and_then(estimate_decoded_bytes) {
+        state.reserve_memory(needed)?;
+    }
+
+    // Ejecutar I/O y decode en thread pool, midiendo cada parte por separado
+    // (en unidades de red la lectura puede dominar sobre el decode)
+    let (loaded, read_ms, decode_ms) = tauri::async_runtime::spawn_blocking(move || {
+        let elapsed_ms = |start: Instant| start.elapsed().as_secs_f64() * 1000.0;
+
+        let start = Instant::now();
+        let file_bytes =
+            std::fs::read(&path_for_load).map_err(|e| WindooshError::FileRead(e.to_string()))?;
+        let read_ms = elapsed_ms(start);
+
+        let start = Instant::now();
+        let loaded = load_image_logic(file_bytes, settings)?;
+        Ok::<_, WindooshError>((loaded, read_ms, elapsed_ms(start)))
+    })
+    .await
+    .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+    .map_err(String::from)?;
+
+    let display_name = std::path::Path::new(&path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("image")
+        .to_string();
+
+    let mut info = state.open_document(loaded, Some(path), display_name);
+    info.read_ms = read_ms;
+    info.decode_ms = decode_ms;
+    Ok(info)
+}
+
+/// Lee la imagen completa de stdin (`windoosh -`) y la abre como documento sin archivo de origen
+fn open_stdin_image(app: &tauri::AppHandle) -> Result<ImageInfo, WindooshError> {
+    use std::io::Read;
+
+    let mut bytes = Vec::new();
+    std::io::stdin()
+        .lock()
+        .read_to_end(&mut bytes)
+        .map_err(|e| WindooshError::FileRead(format!("Error leyendo stdin: {}", e)))?;
+
+    let state = app.state::<AppState>();
+    if let Some(needed) = estimate_decoded_bytes(ImageReader::new(Cursor::new(&bytes))) {
+        state.reserve_memory(needed)?;
+    }
+    let loaded = load_image_logic(bytes, state.load_settings())?;
+    Ok(state.open_document(loaded, None, "stdin".to_string()))
+}
+
+/// Formato del archivo según su cabecera (sin decodificarlo); None si no se reconoce
+#[tauri::command]
+async fn detect_format(path: String) -> Result<Option<String>, String> {
+    use std::io::Read;
+
+    let mut header = Vec::with_capacity(64);
+    std::fs::File::open(&path)
+        .and_then(|file| file.take(64).read_to_end(&mut header))
+        .map_err(|e| WindooshError::FileRead(e.to_string()))?;
+    Ok(sniff_format(&header))
+}
+
+/// Carga una imagen desde memoria (bytes) - Usado para Clipboard paste, data URLs y descargas desde JS
+/// El formato se detecta por contenido; el documento no tiene original_path
+#[tauri::command]
+async fn load_image_from_bytes(
+    bytes: Vec<u8>,
+    state: State<'_, AppState>,
+) -> Result<ImageInfo, String> {
+    let settings = state.load_settings();
+    if let Some(needed) = estimate_decoded_bytes(ImageReader::new(Cursor::new(&bytes))) {
+        state.reserve_memory(needed)?;
+    }
+    let loaded = tauri::async_runtime::spawn_blocking(move || load_image_logic(bytes, settings))
+        .await
+        .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+        .map_err(String::from)?;
+
+    // No path for clipboard images
+    Ok(state.open_document(loaded, None, "Clipboard Image".to_string()))
+}
+
+/// Abre como documento nuevo la imagen del portapapeles del sistema
+/// El portapapeles entrega RGBA sin comprimir: ese es el tamaño "original" para el ahorro
+#[tauri::command]
+async fn load_image_from_clipboard(state: State<'_, AppState>) -> Result<ImageInfo, String> {
+    let loaded = tauri::async_runtime::spawn_blocking(|| {
+        let mut clipboard = arboard::Clipboard::new()
+            .map_err(|e| WindooshError::FileRead(format!("Portapapeles no disponible: {}", e)))?;
+        let data = clipboard.get_image().map_err(|e| match e {
+            arboard::Error::ContentNotAvailable => {
+                WindooshError::ImageDecode("El portapapeles no contiene una imagen".to_string())
+            }
+            other => WindooshError::FileRead(format!("Error leyendo el portapapeles: {}", other)),
+        })?;
+
+        let (width, height) = (data.width as u32, data.height as u32);
+        let file_size = data.bytes.len();
+        let rgba = RgbaImage::from_raw(width, height, data.bytes.into_owned()).ok_or_else(|| {
+            WindooshError::ImageDecode("Datos de imagen del portapapeles incompletos".to_string())
+        })?;
+
+        Ok::<_, WindooshError>(LoadedImage {
+            image: Arc::new(DynamicImage::ImageRgba8(rgba)),
+            file_size,
+            width,
+            height,
+            orientation_corrected: false,
+            exif_orientation: None,
+            icc_profile: None,
+            exif: None,
+            original_color_type: ExtendedColorType::Rgba8,
+            palette: None,
+            format: None,
+            frame_count: 1,
+            animation_source: None,
+            jpeg_source: None,
+            high_depth_source: None,
+        })
+    })
+    .await
+    .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+    .map_err(String::from)?;
+
+    Ok(state.open_document(loaded, None, "Clipboard Image".to_string()))
+}
+
+/// Copia la imagen procesada del documento al portapapeles como RGBA
+/// Los formatos comprimidos no se pegan en otras apps: se copian los píxeles (con artefactos)
+#[tauri::command]
+async fn copy_processed_to_clipboard(
+    doc_id: String,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let img_arc = state.with_document(&doc_id, |doc| {
+        doc.processed_image.clone().ok_or(WindooshError::NoImage)
+    })?;
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let rgba = img_arc.to_rgba8();
+        let data = arboard::ImageData {
+            width: rgba.width() as usize,
+            height: rgba.height() as usize,
+            bytes: rgba.into_raw().into(),
+        };
+        arboard::Clipboard::new()
+            .and_then(|mut clipboard| clipboard.set_image(data))
+            .map_err(|e| WindooshError::FileRead(format!("Error escribiendo en el portapapeles: {}", e)))
+    })
+    .await
+    .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+    .map_err(String::from)
+}
+
+/// Cierra un documento y libera sus imágenes
+#[tauri::command]
+fn close_document(doc_id: String, state: State<'_, AppState>) -> Result<(), String> {
+    let id = parse_doc_id(&doc_id)?;
+    state
+        .documents
+        .write()
+        .remove(&id)
+        .map(|_| ())
+        .ok_or_else(|| WindooshError::DocumentNotFound(doc_id).into())
+}
+
+/// Obtiene los datos raw RGBA de la imagen original para canvas
+/// Esta función permite zoom sin pérdida de calidad; tras la primera llamada no reconvierte
+#[tauri::command]
+async fn get_original_image_data(
+    doc_id: String,
+    state: State<'_, AppState>,
+) -> Result<Arc<ImageDataRaw>, String> {
+    Ok(state.document_rgba(&doc_id, false).await?)
+}
+
+/// EXIF del archivo original (cámara, objetivo, ISO, GPS, fecha...) para revisarlo antes de eliminarlo
+/// Sale del bloque leído al cargar (sea cual sea el formato); sin EXIF el mapa queda vacío
+#[tauri::command]
+fn get_exif(doc_id: String, state: State<'_, AppState>) -> Result<BTreeMap<String, String>, String> {
+    let fields = state.with_document(&doc_id, |doc| match doc.original_exif {
+        Some(ref tiff) => read_exif_fields(tiff),
+        None => Ok(BTreeMap::new()),
+    })?;
+    Ok(fields)
+}
+
+/// Hash del contenido del original (el mismo que ImageInfo.content_hash)
+/// Dos archivos con los mismos píxeles dan el mismo hash aunque difieran formato o metadatos
+#[tauri::command]
+fn get_content_hash(doc_id: String, state: State<'_, AppState>) -> Result<String, String> {
+    Ok(state.with_document(&doc_id, |doc| Ok(doc.content_hash.clone()))?)
+}
+
+/// Obtiene un fragmento del buffer RGBA ("original", "processed" o "preencode")
+/// Evita un único mensaje IPC de ~130 MB en imágenes 8K: el frontend pide rangos sucesivos
+#[tauri::command]
+async fn get_image_data_chunk(
+    doc_id: String,
+    source: String,
+    offset: usize,
+    len: usize,
+    state: State<'_, AppState>,
+) -> Result<ImageDataChunk, String> {
+    let img_arc = state.with_document(&doc_id, |doc| match source.as_str() {
+        "original" => Ok(Arc::clone(&doc.original_image)),
+        "processed" => doc.processed_image.clone().ok_or(WindooshError::NoImage),
+        "preencode" => doc.preencode_image.clone().ok_or(WindooshError::NoImage),
+        other => Err(WindooshError::Processing(format!(
+            "Fuente desconocida: '{}' (usar \"original\", \"processed\" o \"preencode\")",
+            other
+        ))),
+    })?;
+
+    let chunk =
+        tauri::async_runtime::spawn_blocking(move || extract_rgba_chunk(&img_arc, offset, len))
+            .await
+            .map_err(|e| WindooshError::Concurrency(e.to_string()))?;
+
+    Ok(chunk)
+}
+
+/// Obtiene los datos raw RGBA de la imagen procesada para canvas
+/// Cacheado hasta el siguiente process_image/undo/redo
+#[tauri::command]
+async fn get_processed_image_data(
+    doc_id: String,
+    state: State<'_, AppState>,
+) -> Result<Arc<ImageDataRaw>, String> {
+    Ok(state.document_rgba(&doc_id, true).await?)
+}
+
+/// Protocolo que sirve el RGBA del canvas como bytes crudos, sin serializar a JSON
+/// URL: windoosh-img://localhost/<doc_id>/<original|processed>
+/// (en Windows/Android: http://windoosh-img.localhost/<doc_id>/<original|processed>)
+const IMAGE_PROTOCOL: &str = "windoosh-img";
+
+/// Responde una petición de windoosh-img desde la misma caché RGBA que get_*_image_data
+/// Cuerpo: RGBA8 fila a fila; dimensiones en las cabeceras X-Image-Width / X-Image-Height
+async fn serve_image_protocol(
+    app: &tauri::AppHandle,
+    path: &str,
+) -> tauri::http::Response<Vec<u8>> {
+    use tauri::http::{header, Response, StatusCode};
+
+    let mut segments = path.trim_matches('/').split('/');
+    let doc_id = segments.next().unwrap_or_default();
+    let result = match segments.next() {
+        Some("original") => app.state::<AppState>().document_rgba(doc_id, false).await,
+        Some("processed") => app.state::<AppState>().document_rgba(doc_id, true).await,
+        _ => Err(WindooshError::Processing(format!(
+            "Ruta no válida: '{}' (usar /<doc_id>/original o /<doc_id>/processed)",
+            path
+        ))),
+    };
+
+    let response = match result {
+        Ok(data) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header("X-Image-Width", data.width)
+            .header("X-Image-Height", data.height)
+            .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .header(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                "X-Image-Width, X-Image-Height",
+            )
+            .body(data.data.clone()),
+        Err(e) => {
+            let status = match e {
+                WindooshError::DocumentNotFound(_) | WindooshError::NoImage => {
+                    StatusCode::NOT_FOUND
+                }
+                WindooshError::Processing(_) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                .body(e.to_string().into_bytes())
+        }
+    };
+    // Las cabeceras son fijas y válidas: el builder no puede fallar
+    response.unwrap_or_default()
+}
+
+/// Obtiene los datos raw RGBA de la imagen procesada antes de codificar
+/// Junto a get_processed_image_data permite comparar "sin comprimir" vs "comprimida"
+#[tauri::command]
+async fn get_preencode_image_data(
+    doc_id: String,
+    state: State<'_, AppState>,
+) -> Result<ImageDataRaw, String> {
+    let img_arc = state.with_document(&doc_id, |doc| {
+        doc.preencode_image.clone().ok_or(WindooshError::NoImage)
+    })?;
+
+    let result =
+        tauri::async_runtime::spawn_blocking(move || extract_rgba_data(Arc::unwrap_or_clone(img_arc)))
+        .await
+        .map_err(|e| WindooshError::Concurrency(e.to_string()))?;
+
+    Ok(result)
+}
+
+/// Procesa la imagen con las opciones dadas
+/// Almacena la imagen procesada en el documento para get_processed_image_data
+#[tauri::command]
+async fn process_image(
+    app: tauri::AppHandle,
+    doc_id: String,
+    request: OptimizationRequest,
+    state: State<'_, AppState>,
+) -> Result<OptimizationResult, String> {
+    Ok(run_process_image(app, &state, &doc_id, request).await?)
+}
+
+/// Variante sin espera de process_image: responde al instante y el resultado llega por evento
+/// "process-complete" lleva `job_id` y el OptimizationResult; "process-error" el mensaje de error
+#[tauri::command]
+fn process_image_async_event(
+    app: tauri::AppHandle,
+    doc_id: String,
+    request: OptimizationRequest,
+    job_id: String,
+) {
+    tauri::async_runtime::spawn(async move {
+        let state = app.state::<AppState>();
+        match run_process_image(app.clone(), &state, &doc_id, request).await {
+            Ok(result) => {
+                let _ = app.emit("process-complete", ProcessComplete { job_id, result });
+            }
+            Err(e) => {
+                let _ = app.emit(
+                    "process-error",
+                    ProcessError {
+                        job_id,
+                        error: e.to_string(),
+                    },
+                );
+            }
+        }
+    });
+}
+
+/// Cuerpo de process_image: caché, pipeline en thread pool y actualización del documento
+async fn run_process_image(
+    app: tauri::AppHandle,
+    state: &AppState,
+    doc_id: &str,
+    request: OptimizationRequest,
+) -> Result<OptimizationResult, WindooshError> {
+    // Obtener Arcs sin clonar bytes subyacentes; el lock no se mantiene durante el proceso
+    // Imágenes del portapapeles no tienen path: se usa el id del documento como fuente
+    let (version, (job, cache_key)) = state.begin_processing(doc_id, |doc| {
+        let source = doc.original_path.as_deref().unwrap_or(doc_id);
+        Ok((doc.pipeline_job(), ResultCache::key(source, &request)))
+    })?;
+    let encoder = state.encoders.create(&request.encoder_name)?;
+
+    let cached = cache_key.and_then(|key| state.result_cache.lock().get(key));
+    let (result, images, timings) = match cached {
+        // Cache hit: mismo original y mismas opciones, no se toca el thread pool (tiempos en 0)
+        Some(entry) => (entry.encoded, entry.images, StageTimings::default()),
+        None => {
+            state.reserve_memory(job.estimated_bytes(&request))?;
+            job.cancel_flag.store(false, Ordering::SeqCst);
+            let worker_pool = state.worker_pool.read().clone();
+
+            // Procesar en thread pool
+            let (result, images, timings) = tauri::async_runtime::spawn_blocking(move || {
+                worker_pool.install(|| {
+                    let progress = |stage: &str| emit_progress(&app, stage);
+                    let control = PipelineControl {
+                        cancel: Some(&job.cancel_flag),
+                        progress: Some(&progress),
+                    };
+                    process_pipeline(
+                        &job.image,
+                        &request,
+                        encoder.as_ref(),
+                        &job.metadata,
+                        &control,
+                    )
+                })
+            })
+            .await
+            .map_err(|e| WindooshError::Concurrency(e.to_string()))??;
+
+            let entry = CachedResult {
+                encoded: Arc::new(result),
+                images,
+            };
+            if let Some(key) = cache_key {
+                state.result_cache.lock().insert(key, entry.clone());
+            }
+            (entry.encoded, entry.images, timings)
+        }
+    };
+
+    let optimized_size = result.data.len();
+    let savings_percent = savings_percent(job.original_size, optimized_size);
+    let size_increased = optimized_size > job.original_size;
+
+    // Guardar metadata y imagen procesada, salvo que otro process_image más nuevo ya haya empezado
+    // (el resultado sigue en caché: si se vuelve a pedir no hay que recalcularlo)
+    state.commit_processed(doc_id, version, |doc| {
+        doc.set_processed(
+            images,
+            OptimizationMetadata {
+                optimized_size,
+                savings_percent,
+                size_increased,
+                mime_type: result.mime_type.clone(),
+                extension: result.extension.clone(),
+                timings,
+            },
+        );
+    })?;
+
+    Ok(OptimizationResult {
+        optimized_size,
+        savings_percent,
+        size_increased,
+        mime_type: result.mime_type.clone(),
+        extension: result.extension.clone(),
+        timings,
+    })
+}
+
+/// Preview a resolución completa de la reducción de paleta, sin codificar
+/// Queda como imagen procesada (get_processed_image_data) pero no entra en el historial
+#[tauri::command]
+async fn preview_quantize(
+    doc_id: String,
+    opts: QuantizeOptionsDto,
+    state: State<'_, AppState>,
+) -> Result<ImageDataRaw, String> {
+    let (version, image) =
+        state.begin_processing(&doc_id, |doc| Ok(Arc::clone(&doc.original_image)))?;
+    state.reserve_memory(2 * rgba_bytes(image.width(), image.height()))?;
+    let worker_pool = state.worker_pool.read().clone();
+
+    let (quantized, raw) = tauri::async_runtime::spawn_blocking(move || {
+        worker_pool.install(|| {
+            let quantized = apply_quantize(image.as_ref().clone(), &opts)?;
+            // Una copia para el canvas; la otra queda como imagen procesada
+            let raw = extract_rgba_data(quantized.clone());
+            Ok::<_, WindooshError>((Arc::new(quantized), raw))
+        })
+    })
+    .await
+    .map_err(|e| WindooshError::Concurrency(e.to_string()))??;
+
+    state.commit_processed(&doc_id, version, |doc| {
+        doc.show_processed(ProcessedImages {
+            preencode: Arc::clone(&quantized),
+            preview: quantized,
+        });
+    })?;
+
+    Ok(raw)
+}
+
+/// Preview de artefactos de una región del original (el viewport visible) sin codificar la imagen entera
+/// El rectángulo está en píxeles del original: crop/transform/auto_trim/aspect_crop/resize/pad_to/watermark del request se ignoran
+/// No toca la imagen procesada ni el historial del documento
+#[tauri::command]
+async fn process_region(
+    doc_id: String,
+    request: OptimizationRequest,
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+    state: State<'_, AppState>,
+) -> Result<ImageDataRaw, String> {
+    let job = state.with_document(&doc_id, |doc| Ok(doc.pipeline_job()))?;
+    let encoder = state.encoders.create(&request.encoder_name)?;
+    let worker_pool = state.worker_pool.read().clone();
+
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        let region = CropOptionsDto {
+            x,
+            y,
+            width: w,
+            height: h,
+        };
+        let tile = Arc::new(apply_crop(&job.image, &region)?);
+        // Re-optimización sin pérdida: la región se ve exactamente como en el original
+        if request.lossless_jpeg_source(&job.metadata).is_some() {
+            return Ok(extract_rgba_data(Arc::unwrap_or_clone(tile)));
+        }
+        // El fondo por defecto sale del borde de la imagen entera, no del de la región
+        let flatten_background = request.flatten_background.or_else(|| {
+            (!encoder.supports_alpha() && job.image.color().has_alpha())
+                .then(|| border_background_color(&job.image))
+        });
+        let tile_request = OptimizationRequest {
+            flatten_background,
+            crop: None,
+            transform: None,
+            auto_trim: None,
+            aspect_crop: None,
+            resize: None,
+            pad_to: None,
+            watermark: None,
+            // Sin crop/resize el pipeline creería que el tile es el original y lo re-optimizaría
+            // sin pérdida, aunque el resultado real sí se vuelve a codificar
+            jpeg_lossless_optimize: false,
+            ..request
+        };
+        let (_, images, _) = worker_pool.install(|| {
+            process_pipeline(
+                &tile,
+                &tile_request,
+                encoder.as_ref(),
+                &job.metadata,
+                &PipelineControl::default(),
+            )
+        })?;
+        // Sin la pre-encode, la preview suele quedar como única referencia y no se copia
+        let ProcessedImages { preview, preencode } = images;
+        drop(preencode);
+        Ok::<_, WindooshError>(extract_rgba_data(Arc::unwrap_or_clone(preview)))
+    })
+    .await
+    .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+    .map_err(String::from)?;
+
+    Ok(result)
+}
+
+/// Encoders que compara compare_all_codecs (los no registrados, como jxl sin feature, se omiten)
+const COMPARE_ENCODERS: [&str; 5] = ["mozjpeg", "webp", "oxipng", "avif", "jxl"];
+
+/// Codifica el documento con cada encoder usando los mismos ajustes, en paralelo
+/// Las opciones que un encoder no conoce se ignoran; no modifica la imagen procesada
+#[tauri::command]
+async fn compare_all_codecs(
+    doc_id: String,
+    base_request: OptimizationRequest,
+    state: State<'_, AppState>,
+) -> Result<HashMap<String, CodecComparison>, String> {
+    let job = Arc::new(state.with_document(&doc_id, |doc| Ok(doc.pipeline_job()))?);
+    let worker_pool = state.worker_pool.read().clone();
+
+    // Un spawn_blocking por encoder: se lanzan todos antes de esperar el primero
+    // Comparten el mismo pool, así que entre todos no pasan del número de hilos configurado
+    let handles: Vec<_> = COMPARE_ENCODERS
+        .iter()
+        .filter_map(|&name| state.encoders.create(name).ok().map(|encoder| (name, encoder)))
+        .map(|(name, encoder)| {
+            let job = Arc::clone(&job);
+            let worker_pool = worker_pool.clone();
+            let request = OptimizationRequest {
+                encoder_name: name.to_string(),
+                ..base_request.clone()
+            };
+            let handle = tauri::async_runtime::spawn_blocking(move || {
+                worker_pool.install(|| {
+                    process_pipeline(
+                        &job.image,
+                        &request,
+                        encoder.as_ref(),
+                        &job.metadata,
+                        &PipelineControl::default(),
+                    )
+                    .map(|(result, _, _)| result)
+                })
+            });
+            (name, handle)
+        })
+        .collect();
+
+    let mut comparison = HashMap::new();
+    for (name, handle) in handles {
+        let outcome = handle
+            .await
+            .map_err(|e| WindooshError::Concurrency(e.to_string()))?;
+        let entry = match outcome {
+            Ok(result) => CodecComparison {
+                size: result.data.len(),
+                savings_percent: savings_percent(job.original_size, result.data.len()),
+                mime_type: result.mime_type,
+                error: None,
+            },
+            Err(e) => CodecComparison {
+                size: 0,
+                savings_percent: 0.0,
+                mime_type: String::new(),
+                error: Some(e.to_string()),
+            },
+        };
+        comparison.insert(name.to_string(), entry);
+    }
+
+    Ok(comparison)
+}
+
+/// Comprime al tamaño objetivo buscando la calidad adecuada
+/// Actualiza la imagen procesada igual que process_image
+#[tauri::command]
+async fn optimize_to_target(
+    app: tauri::AppHandle,
+    doc_id: String,
+    request: OptimizationRequest,
+    target_bytes: usize,
+    state: State<'_, AppState>,
+) -> Result<TargetSizeResult, String> {
+    let (version, job) = state.begin_processing(&doc_id, |doc| Ok(doc.pipeline_job()))?;
+    let encoder = state.encoders.create(&request.encoder_name)?;
+    state.reserve_memory(job.estimated_bytes(&request))?;
+    job.cancel_flag.store(false, Ordering::SeqCst);
+    let worker_pool = state.worker_pool.read().clone();
+
+    // Varias codificaciones completas: siempre en thread pool
+    let (result, images, timings, quality, iterations) =
+        tauri::async_runtime::spawn_blocking(move || {
+            worker_pool.install(|| {
+                let progress = |stage: &str| emit_progress(&app, stage);
+                let control = PipelineControl {
+                    cancel: Some(&job.cancel_flag),
+                    progress: Some(&progress),
+                };
+                search_quality_for_target(
+                    &job.image,
+                    &request,
+                    encoder.as_ref(),
+                    &job.metadata,
+                    target_bytes,
+                    &control,
+                )
+            })
+        })
+        .await
+        .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+        .map_err(String::from)?;
+
+    let optimized_size = result.data.len();
+    let savings_percent = savings_percent(job.original_size, optimized_size);
+    let size_increased = optimized_size > job.original_size;
+
+    state.commit_processed(&doc_id, version, |doc| {
+        doc.set_processed(
+            images,
+            OptimizationMetadata {
+                optimized_size,
+                savings_percent,
+                size_increased,
+                mime_type: result.mime_type.clone(),
+                extension: result.extension.clone(),
+                timings,
+            },
+        );
+    })?;
+
+    Ok(TargetSizeResult {
+        optimized_size,
+        savings_percent,
+        quality,
+        iterations,
+        mime_type: result.mime_type,
+        extension: result.extension,
+    })
+}
+
+/// Estimación rápida del tamaño final, para actualizar la UI mientras se mueve un slider
+/// Codifica un proxy al 25% y extrapola con un exponente por códec; las salidas pequeñas se
+/// codifican enteras. No toca la imagen procesada: el tamaño exacto lo da process_image al soltar
+#[tauri::command]
+async fn estimate_size(
+    doc_id: String,
+    request: OptimizationRequest,
+    state: State<'_, AppState>,
+) -> Result<SizeEstimate, String> {
+    let job = state.with_document(&doc_id, |doc| Ok(doc.pipeline_job()))?;
+    let encoder = state.encoders.create(&request.encoder_name)?;
+    let worker_pool = state.worker_pool.read().clone();
+
+    let estimate = tauri::async_runtime::spawn_blocking(move || {
+        worker_pool.install(|| estimate_encoded_size(&job, &request, encoder.as_ref()))
+    })
+    .await
+    .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+    .map_err(String::from)?;
+
+    Ok(estimate)
+}
+
+/// Ruta de guardado por defecto: junto al original, como "<nombre>.optimized.<ext>"
+/// El sufijo evita pisar el original cuando el formato no cambia
+fn default_save_path(original_path: &str, extension: &str) -> String {
+    let original = std::path::Path::new(original_path);
+    let stem = original
+        .file_stem()
+        .and_then(|n| n.to_str())
+        .unwrap_or("image");
+    original
+        .with_file_name(format!("{}.optimized.{}", stem, extension))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Destino de save_image: la ruta indicada o, sin ella, junto al original
+enum SaveTarget {
+    Path(String),
+    NextToOriginal(String),
+}
+
+impl SaveTarget {
+    /// La extensión por defecto depende del encoder: se resuelve tras codificar
+    fn resolve(self, extension: &str) -> String {
+        match self {
+            SaveTarget::Path(path) => path,
+            SaveTarget::NextToOriginal(original) => default_save_path(&original, extension),
+        }
+    }
+}
+
+/// Comprueba que exista el directorio de `path`; con `create` lo crea (y sus padres) si falta
+fn ensure_parent_dir(path: &str, create: bool) -> Result<(), WindooshError> {
+    let parent = match std::path::Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => return Ok(()),
+    };
+    if parent.is_dir() {
+        return Ok(());
+    }
+    if !create {
+        return Err(WindooshError::FileRead(format!(
+            "directorio destino no existe: {}",
+            parent.display()
+        )));
+    }
+    std::fs::create_dir_all(parent).map_err(|e| {
+        WindooshError::FileRead(format!("No se pudo crear {}: {}", parent.display(), e))
+    })
+}
+
+/// Escribe a un temporal en el mismo directorio y lo renombra al terminar
+/// Un fallo a mitad de escritura nunca deja el destino truncado
+fn write_atomic(path: &str, data: &[u8], overwrite: bool) -> Result<(), WindooshError> {
+    let target = std::path::Path::new(path);
+    if !overwrite && target.exists() {
+        return Err(WindooshError::FileExists(path.to_string()));
+    }
+
+    let file_name = target
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| WindooshError::FileRead(format!("Ruta de destino inválida: {}", path)))?;
+    let temp = target.with_file_name(format!(".{}.{}.tmp", file_name, Uuid::new_v4()));
+
+    // rename en el mismo directorio es atómico (y reemplaza el destino también en Windows)
+    let written = std::fs::write(&temp, data).and_then(|_| std::fs::rename(&temp, target));
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temp);
+        return Err(WindooshError::FileRead(format!("Error al guardar: {}", e)));
+    }
+    Ok(())
+}
+
+/// Guarda la imagen optimizada en disco
+/// Sin `path` se guarda junto al original; los documentos sin archivo (bytes, portapapeles) requieren ruta
+/// Con `overwrite` = false falla con FileExists si el destino ya existe
+/// Con `create_dirs` se crean las carpetas que falten de `path`; sin él, falta una es un error
+#[tauri::command]
+async fn save_image(
+    app: tauri::AppHandle,
+    doc_id: String,
+    path: Option<String>,
+    request: OptimizationRequest,
+    overwrite: bool,
+    create_dirs: Option<bool>,
+    state: State<'_, AppState>,
+) -> Result<SaveResult, String> {
+    let (job, target, original_path, original_format) = state.with_document(&doc_id, |doc| {
+        let target = match (path, &doc.original_path) {
+            (Some(path), _) => SaveTarget::Path(path),
+            (None, Some(original)) => SaveTarget::NextToOriginal(original.clone()),
+            (None, None) => {
+                return Err(WindooshError::FileRead(
+                    "El documento no proviene de un archivo: indica una ruta de destino".to_string(),
+                ))
+            }
+        };
+        Ok((doc.pipeline_job(), target, doc.original_path.clone(), doc.detected_format))
+    })?;
+    let encoder = state.encoders.create(&request.encoder_name)?;
+    job.cancel_flag.store(false, Ordering::SeqCst);
+    let worker_pool = state.worker_pool.read().clone();
+
+    let (path, final_size, kept_original) = tauri::async_runtime::spawn_blocking(move || {
+        // Antes de codificar: un directorio inexistente no debe costar el encode entero
+        if let SaveTarget::Path(ref path) = target {
+            ensure_parent_dir(path, create_dirs.unwrap_or(false))?;
+        }
+        let (result, _, _) = worker_pool.install(|| {
+            let progress = |stage: &str| emit_progress(&app, stage);
+            let control = PipelineControl {
+                cancel: Some(&job.cancel_flag),
+                progress: Some(&progress),
+            };
+            process_pipeline(
+                &job.image,
+                &request,
+                encoder.as_ref(),
+                &job.metadata,
+                &control,
+            )
+        })?;
+        let original_bytes = if request.keep_original_if_larger {
+            original_if_smaller(&job, &request, &result, original_path.as_deref(), original_format)
+        } else {
+            None
+        };
+        let data = original_bytes.as_deref().unwrap_or(&result.data);
+        let path = target.resolve(&result.extension);
+        write_atomic(&path, data, overwrite)?;
+        Ok::<_, WindooshError>((path, data.len(), original_bytes.is_some()))
+    })
+    .await
+    .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+    .map_err(String::from)?;
+
+    Ok(SaveResult {
+        path,
+        final_size,
+        kept_original,
+    })
+}
+
+/// Bytes del archivo original si `result` ocupa más y el original sirve en su lugar:
+/// mismo formato de salida, ninguna etapa que cambie los píxeles y archivo sin modificar en disco
+/// Si el encoder quita o filtra metadatos, el original tampoco puede llevarlos: un JPEG se
+/// re-optimiza sin pérdida con el mismo ICC/EXIF que la re-codificación; otros formatos no se sustituyen
+fn original_if_smaller(
+    job: &PipelineJob,
+    request: &OptimizationRequest,
+    result: &EncodingResult,
+    original_path: Option<&str>,
+    original_format: Option<ImageFormat>,
+) -> Option<Vec<u8>> {
+    let same_format = original_format.is_some_and(|f| f.to_mime_type() == result.mime_type);
+    let keeps_metadata = request.keeps_metadata();
+    let strippable = original_format == Some(ImageFormat::Jpeg);
+    if !same_format || request.alters_pixels() || !(keeps_metadata || strippable) {
+        return None;
+    }
+    if keeps_metadata && result.data.len() <= job.original_size {
+        return None;
+    }
+    let bytes = std::fs::read(original_path?)
+        .ok()
+        .filter(|bytes| bytes.len() == job.original_size)?;
+    let bytes = if keeps_metadata {
+        bytes
+    } else {
+        let exif = match request.metadata_policy {
+            Some(ref policy) => filter_exif(job.metadata.source_exif.as_deref(), policy).ok()?,
+            None => None,
+        };
+        let icc = request.icc_to_embed(&job.metadata);
+        codecs::jpeg::optimize_lossless(&bytes, icc, exif.as_deref()).ok()?
+    };
+    (bytes.len() < result.data.len()).then_some(bytes)
+}
+
+/// Devuelve los bytes codificados sin escribir a disco (p. ej. para subirlos a un servidor)
+/// Ya están comprimidos, así que pasarlos por IPC es mucho más barato que el RGBA crudo
+#[tauri::command]
+async fn get_encoded_bytes(
+    app: tauri::AppHandle,
+    doc_id: String,
+    request: OptimizationRequest,
+    state: State<'_, AppState>,
+) -> Result<EncodingResult, String> {
+    let job = state.with_document(&doc_id, |doc| Ok(doc.pipeline_job()))?;
+    let encoder = state.encoders.create(&request.encoder_name)?;
+    job.cancel_flag.store(false, Ordering::SeqCst);
+    let worker_pool = state.worker_pool.read().clone();
+
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        worker_pool.install(|| {
+            let progress = |stage: &str| emit_progress(&app, stage);
+            let control = PipelineControl {
+                cancel: Some(&job.cancel_flag),
+                progress: Some(&progress),
+            };
+            let (result, _, _) = process_pipeline(
+                &job.image,
+                &request,
+                encoder.as_ref(),
+                &job.metadata,
+                &control,
+            )?;
+            Ok::<_, WindooshError>(result)
+        })
+    })
+    .await
+    .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+    .map_err(String::from)?;
+
+    Ok(result)
+}
+
+/// Re-codifica una imagen animada conservando todos los frames y sus tiempos
+/// Si se indica `path` también escribe el resultado a disco (como save_image: atómico y,
+/// sin `overwrite`, FileExists si el destino ya existe)
+#[tauri::command]
+async fn process_animated(
+    app: tauri::AppHandle,
+    doc_id: String,
+    request: OptimizationRequest,
+    path: Option<String>,
+    overwrite: Option<bool>,
+    state: State<'_, AppState>,
+) -> Result<AnimatedResult, String> {
+    let (source, original_size, cancel_flag) = state.with_document(&doc_id, |doc| {
+        let source = doc.animation_source.clone().ok_or_else(|| {
+            WindooshError::Processing("El documento no es una imagen animada".to_string())
+        })?;
+        Ok((source, doc.original_size, Arc::clone(&doc.cancel_flag)))
+    })?;
+    cancel_flag.store(false, Ordering::SeqCst);
+    let worker_pool = state.worker_pool.read().clone();
+
+    let (result, frame_count) = tauri::async_runtime::spawn_blocking(move || {
+        let (result, frame_count) = worker_pool.install(|| {
+            let progress = |stage: &str| emit_progress(&app, stage);
+            let control = PipelineControl {
+                cancel: Some(&cancel_flag),
+                progress: Some(&progress),
+            };
+            process_animation(&source, &request, &control)
+        })?;
+        if let Some(path) = path {
+            write_atomic(&path, &result.data, overwrite.unwrap_or(false))?;
+        }
+        Ok::<_, WindooshError>((result, frame_count))
+    })
+    .await
+    .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+    .map_err(String::from)?;
+
+    let total_size = result.data.len();
+    Ok(AnimatedResult {
+        frame_count,
+        total_size,
+        savings_percent: savings_percent(original_size, total_size),
+        mime_type: result.mime_type,
+        extension: result.extension,
+    })
+}
+
+/// Genera una miniatura (lado más largo = max_size) como data URL JPEG
+/// No usa los documentos de AppState: pensada para listas de archivos del modo batch
+#[tauri::command]
+async fn generate_thumbnail(
+    path: String,
+    max_size: u32,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    let settings = state.load_settings();
+    let data_url =
+        tauri::async_runtime::spawn_blocking(move || build_thumbnail(&path, max_size, settings))
+            .await
+            .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+            .map_err(String::from)?;
+
+    Ok(data_url)
+}
+
+/// Optimiza varios archivos con la misma configuración y los guarda en output_dir
+/// No usa los documentos de AppState: la imagen cargada en el editor no se modifica
+/// Emite "batch-progress" por cada archivo terminado (con o sin error)
+#[tauri::command]
+async fn process_batch(
+    app: tauri::AppHandle,
+    paths: Vec<String>,
+    request: OptimizationRequest,
+    output_dir: String,
+    state: State<'_, AppState>,
+) -> Result<Vec<BatchItemResult>, String> {
+    // Un encoder desconocido falla el lote entero antes de leer ningún archivo
+    let encoder = state.encoders.create(&request.encoder_name)?;
+    let settings = state.load_settings();
+    let worker_pool = state.worker_pool.read().clone();
 
-    // 2. Quantize (si es necesario)
-    let final_img = if let Some(ref quant_opts) = request.quantize {
-        apply_quantize(processed, quant_opts)?
-    } else {
-        processed
-    };
+    let results = tauri::async_runtime::spawn_blocking(move || {
+        use std::sync::atomic::AtomicUsize;
 
-    // 3. Encode con el códec seleccionado
-    let encoder = get_encoder(&request.encoder_name);
-    let result = encoder
-        .encode(&final_img, &request.options)
-        .map_err(WindooshError::Encoding)?;
+        let output_dir = std::path::Path::new(&output_dir);
+        std::fs::create_dir_all(output_dir)
+            .map_err(|e| WindooshError::FileRead(format!("Error creando directorio: {}", e)))?;
 
-    // 4. RE-DECODIFICAR la imagen comprimida para mostrar artefactos de compresión
-    // Esto es lo que hace Squoosh: muestra cómo se ve la imagen DESPUÉS de compresión
-    // No la imagen original pre-encoding
-    let preview_img = if result.mime_type.contains("jpeg") || result.mime_type.contains("webp") {
-        // Para formatos con pérdida, re-decodificar para ver artefactos
-        ImageReader::new(Cursor::new(&result.data))
-            .with_guessed_format()
-            .map_err(|e| WindooshError::ImageDecode(e.to_string()))?
-            .decode()
-            .map_err(|e| WindooshError::ImageDecode(e.to_string()))?
-    } else {
-        // Para PNG (sin pérdida), no hay artefactos visibles
-        final_img
-    };
+        // Hasta BATCH_MAX_CONCURRENCY archivos a la vez (acota las imágenes completas en memoria)
+        // y los hilos configurados repartidos entre ellos: cada archivo codifica en su propio pool
+        // Los workers son hilos normales: un hilo rayon esperando en otro pool robaría más archivos
+        let concurrency = BATCH_MAX_CONCURRENCY.min(worker_pool.threads).max(1);
+        let file_pools = (0..concurrency)
+            .map(|_| WorkerPool::new(worker_pool.threads / concurrency))
+            .collect::<Result<Vec<_>, _>>()?;
 
-    Ok((result, preview_img))
-}
+        let total = paths.len();
+        let next = AtomicUsize::new(0);
+        let completed = AtomicUsize::new(0);
 
-// ============================================================================
-// Comandos Tauri - Async para no bloquear UI
-// ============================================================================
+        let mut indexed = std::thread::scope(|scope| {
+            let workers: Vec<_> = file_pools
+                .iter()
+                .map(|file_pool| {
+                    let (paths, request, encoder) = (&paths, &request, &encoder);
+                    let (app, next, completed) = (&app, &next, &completed);
+                    scope.spawn(move || {
+                        let mut items = Vec::new();
+                        loop {
+                            let index = next.fetch_add(1, Ordering::SeqCst);
+                            let Some(path) = paths.get(index) else {
+                                break;
+                            };
+                            let item = file_pool.install(|| {
+                                batch_item_result(
+                                    path,
+                                    request,
+                                    encoder.as_ref(),
+                                    output_dir,
+                                    settings,
+                                )
+                            });
 
-/// Helper para cargar imagen desde bytes y actualizar estado
-fn load_image_logic(bytes: Vec<u8>) -> Result<(Arc<DynamicImage>, usize, u32, u32), WindooshError> {
-    let file_size = bytes.len();
-    let img = ImageReader::new(Cursor::new(&bytes))
-        .with_guessed_format()
-        .map_err(|e| WindooshError::ImageDecode(e.to_string()))?
-        .decode()
-        .map_err(|e| WindooshError::ImageDecode(e.to_string()))?;
+                            let done = completed.fetch_add(1, Ordering::SeqCst) + 1;
+                            let _ = app.emit(
+                                "batch-progress",
+                                BatchProgress {
+                                    completed: done,
+                                    total,
+                                    path: path.clone(),
+                                },
+                            );
 
-    let width = img.width();
-    let height = img.height();
+                            items.push((index, item));
+                        }
+                        items
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .map(|worker| {
+                    worker.join().map_err(|_| {
+                        WindooshError::Concurrency("Un worker del lote falló".to_string())
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+        // Mismo orden que `paths`
+        indexed.sort_by_key(|(index, _)| *index);
+        let results: Vec<_> = indexed.into_iter().map(|(_, item)| item).collect();
+
+        Ok::<_, WindooshError>(results)
+    })
+    .await
+    .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+    .map_err(String::from)?;
 
-    Ok((Arc::new(img), file_size, width, height))
+    Ok(results)
 }
 
-/// Carga una imagen desde disco de forma asíncrona
-/// NO devuelve preview - el frontend debe llamar a get_original_image_data
+/// Calcula PSNR y SSIM entre la imagen original y la procesada
+/// Si las dimensiones difieren (resize/crop), la procesada se reescala al tamaño original
 #[tauri::command]
-async fn load_image(path: String, state: State<'_, AppState>) -> Result<ImageInfo, String> {
-    let path_for_load = path.clone();
+async fn compute_quality_metrics(
+    doc_id: String,
+    state: State<'_, AppState>,
+) -> Result<QualityMetrics, String> {
+    let (original, processed) = state.with_document(&doc_id, |doc| {
+        let processed = doc.processed_image.clone().ok_or(WindooshError::NoImage)?;
+        Ok((Arc::clone(&doc.original_image), processed))
+    })?;
 
-    // Ejecutar I/O y decode en thread pool
-    let (img_arc, file_size, width, height) = tauri::async_runtime::spawn_blocking(move || {
-        let file_bytes =
-            std::fs::read(&path_for_load).map_err(|e| WindooshError::FileRead(e.to_string()))?;
+    let metrics = tauri::async_runtime::spawn_blocking(move || {
+        let resized = if processed.width() != original.width()
+            || processed.height() != original.height()
+        {
+            Some(resize_exact_simd(
+                &processed,
+                original.width(),
+                original.height(),
+                "Lanczos3",
+            )?)
+        } else {
+            None
+        };
+        let processed: &DynamicImage = resized.as_ref().unwrap_or(&processed);
 
-        load_image_logic(file_bytes)
+        let (a, b) = (original.to_rgba8(), processed.to_rgba8());
+        Ok::<_, WindooshError>(QualityMetrics {
+            psnr: compute_psnr(&a, &b),
+            ssim: compute_ssim(&a, &b),
+        })
     })
     .await
     .map_err(|e| WindooshError::Concurrency(e.to_string()))?
     .map_err(String::from)?;
 
-    // Guardar en estado (Arc::clone es O(1))
-    {
-        *state.original_image.write() = Some(Arc::clone(&img_arc));
-        *state.original_size.write() = file_size;
-        *state.original_path.write() = Some(path.clone());
-        *state.processed_image.write() = None; // Reset processed
-    }
+    Ok(metrics)
+}
 
-    let display_name = std::path::Path::new(&path)
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("image")
-        .to_string();
+/// Genera el mapa de diferencias entre original y procesada para ver artefactos
+/// Si la procesada cambió de tamaño se reescala al original con vecino más cercano
+#[tauri::command]
+async fn compute_diff_image(
+    doc_id: String,
+    amplification: Option<f32>,
+    state: State<'_, AppState>,
+) -> Result<ImageDataRaw, String> {
+    let (original, processed) = state.with_document(&doc_id, |doc| {
+        let processed = doc.processed_image.clone().ok_or(WindooshError::NoImage)?;
+        Ok((Arc::clone(&doc.original_image), processed))
+    })?;
+    let amplification = amplification.unwrap_or(DEFAULT_DIFF_AMPLIFICATION).max(0.0);
 
-    Ok(ImageInfo {
-        width,
-        height,
-        original_size: file_size,
-        name: display_name,
+    let diff = tauri::async_runtime::spawn_blocking(move || {
+        // Nearest: no suaviza bloques, así los artefactos siguen visibles
+        let resized = if processed.width() != original.width()
+            || processed.height() != original.height()
+        {
+            Some(resize_exact_simd(
+                &processed,
+                original.width(),
+                original.height(),
+                "Nearest",
+            )?)
+        } else {
+            None
+        };
+        let processed: &DynamicImage = resized.as_ref().unwrap_or(&processed);
+
+        Ok::<_, WindooshError>(build_diff_image(
+            &original.to_rgba8(),
+            &processed.to_rgba8(),
+            amplification,
+        ))
     })
+    .await
+    .map_err(|e| WindooshError::Concurrency(e.to_string()))?
+    .map_err(String::from)?;
+
+    Ok(diff)
 }
 
-/// Carga una imagen desde memoria (bytes) - Usado para Clipboard paste
+/// Histograma de la imagen procesada (o de la original si aún no se procesó)
 #[tauri::command]
-async fn load_image_from_bytes(
-    bytes: Vec<u8>,
+async fn get_histogram(
+    doc_id: String,
     state: State<'_, AppState>,
-) -> Result<ImageInfo, String> {
-    let (img_arc, file_size, width, height) =
-        tauri::async_runtime::spawn_blocking(move || load_image_logic(bytes))
-            .await
-            .map_err(|e| WindooshError::Concurrency(e.to_string()))?
-            .map_err(String::from)?;
+) -> Result<HistogramData, String> {
+    let img_arc = state.with_document(&doc_id, |doc| {
+        Ok(doc
+            .processed_image
+            .clone()
+            .unwrap_or_else(|| Arc::clone(&doc.original_image)))
+    })?;
 
-    {
-        *state.original_image.write() = Some(Arc::clone(&img_arc));
-        *state.original_size.write() = file_size;
-        *state.original_path.write() = None; // No path for clipboard images
-        *state.processed_image.write() = None;
-    }
+    let histogram = tauri::async_runtime::spawn_blocking(move || compute_histogram(&img_arc))
+        .await
+        .map_err(|e| WindooshError::Concurrency(e.to_string()))?;
 
-    Ok(ImageInfo {
-        width,
-        height,
-        original_size: file_size,
-        name: "Clipboard Image".to_string(),
+    Ok(histogram)
+}
+
+/// Colores distintos del original (≤256 sugiere PNG con paleta)
+#[tauri::command]
+async fn count_unique_colors(
+    doc_id: String,
+    state: State<'_, AppState>,
+) -> Result<UniqueColors, String> {
+    let img_arc = state.with_document(&doc_id, |doc| Ok(Arc::clone(&doc.original_image)))?;
+
+    let colors = tauri::async_runtime::spawn_blocking(move || {
+        count_unique_colors_capped(&rgba_pixels(&img_arc), UNIQUE_COLORS_CAP)
     })
+    .await
+    .map_err(|e| WindooshError::Concurrency(e.to_string()))?;
+
+    Ok(colors)
 }
 
-/// Obtiene los datos raw RGBA de la imagen original para canvas
-/// Esta función permite zoom sin pérdida de calidad
+/// Encoders recomendados para el original, del más al menos adecuado
+/// Solo incluye los registrados (p. ej. jxl requiere la feature)
 #[tauri::command]
-async fn get_original_image_data(state: State<'_, AppState>) -> Result<ImageDataRaw, String> {
-    let img_arc = {
-        let guard = state.original_image.read();
-        guard
-            .as_ref()
-            .ok_or_else(|| WindooshError::NoImage)?
-            .clone()
-    };
+async fn suggest_encoder(
+    doc_id: String,
+    state: State<'_, AppState>,
+) -> Result<Vec<EncoderSuggestion>, String> {
+    let img_arc = state.with_document(&doc_id, |doc| Ok(Arc::clone(&doc.original_image)))?;
 
-    // Extraer RGBA en thread pool (puede ser pesado para imágenes 4K+)
-    let result = tauri::async_runtime::spawn_blocking(move || extract_rgba_data(&img_arc))
+    let ranked = tauri::async_runtime::spawn_blocking(move || rank_encoders(&img_arc))
         .await
         .map_err(|e| WindooshError::Concurrency(e.to_string()))?;
 
-    Ok(result)
+    Ok(ranked
+        .into_iter()
+        .filter(|(name, _)| state.encoders.create(name).is_ok())
+        .map(|(name, reason)| EncoderSuggestion {
+            encoder: name.to_string(),
+            reason: reason.to_string(),
+        })
+        .collect())
 }
 
-/// Obtiene los datos raw RGBA de la imagen procesada para canvas
+/// Color RGB que se usaría para aplanar el original si no se indica flatten_background
+/// (dominante en el borde, o blanco si el borde es transparente)
 #[tauri::command]
-async fn get_processed_image_data(state: State<'_, AppState>) -> Result<ImageDataRaw, String> {
-    let img_arc = {
-        let guard = state.processed_image.read();
-        guard
-            .as_ref()
-            .ok_or_else(|| WindooshError::NoImage)?
-            .clone()
-    };
+async fn detect_background_color(
+    doc_id: String,
+    state: State<'_, AppState>,
+) -> Result<[u8; 3], String> {
+    let img_arc = state.with_document(&doc_id, |doc| Ok(Arc::clone(&doc.original_image)))?;
 
-    let result = tauri::async_runtime::spawn_blocking(move || extract_rgba_data(&img_arc))
+    let color = tauri::async_runtime::spawn_blocking(move || border_background_color(&img_arc))
         .await
         .map_err(|e| WindooshError::Concurrency(e.to_string()))?;
 
-    Ok(result)
+    Ok(color)
 }
 
-/// Procesa la imagen con las opciones dadas
-/// Almacena la imagen procesada internamente para get_processed_image_data
+/// Vuelve al resultado anterior del historial y lo devuelve para el canvas
 #[tauri::command]
-async fn process_image(
-    request: OptimizationRequest,
-    state: State<'_, AppState>,
-) -> Result<OptimizationResult, String> {
-    // Obtener Arc sin clonar bytes subyacentes
-    let img_arc = {
-        let guard = state.original_image.read();
-        guard
-            .as_ref()
-            .ok_or_else(|| WindooshError::NoImage)?
-            .clone() // Arc::clone = O(1)
-    };
-    let original_size = *state.original_size.read();
+async fn undo(doc_id: String, state: State<'_, AppState>) -> Result<Arc<ImageDataRaw>, String> {
+    state.with_document_mut(&doc_id, |doc| {
+        let img = doc
+            .history
+            .undo()
+            .ok_or_else(|| WindooshError::Processing("No hay operaciones para deshacer".into()))?;
+        doc.show_processed(img);
+        // Un process_image todavía en curso ya no debe reemplazar lo que se acaba de mostrar
+        doc.process_version = state.next_process_version();
+        Ok(())
+    })?;
 
-    // Procesar en thread pool
-    let (result, processed_img) =
-        tauri::async_runtime::spawn_blocking(move || process_pipeline(&img_arc, &request))
-            .await
-            .map_err(|e| WindooshError::Concurrency(e.to_string()))?
-            .map_err(String::from)?;
+    Ok(state.document_rgba(&doc_id, true).await?)
+}
 
-    let optimized_size = result.data.len();
-    let savings_percent = if original_size > 0 {
-        ((original_size as f32 - optimized_size as f32) / original_size as f32) * 100.0
+/// Rehace el siguiente resultado del historial y lo devuelve para el canvas
+#[tauri::command]
+async fn redo(doc_id: String, state: State<'_, AppState>) -> Result<Arc<ImageDataRaw>, String> {
+    state.with_document_mut(&doc_id, |doc| {
+        let img = doc
+            .history
+            .redo()
+            .ok_or_else(|| WindooshError::Processing("No hay operaciones para rehacer".into()))?;
+        doc.show_processed(img);
+        // Un process_image todavía en curso ya no debe reemplazar lo que se acaba de mostrar
+        doc.process_version = state.next_process_version();
+        Ok(())
+    })?;
+
+    Ok(state.document_rgba(&doc_id, true).await?)
+}
+
+/// Cambia la profundidad máxima del historial (mínimo 1)
+/// Aplica a los documentos abiertos y a los que se abran después
+#[tauri::command]
+fn set_history_depth(depth: usize, state: State<'_, AppState>) {
+    *state.history_depth.write() = depth;
+    for doc in state.documents.write().values_mut() {
+        doc.history.set_max_depth(depth);
+    }
+}
+
+/// Cambia el máximo de píxeles aceptados al cargar (aplica a las cargas siguientes)
+#[tauri::command]
+fn set_max_pixels(max_pixels: u64, state: State<'_, AppState>) -> Result<(), String> {
+    if max_pixels == 0 {
+        return Err("El límite de píxeles debe ser mayor que 0".to_string());
+    }
+    *state.max_pixels.write() = max_pixels;
+    Ok(())
+}
+
+/// Activa o desactiva la corrección de orientación EXIF (aplica a las cargas siguientes)
+/// Desactivada, ImageInfo.exif_orientation trae el tag para rotar a mano
+#[tauri::command]
+fn set_auto_orient(enabled: bool, state: State<'_, AppState>) {
+    *state.auto_orient.write() = enabled;
+}
+
+/// Limita la memoria retenida en imágenes y cachés (0 = sin límite)
+/// Cargas y procesados que no quepan fallan con "out of memory budget" en lugar de abortar
+#[tauri::command]
+fn set_memory_budget(bytes: u64, state: State<'_, AppState>) {
+    *state.memory_budget.write() = if bytes == 0 {
+        UNLIMITED_MEMORY_BUDGET
     } else {
-        0.0
+        bytes
     };
+}
 
-    // Guardar metadata y imagen procesada
-    {
-        *state.processed_image.write() = Some(Arc::new(processed_img));
-        *state.last_optimization.write() = Some(OptimizationMetadata {
-            optimized_size,
-            savings_percent,
-            mime_type: result.mime_type.clone(),
-            extension: result.extension.clone(),
-        });
-    }
+/// Hilos para procesar y codificar (0 = paralelismo disponible, el valor por defecto)
+/// process_batch los reparte entre los archivos que procesa a la vez
+#[tauri::command]
+fn set_thread_count(n: usize, state: State<'_, AppState>) -> Result<(), String> {
+    let threads = if n == 0 { default_thread_count() } else { n };
+    *state.worker_pool.write() = WorkerPool::new(threads)?;
+    Ok(())
+}
 
-    Ok(OptimizationResult {
-        optimized_size,
-        savings_percent,
-        mime_type: result.mime_type,
-        extension: result.extension,
-    })
+/// Memoria retenida ahora mismo por documentos y caché
+#[tauri::command]
+fn get_memory_usage(state: State<'_, AppState>) -> MemoryUsage {
+    state.memory_usage()
 }
 
-/// Guarda la imagen optimizada en disco
+/// Pide cancelar el procesamiento en curso del documento
+/// El pipeline se detiene al empezar la siguiente etapa (una etapa en marcha no se interrumpe)
 #[tauri::command]
-async fn save_image(
-    path: String,
+fn cancel_processing(doc_id: String, state: State<'_, AppState>) -> Result<(), String> {
+    state.with_document(&doc_id, |doc| {
+        doc.cancel_flag.store(true, Ordering::SeqCst);
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Schema de opciones del encoder, para generar los controles de la UI
+#[tauri::command]
+fn get_encoder_schema(encoder_name: String, state: State<'_, AppState>) -> Result<Value, String> {
+    Ok(state.encoders.create(&encoder_name)?.options_schema())
+}
+
+/// Lista los encoders disponibles con sus formatos soportados
+#[tauri::command]
+fn list_encoders(state: State<'_, AppState>) -> Vec<EncoderInfo> {
+    state
+        .encoders
+        .encoders()
+        .into_iter()
+        .map(|encoder| {
+            EncoderInfo {
+                name: encoder.name().to_string(),
+                formats: encoder.supported_formats().into_iter().map(String::from).collect(),
+            }
+        })
+        .collect()
+}
+
+/// Vacía la caché de resultados (para liberar memoria)
+#[tauri::command]
+fn clear_cache(state: State<'_, AppState>) {
+    state.result_cache.lock().clear();
+}
+
+/// Presets de optimización por nombre (BTreeMap: list_presets sale ordenado)
+type PresetMap = BTreeMap<String, OptimizationRequest>;
+
+/// Archivo de presets en el directorio de configuración de la app
+fn presets_path(app: &tauri::AppHandle) -> Result<std::path::PathBuf, WindooshError> {
+    let dir = app.path().app_config_dir().map_err(|e| {
+        WindooshError::FileRead(format!("Directorio de configuración no disponible: {}", e))
+    })?;
+    Ok(dir.join("presets.json"))
+}
+
+/// Lee los presets; si el archivo aún no existe no hay ninguno
+fn read_presets(path: &std::path::Path) -> Result<PresetMap, WindooshError> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(PresetMap::new()),
+        Err(e) => return Err(WindooshError::FileRead(e.to_string())),
+    };
+    serde_json::from_slice(&bytes)
+        .map_err(|e| WindooshError::FileRead(format!("presets.json inválido: {}", e)))
+}
+
+fn write_presets(path: &std::path::Path, presets: &PresetMap) -> Result<(), WindooshError> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| WindooshError::FileRead(format!("Error creando directorio: {}", e)))?;
+    }
+    let json = serde_json::to_vec_pretty(presets)
+        .map_err(|e| WindooshError::Processing(e.to_string()))?;
+    std::fs::write(path, json)
+        .map_err(|e| WindooshError::FileRead(format!("Error al guardar: {}", e)))
+}
+
+/// Guarda (o reemplaza) un preset con el request completo: encoder, opciones, resize, quantize...
+#[tauri::command]
+async fn save_preset(
+    app: tauri::AppHandle,
+    name: String,
     request: OptimizationRequest,
     state: State<'_, AppState>,
-) -> Result<SaveResult, String> {
-    let img_arc = {
-        let guard = state.original_image.read();
-        guard
-            .as_ref()
-            .ok_or_else(|| WindooshError::NoImage)?
-            .clone()
-    };
+) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("El nombre del preset no puede estar vacío".to_string());
+    }
+    let path = presets_path(&app)?;
+    let _guard = state.presets_lock.lock();
+    let mut presets = read_presets(&path)?;
+    presets.insert(name, request);
+    Ok(write_presets(&path, &presets)?)
+}
+
+/// Request guardado con ese nombre
+#[tauri::command]
+async fn load_preset(
+    app: tauri::AppHandle,
+    name: String,
+    state: State<'_, AppState>,
+) -> Result<OptimizationRequest, String> {
+    let path = presets_path(&app)?;
+    let _guard = state.presets_lock.lock();
+    read_presets(&path)?
+        .remove(&name)
+        .ok_or_else(|| format!("Preset no encontrado: {}", name))
+}
 
-    let path_for_save = path.clone();
+/// Nombres de los presets guardados, en orden alfabético
+#[tauri::command]
+async fn list_presets(
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Vec<String>, String> {
+    let path = presets_path(&app)?;
+    let _guard = state.presets_lock.lock();
+    Ok(read_presets(&path)?.into_keys().collect())
+}
 
-    let final_size = tauri::async_runtime::spawn_blocking(move || {
-        let (result, _) = process_pipeline(&img_arc, &request)?;
-        std::fs::write(&path_for_save, &result.data)
-            .map_err(|e| WindooshError::FileRead(format!("Error al guardar: {}", e)))?;
-        Ok::<_, WindooshError>(result.data.len())
-    })
-    .await
-    .map_err(|e| WindooshError::Concurrency(e.to_string()))?
-    .map_err(String::from)?;
+#[tauri::command]
+async fn delete_preset(
+    app: tauri::AppHandle,
+    name: String,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let path = presets_path(&app)?;
+    let _guard = state.presets_lock.lock();
+    let mut presets = read_presets(&path)?;
+    if presets.remove(&name).is_none() {
+        return Err(format!("Preset no encontrado: {}", name));
+    }
+    Ok(write_presets(&path, &presets)?)
+}
 
-    Ok(SaveResult { path, final_size })
+/// Propone la ruta de guardado según la extensión del último resultado codificado
+/// None si el documento no tiene archivo de origen o aún no se ha optimizado
+#[tauri::command]
+fn suggest_save_path(
+    doc_id: String,
+    state: State<'_, AppState>,
+) -> Result<Option<String>, String> {
+    let suggestion = state.with_document(&doc_id, |doc| {
+        Ok(match (&doc.original_path, &doc.last_optimization) {
+            (Some(original), Some(metadata)) => Some(default_save_path(original, &metadata.extension)),
+            _ => None,
+        })
+    })?;
+    Ok(suggestion)
 }
 
-/// Obtiene la metadata de la última optimización
+/// Obtiene la metadata de la última optimización del documento
 #[tauri::command]
-fn get_optimization_metadata(state: State<AppState>) -> Option<OptimizationMetadata> {
-    state.last_optimization.read().clone()
+fn get_optimization_metadata(
+    doc_id: String,
+    state: State<'_, AppState>,
+) -> Result<Option<OptimizationMetadata>, String> {
+    let metadata = state.with_document(&doc_id, |doc| Ok(doc.last_optimization.clone()))?;
+    Ok(metadata)
 }
 
 // ============================================================================
@@ -733,6 +5579,13 @@ pub fn run() {
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_fs::init())
         .manage(AppState::default())
+        .register_asynchronous_uri_scheme_protocol(IMAGE_PROTOCOL, |ctx, request, responder| {
+            let app = ctx.app_handle().clone();
+            let path = request.uri().path().to_string();
+            tauri::async_runtime::spawn(async move {
+                responder.respond(serve_image_protocol(&app, &path).await);
+            });
+        })
         .setup(|app| {
             let args: Vec<String> = std::env::args().collect();
 
@@ -743,9 +5596,22 @@ pub fn run() {
                 let args_clone = args.clone();
 
                 std::thread::spawn(move || {
+                    // stdin se lee y decodifica mientras arranca el frontend
+                    let stdin_document = (arg1 == "-").then(|| open_stdin_image(&handle));
+
                     std::thread::sleep(std::time::Duration::from_millis(800));
 
-                    if arg1 == "--preset" && args_clone.len() > 3 {
+                    if let Some(opened) = stdin_document {
+                        // usage: cat photo.jpg | windoosh -
+                        match opened {
+                            Ok(info) => {
+                                let _ = handle.emit("open-document-from-stdin", info);
+                            }
+                            Err(e) => {
+                                let _ = handle.emit("open-document-from-stdin-error", e.to_string());
+                            }
+                        }
+                    } else if arg1 == "--preset" && args_clone.len() > 3 {
                         // usage: windoosh --preset <id> <file>
                         let preset_id = args_clone[2].clone();
                         let file_path = args_clone[3].clone();
@@ -765,11 +5631,52 @@ pub fn run() {
         .invoke_handler(tauri::generate_handler![
             load_image,
             load_image_from_bytes,
+            load_image_from_clipboard,
+            detect_format,
+            copy_processed_to_clipboard,
+            close_document,
             process_image,
+            process_image_async_event,
+            process_region,
+            preview_quantize,
+            optimize_to_target,
+            estimate_size,
+            compare_all_codecs,
+            process_animated,
             save_image,
+            get_encoded_bytes,
+            process_batch,
+            generate_thumbnail,
+            cancel_processing,
+            clear_cache,
+            compute_quality_metrics,
+            get_histogram,
+            count_unique_colors,
+            suggest_encoder,
+            detect_background_color,
+            compute_diff_image,
+            undo,
+            redo,
+            set_history_depth,
+            set_max_pixels,
+            set_auto_orient,
+            set_memory_budget,
+            set_thread_count,
+            get_memory_usage,
+            suggest_save_path,
             get_optimization_metadata,
+            save_preset,
+            load_preset,
+            list_presets,
+            delete_preset,
             get_original_image_data,
+            get_content_hash,
+            get_exif,
             get_processed_image_data,
+            get_preencode_image_data,
+            get_image_data_chunk,
+            get_encoder_schema,
+            list_encoders,
             toggle_context_menu,
             get_context_menu_state,
             update_context_menu_items
@@ -777,3 +5684,316 @@ pub fn run() {
         .run(tauri::generate_context!())
         .expect("Error al ejecutar la aplicación Tauri");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn resize_options(value: Value) -> ResizeOptionsDto {
+        serde_json::from_value(value).expect("ResizeOptionsDto válido")
+    }
+
+    #[test]
+    fn max_dimension_caps_the_longest_side() {
+        let opts = resize_options(json!({ "width": 0, "height": 0, "max_dimension": 2048 }));
+
+        let landscape = resolve_resize_options(4000, 3000, &opts).expect("debe reducir");
+        assert_eq!((landscape.width, landscape.height), (2048, 1536));
+
+        let portrait = resolve_resize_options(3000, 4000, &opts).expect("debe reducir");
+        assert_eq!((portrait.width, portrait.height), (1536, 2048));
+
+        assert!(resolve_resize_options(2048, 1024, &opts).is_none());
+    }
+
+    fn load(bytes: Vec<u8>) -> LoadedImage {
+        let settings = LoadSettings {
+            max_pixels: u64::MAX,
+            auto_orient: true,
+        };
+        load_image_logic(bytes, settings).expect("imagen válida")
+    }
+
+    fn encode_png(image: &DynamicImage) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .expect("PNG");
+        bytes
+    }
+
+    #[test]
+    fn sixteen_bit_png_is_reported_and_reduced_to_8_bit() {
+        let source = image::ImageBuffer::from_fn(4, 4, |x, y| {
+            image::Rgb([x as u16 * 16_000, y as u16 * 20_000 + 300, 65_535])
+        });
+        let loaded = load(encode_png(&DynamicImage::ImageRgb16(source)));
+
+        assert_eq!(loaded.original_color_type, ExtendedColorType::Rgb16);
+        let rgb = loaded.image.as_rgb8().expect("el pipeline trabaja en 8 bits");
+        // 16 → 8 bits redondeando (v / 257)
+        assert_eq!(rgb.get_pixel(3, 2).0, [187, 157, 255]);
+        assert!(loaded.high_depth_source.is_some());
+    }
+
+    #[test]
+    fn sprite_resize_has_no_dark_fringe() {
+        // Sprite blanco opaco sobre fondo transparente negro: sin premultiplicar, el borde se oscurece
+        let sprite = RgbaImage::from_fn(64, 64, |x, y| {
+            if (16..48).contains(&x) && (16..48).contains(&y) {
+                image::Rgba([255, 255, 255, 255])
+            } else {
+                image::Rgba([0, 0, 0, 0])
+            }
+        });
+        let resized = resize_exact_simd(&DynamicImage::ImageRgba8(sprite), 27, 27, "Lanczos3")
+            .expect("resize")
+            .to_rgba8();
+
+        let edge = resized.pixels().filter(|p| p[3] > 0 && p[3] < 255).count();
+        assert!(edge > 0, "el borde debe quedar semitransparente");
+        for pixel in resized.pixels().filter(|p| p[3] >= 8) {
+            assert!(pixel[0] >= 240 && pixel[1] >= 240 && pixel[2] >= 240, "halo oscuro: {:?}", pixel);
+        }
+    }
+
+    /// Degradado con ruido pseudoaleatorio de semilla fija (LCG): misma imagen en cada ejecución
+    fn seeded_noise(width: u32, height: u32, seed: u32) -> RgbaImage {
+        let mut state = seed;
+        RgbaImage::from_fn(width, height, |x, y| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let noise = (state >> 24) as u8 / 8;
+            image::Rgba([
+                (x * 200 / width) as u8 + noise,
+                (y * 200 / height) as u8 + noise,
+                noise * 3,
+                255,
+            ])
+        })
+    }
+
+    #[test]
+    fn parallel_quantize_matches_serial() {
+        let image = DynamicImage::ImageRgba8(seeded_noise(192, 160, 42));
+        let opts = QuantizeOptionsDto {
+            num_colors: 64,
+            dither: 1.0,
+            dither_algorithm: String::new(),
+            fixed_palette: None,
+            speed: Some(4),
+            min_quality: None,
+            max_quality: None,
+        };
+        let run = |threads: usize| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .expect("pool")
+                .install(|| apply_quantize(image.clone(), &opts))
+                .expect("quantize")
+                .into_bytes()
+        };
+
+        assert_eq!(run(1), run(8));
+    }
+
+    fn request(value: Value) -> OptimizationRequest {
+        serde_json::from_value(value).expect("OptimizationRequest válido")
+    }
+
+    fn run_pipeline(image: DynamicImage, request: &OptimizationRequest) -> EncodingResult {
+        let encoder = EncoderRegistry::default()
+            .create(&request.encoder_name)
+            .expect("encoder");
+        let (result, _, _) = process_pipeline(
+            &Arc::new(image),
+            request,
+            encoder.as_ref(),
+            &ImageMetadata::default(),
+            &PipelineControl::default(),
+        )
+        .expect("pipeline");
+        result
+    }
+
+    #[test]
+    fn grayscale_output_is_single_channel_and_smaller() {
+        let image = DynamicImage::ImageRgba8(seeded_noise(128, 128, 7));
+        let color = request(json!({ "encoder_name": "oxipng", "options": {} }));
+        let gray = request(json!({ "encoder_name": "oxipng", "options": {}, "grayscale": true }));
+        let color = run_pipeline(image.clone(), &color);
+        let gray = run_pipeline(image, &gray);
+
+        assert_eq!(gray.mime_type, color.mime_type);
+        let decoded = image::load_from_memory(&gray.data).expect("PNG válido");
+        assert_eq!(decoded.color().channel_count(), 1);
+        assert!(
+            gray.data.len() * 4 < color.data.len() * 3,
+            "gris = {} bytes, color = {} bytes",
+            gray.data.len(),
+            color.data.len()
+        );
+    }
+
+    #[test]
+    fn mislabeled_png_is_detected_by_content() {
+        let png = encode_png(&DynamicImage::ImageRgba8(seeded_noise(8, 8, 1)));
+        let path = std::env::temp_dir().join(format!("windoosh-{}.jpg", Uuid::new_v4()));
+        std::fs::write(&path, &png).expect("escribir temporal");
+        let bytes = std::fs::read(&path).expect("leer temporal");
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(sniff_format(&bytes).as_deref(), Some("PNG"));
+        let info = AppState::default().open_document(
+            load(bytes),
+            Some(path.to_string_lossy().into_owned()),
+            "foto.jpg".to_string(),
+        );
+        assert_eq!(info.detected_format.as_deref(), Some("PNG"));
+    }
+
+    #[test]
+    fn older_job_cannot_replace_processed_image() {
+        let state = AppState::default();
+        let png = encode_png(&DynamicImage::ImageRgba8(seeded_noise(8, 8, 2)));
+        let info = state.open_document(load(png), None, "a.png".to_string());
+        let original = |doc: &Document| Ok(Arc::clone(&doc.original_image));
+        let show = |image: Arc<DynamicImage>| {
+            move |doc: &mut Document| {
+                doc.show_processed(ProcessedImages {
+                    preencode: Arc::clone(&image),
+                    preview: image,
+                })
+            }
+        };
+
+        let (old, image) = state.begin_processing(&info.id, original).expect("documento");
+        let (new, _) = state.begin_processing(&info.id, original).expect("documento");
+        assert!(matches!(
+            state.commit_processed(&info.id, old, show(Arc::clone(&image))),
+            Err(WindooshError::Superseded)
+        ));
+        state.commit_processed(&info.id, new, show(image)).expect("versión vigente");
+    }
+
+    #[test]
+    fn explicit_flatten_background_alters_pixels() {
+        let base = json!({ "encoder_name": "oxipng", "options": {} });
+        assert!(!request(base.clone()).alters_pixels());
+
+        let mut flattened = base;
+        flattened["flatten_background"] = json!([255, 255, 255]);
+        assert!(request(flattened).alters_pixels());
+    }
+
+    /// Bloque EXIF (TIFF) con Make, DateTimeOriginal y GPSLatitude
+    fn sample_exif() -> Vec<u8> {
+        use exif::{Field, In, Rational, Tag, Value};
+
+        let fields = [
+            Field {
+                tag: Tag::Make,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"Windoosh Camera".to_vec()]),
+            },
+            Field {
+                tag: Tag::DateTimeOriginal,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"2024:05:01 12:34:56".to_vec()]),
+            },
+            Field {
+                tag: Tag::GPSLatitude,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![
+                    Rational { num: 40, denom: 1 },
+                    Rational { num: 25, denom: 1 },
+                    Rational { num: 0, denom: 1 },
+                ]),
+            },
+        ];
+        let mut writer = exif::experimental::Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut output = Cursor::new(Vec::new());
+        writer.write(&mut output, false).expect("EXIF");
+        output.into_inner()
+    }
+
+    #[test]
+    fn metadata_policy_keeps_only_selected_categories() {
+        let policy = ["datetime".to_string(), "camera".to_string()];
+        let filtered = filter_exif(Some(&sample_exif()), &policy)
+            .expect("política válida")
+            .expect("quedan tags");
+
+        let exif = exif::Reader::new().read_raw(filtered).expect("EXIF válido");
+        assert!(exif.get_field(exif::Tag::Make, exif::In::PRIMARY).is_some());
+        assert!(exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY).is_some());
+        assert!(exif.fields().all(|f| f.tag.context() != exif::Context::Gps));
+
+        // El original sí tenía GPS
+        let gps = filter_exif(Some(&sample_exif()), &["gps".to_string()]).expect("política válida");
+        assert!(gps.is_some());
+    }
+
+    #[test]
+    fn kept_original_never_reintroduces_stripped_metadata() {
+        // JPEG con un segmento APP1 EXIF (incluye GPS) justo después de SOI
+        let mut plain = Vec::new();
+        DynamicImage::ImageRgba8(seeded_noise(64, 64, 3))
+            .to_rgb8()
+            .write_to(&mut Cursor::new(&mut plain), ImageFormat::Jpeg)
+            .expect("JPEG");
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend(sample_exif());
+        let mut original = plain[..2].to_vec();
+        original.extend([0xFF, 0xE1]);
+        original.extend(((app1.len() + 2) as u16).to_be_bytes());
+        original.extend(&app1);
+        original.extend(&plain[2..]);
+
+        let path = std::env::temp_dir().join(format!("windoosh-{}.jpg", Uuid::new_v4()));
+        std::fs::write(&path, &original).expect("escribir temporal");
+        let state = AppState::default();
+        let path_str = path.to_string_lossy().into_owned();
+        let info = state.open_document(
+            load(original.clone()),
+            Some(path_str.clone()),
+            "foto.jpg".to_string(),
+        );
+        let job = state
+            .with_document(&info.id, |doc| Ok(doc.pipeline_job()))
+            .expect("documento");
+        // Re-codificación (ficticia) mucho mayor que el original
+        let result = EncodingResult {
+            data: vec![0; original.len() * 4],
+            mime_type: "image/jpeg".to_string(),
+            extension: "jpg".to_string(),
+        };
+        let fallback = |options: Value| {
+            let request = request(json!({ "encoder_name": "mozjpeg", "options": options }));
+            original_if_smaller(&job, &request, &result, Some(&path_str), Some(ImageFormat::Jpeg))
+                .expect("el original ocupa menos")
+        };
+
+        // strip_metadata por defecto: el original se sustituye ya sin EXIF
+        let stripped = fallback(json!({}));
+        assert!(stripped.len() < original.len());
+        assert!(!stripped.windows(6).any(|w| w == b"Exif\0\0"));
+        assert!(image::load_from_memory(&stripped).is_ok());
+
+        // Conservar metadatos sin filtrar: el original tal cual
+        let kept = fallback(json!({ "strip_metadata": false }));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(kept, original);
+    }
+
+    #[test]
+    fn metadata_policy_rejects_unknown_category() {
+        let policy = ["bogus".to_string()];
+        assert!(filter_exif(Some(&sample_exif()), &policy).is_err());
+        assert!(filter_exif(None, &policy).is_err());
+    }
+}
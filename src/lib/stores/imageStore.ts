@@ -9,10 +9,35 @@ import { writable, derived, get } from "svelte/store";
  * Se obtiene al cargar la imagen
  */
 export interface ImageInfo {
+  /** Id del documento en el backend (docId en los comandos) */
+  id: string;
   width: number;
   height: number;
   original_size: number;
   name: string;
+  /** true si se corrigió la orientación según EXIF */
+  orientation_corrected: boolean;
+  /** Tag Orientation del EXIF (1-8) sin aplicar (set_auto_orient(false)); null si se aplicó o no hay */
+  exif_orientation: number | null;
+  /** Tipo de color del archivo ("Rgb8", "Rgba16", "Cmyk8"...); 16 bits y CMYK pierden precisión */
+  original_color_type: string;
+  /** Entradas de la paleta si el original es un PNG indexado (0 si no lo es) */
+  palette_colors: number;
+  /** Formato real según el contenido ("PNG", "JPEG"...); null si no vino de un archivo */
+  detected_format: string | null;
+  /** Tiene canal alpha (aunque sea totalmente opaco) */
+  has_alpha: boolean;
+  /** Algún píxel es transparente: JPEG perdería la transparencia */
+  uses_transparency: boolean;
+  /** Hash de los píxeles (hex): mismos píxeles = mismo hash aunque cambien formato o metadatos */
+  content_hash: string;
+  /** GIF/WebP/APNG con varios frames (solo se carga el primero) */
+  is_animated: boolean;
+  frame_count: number;
+  /** ms leyendo el archivo de disco (0 si no vino de un archivo) */
+  read_ms: number;
+  /** ms decodificando */
+  decode_ms: number;
 }
 
 /**
@@ -32,13 +57,42 @@ export interface ImageDataRaw {
 export interface OptimizationResult {
   optimized_size: number;
   savings_percent: number;
+  /** El resultado ocupa más que el original (savings_percent negativo) */
+  size_increased: boolean;
   mime_type: string;
   extension: string;
+  /** Duración de cada etapa en ms (0 si no se ejecutó) */
+  resize_ms: number;
+  quantize_ms: number;
+  encode_ms: number;
 }
 
 export interface OptimizationMetadata {
   optimized_size: number;
   savings_percent: number;
+  /** El resultado ocupa más que el original (savings_percent negativo) */
+  size_increased: boolean;
+  mime_type: string;
+  extension: string;
+  /** Duración de cada etapa en ms (0 si no se ejecutó) */
+  resize_ms: number;
+  quantize_ms: number;
+  encode_ms: number;
+}
+
+/** Resultado de estimate_size (proxy reducido extrapolado) */
+export interface SizeEstimate {
+  estimated_size: number;
+  savings_percent: number;
+  /** false si la extrapolación es poco fiable; las salidas pequeñas son exactas */
+  confident: boolean;
+}
+
+/** Resultado de process_animated (animación re-codificada en WebP/GIF) */
+export interface AnimatedResult {
+  frame_count: number;
+  total_size: number;
+  savings_percent: number;
   mime_type: string;
   extension: string;
 }
@@ -46,19 +100,87 @@ export interface OptimizationMetadata {
 export interface ResizeOptions {
   width: number;
   height: number;
-  filter: string;
+  /** Filtro explícito (Lanczos3, CatmullRom...); tiene prioridad sobre resize_quality */
+  filter?: string;
+  /** "fast" | "balanced" | "best" */
+  resize_quality?: string;
+  /** Redimensionar en luz lineal (reducciones más fieles) */
+  linear_light?: boolean;
+  /** No ampliar imágenes menores que el objetivo */
+  no_upscale?: boolean;
+}
+
+export interface TrimOptions {
+  /** Diferencia máxima por canal con el color de la esquina (0 = idéntico) */
+  tolerance: number;
+}
+
+export interface PadOptions {
+  width: number;
+  height: number;
+  /** Color RGBA del relleno */
+  color: [number, number, number, number];
+  /** "center" | "top-left" | "top" | "top-right" | "left" | "right" | "bottom-left" | "bottom" | "bottom-right" */
+  anchor?: string;
+}
+
+export interface WatermarkOptions {
+  /** Bytes del archivo de la marca (PNG con alpha, normalmente) */
+  image: number[];
+  /** Mismos valores que PadOptions.anchor (por defecto "bottom-right") */
+  anchor?: string;
+  /** 0 a 1 */
+  opacity: number;
+  /** Separación en píxeles con los bordes */
+  margin?: number;
+  /** Tamaño máximo como fracción de la imagen (0.2 = 20%) */
+  scale: number;
 }
 
 export interface QuantizeOptions {
   num_colors: number;
   dither: number;
+  /** "floyd_steinberg" | "ordered" | "none" (por defecto "floyd_steinberg") */
+  dither_algorithm?: string;
+  /** Paleta RGBA fija (2-256 colores); ignora num_colors */
+  fixed_palette?: [number, number, number, number][];
+  /** Velocidad de imagequant 1-10 (10 = preview, 1 = exportación); por defecto según el tamaño */
+  speed?: number;
+  /** Calidad mínima 0-100: por debajo falla en lugar de dar una paleta pobre */
+  min_quality?: number;
+  /** Calidad máxima 0-100 */
+  max_quality?: number;
 }
 
 export interface OptimizationRequest {
   encoder_name: string;
   options: Record<string, unknown>;
+  /** Recortar bordes de color uniforme antes del resize */
+  auto_trim?: TrimOptions;
+  /** Recorte centrado a una proporción "ancho:alto" ("16:9", "1:1") antes del resize */
+  aspect_crop?: string;
   resize?: ResizeOptions;
+  /** Lienzo de tamaño exacto tras el resize (error si la imagen no cabe) */
+  pad_to?: PadOptions;
+  /** Marca de agua superpuesta antes de codificar */
+  watermark?: WatermarkOptions;
   quantize?: QuantizeOptions;
+  /** Fondo RGB para aplanar el alpha (JPEG usa el color del borde, ver detect_background_color) */
+  flatten_background?: [number, number, number];
+  /** Convertir a escala de grises (JPEG/PNG de un solo canal) */
+  grayscale?: boolean;
+  /** Original JPEG sin operaciones sobre los píxeles: re-optimizar sin pérdida (Huffman + progresivo, sin metadatos) */
+  jpeg_lossless_optimize?: boolean;
+  /** save_image escribe el original si la re-codificación ocupa más (mismo formato, sin cambios de píxeles) */
+  keep_original_if_larger?: boolean;
+  /** Categorías EXIF a conservar ("datetime" | "camera" | "gps" | "copyright"); JPEG/WebP re-incrustan solo esas */
+  metadata_policy?: string[];
+}
+
+/** Encoder disponible en el backend (list_encoders) */
+export interface EncoderInfo {
+  name: string;
+  formats: string[];
 }
 
 export interface EncoderOptions {
@@ -164,6 +286,41 @@ export function rawToImageData(raw: ImageDataRaw): ImageData {
   return new ImageData(clampedArray, raw.width, raw.height);
 }
 
+/** Imágenes que sirve el protocolo windoosh-img */
+export type ImageProtocolSource = "original" | "processed";
+
+/**
+ * URL del protocolo windoosh-img para el RGBA de un documento
+ * Windows expone los esquemas propios como http://<esquema>.localhost
+ */
+export function imageProtocolUrl(
+  docId: string,
+  source: ImageProtocolSource
+): string {
+  const base = navigator.userAgent.includes("Windows")
+    ? "http://windoosh-img.localhost"
+    : "windoosh-img://localhost";
+  return `${base}/${docId}/${source}`;
+}
+
+/**
+ * Descarga el RGBA por windoosh-img como ArrayBuffer (sin serializar a JSON)
+ * Mismo contenido que get_original_image_data / get_processed_image_data
+ */
+export async function fetchImageData(
+  docId: string,
+  source: ImageProtocolSource
+): Promise<ImageData> {
+  const response = await fetch(imageProtocolUrl(docId, source));
+  if (!response.ok) {
+    throw new Error(await response.text());
+  }
+  const width = Number(response.headers.get("X-Image-Width"));
+  const height = Number(response.headers.get("X-Image-Height"));
+  const pixels = new Uint8ClampedArray(await response.arrayBuffer());
+  return new ImageData(pixels, width, height);
+}
+
 /**
  * Dibuja ImageData en un canvas de forma optimizada
  * Similar a drawDataToCanvas de Squoosh